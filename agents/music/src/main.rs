@@ -1,8 +1,8 @@
 use claxon::FlacReader;
-use futures_util::{AsyncReadExt, StreamExt, TryStreamExt};
-use music::structs::{Metadata, Rpc, TrackPath};
+use futures_util::{AsyncReadExt, TryStreamExt};
+use music::structs::{RpcClient, TrackPath};
 use music::Client;
-use std::io::{Cursor, ErrorKind};
+use std::io::Cursor;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -13,17 +13,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = Client::new("172.19.207.105:30655").await.unwrap();
     // todo this is inefficient, as it loads the whole file in memory
     client
-        .stream_track(
-            TrackPath {
-                path: "Music/HOLYCHILD/The Shape of Brat Pop to Come/01. Barbie Nation.flac"
-                    .to_string(),
-            },
-            Metadata {},
-        )
+        .stream_track(TrackPath {
+            path: "Music/HOLYCHILD/The Shape of Brat Pop to Come/01. Barbie Nation.flac"
+                .to_string(),
+        })
         .await
         .unwrap()
-        .map(|x| base64::decode(x.unwrap().data))
-        .map_err(|x| std::io::Error::new(ErrorKind::AlreadyExists, x))
+        .map_ok(|x| x.data)
+        .map_err(std::io::Error::other)
         .into_async_read()
         .read_to_end(&mut vec)
         .await?;
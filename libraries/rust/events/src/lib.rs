@@ -1,155 +1,718 @@
 #[rustfmt::skip]
 mod structs;
 
-use async_std::prelude::Stream;
-use rpc_support::rpc_error::RpcError;
-use rpc_support::{read_request, send_response, send_stream_response, RawRpcClient};
-use std::pin::Pin;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
 pub use structs::*;
-use thiserror::Error;
-use tokio::io::BufReader;
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tracing::info;
-
-#[macro_use]
-extern crate async_trait;
-
-pub struct Client {
-    id: AtomicU64,
-    raw: RawRpcClient,
-}
 
-pub struct Server<T>
-where
-    T: Rpc + Send + Sync,
-{
-    tcp: Arc<Mutex<TcpListener>>,
-    rpc: Arc<Mutex<T>>,
-}
+#[cfg(test)]
+mod test {
+    use crate::{
+        Client, Event, EventKind, FileOnMountPath, Metadata, Rpc, RpcClient, Server,
+        SubscribeRequest,
+    };
+    use async_std::stream::Stream;
+    use futures::future::BoxFuture;
+    use futures::StreamExt;
+    use rpc_support::compression::Compression;
+    use rpc_support::framing::Framing;
+    use rpc_support::handshake::Handshake;
+    use rpc_support::interceptor::{
+        CallInfo, ClientInterceptor, ClientNext, Interceptor, Next, OutgoingCall,
+    };
+    use rpc_support::pool::{Endpoint, Pool, Resolver};
+    use rpc_support::retry::RetryPolicy;
+    use rpc_support::rpc_error::{ErrorCode, RpcError};
+    use rpc_support::trace_context::{self, TraceContext};
+    use rpc_support::transport::{Connector, Listeners, MemoryConnector};
+    use rpc_support::websocket::{WebSocketConnector, WebSocketListener};
+    use std::fmt::{Display, Formatter};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tokio::sync::Notify;
 
-impl Client {
-    /// # Errors
-    /// Will return an error when the TCP connection fails.
-    pub async fn new(addr: &str) -> Result<Self, RpcError> {
-        let tcp = TcpStream::connect(addr).await?;
+    type Events = Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>;
+    type Handler<TRequest, TResponse> = Box<
+        dyn Fn(TRequest, Metadata) -> BoxFuture<'static, Result<TResponse, RpcError>> + Send + Sync,
+    >;
 
-        Ok(Client {
-            raw: RawRpcClient::new(tcp),
-            id: AtomicU64::new(0),
-        })
+    /// Handles every call with the handler the test set for it. By default `send_event`
+    /// succeeds, `send_events` consumes its requests and subscriptions send a single event and
+    /// never end.
+    struct TestRpc {
+        send_event: Handler<Event, ()>,
+        send_events: Handler<Events, ()>,
+        subscribe: Handler<SubscribeRequest, Events>,
+    }
+
+    impl Default for TestRpc {
+        fn default() -> Self {
+            Self {
+                send_event: Box::new(|_, _| Box::pin(async { Ok(()) })),
+                send_events: Box::new(|mut events, _| {
+                    Box::pin(async move {
+                        while let Some(event) = events.next().await {
+                            event?;
+                        }
+
+                        Ok(())
+                    })
+                }),
+                subscribe: Box::new(|_, _| {
+                    let events =
+                        futures::stream::iter([Ok(event())]).chain(futures::stream::pending());
+
+                    Box::pin(async { Ok(Box::pin(events) as Events) })
+                }),
+            }
+        }
+    }
+
+    impl TestRpc {
+        fn with_send_event<F>(
+            mut self,
+            handler: impl Fn(Event, Metadata) -> F + Send + Sync + 'static,
+        ) -> Self
+        where
+            F: Future<Output = Result<(), RpcError>> + Send + 'static,
+        {
+            self.send_event =
+                Box::new(move |request, metadata| Box::pin(handler(request, metadata)));
+            self
+        }
+
+        fn with_send_events<F>(
+            mut self,
+            handler: impl Fn(Events, Metadata) -> F + Send + Sync + 'static,
+        ) -> Self
+        where
+            F: Future<Output = Result<(), RpcError>> + Send + 'static,
+        {
+            self.send_events =
+                Box::new(move |request, metadata| Box::pin(handler(request, metadata)));
+            self
+        }
+
+        fn with_subscribe<F>(
+            mut self,
+            handler: impl Fn(SubscribeRequest, Metadata) -> F + Send + Sync + 'static,
+        ) -> Self
+        where
+            F: Future<Output = Result<Events, RpcError>> + Send + 'static,
+        {
+            self.subscribe =
+                Box::new(move |request, metadata| Box::pin(handler(request, metadata)));
+            self
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Rpc for TestRpc {
+        async fn send_event(&self, request: Event, metadata: Metadata) -> Result<(), RpcError> {
+            (self.send_event)(request, metadata).await
+        }
+
+        async fn send_events(&self, request: Events, metadata: Metadata) -> Result<(), RpcError> {
+            (self.send_events)(request, metadata).await
+        }
+
+        async fn subscribe(
+            &self,
+            request: SubscribeRequest,
+            metadata: Metadata,
+        ) -> Result<Events, RpcError> {
+            (self.subscribe)(request, metadata).await
+        }
+    }
+
+    /// Fails the first `failures` calls of `send_event` as unavailable, counts every call
+    fn failing(failures: usize) -> (TestRpc, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let rpc = TestRpc::default().with_send_event({
+            let calls = calls.clone();
+            move |_, _| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if call < failures {
+                        return Err(RpcError::unavailable("try again"));
+                    }
+
+                    Ok(())
+                }
+            }
+        });
+
+        (rpc, calls)
+    }
+
+    /// Rejects every `send_event`
+    struct DenySendEvent;
+
+    #[async_trait::async_trait]
+    impl Interceptor<Metadata> for DenySendEvent {
+        async fn intercept(
+            &self,
+            call: &CallInfo<Metadata>,
+            next: Next<'_, Metadata>,
+        ) -> Result<(), RpcError> {
+            if call.method_name == "send_event" {
+                return Err(RpcError::permission_denied("send_event is not allowed"));
+            }
+
+            next.run(call).await
+        }
+    }
+
+    /// Sends calls again when the server is unavailable
+    struct RetryUnavailable;
+
+    #[async_trait::async_trait]
+    impl ClientInterceptor for RetryUnavailable {
+        async fn intercept(
+            &self,
+            call: &mut OutgoingCall,
+            next: ClientNext<'_>,
+        ) -> Result<(), RpcError> {
+            loop {
+                match next.run(call).await {
+                    Err(e) if e.code() == ErrorCode::Unavailable => continue,
+                    result => return result,
+                }
+            }
+        }
+    }
+
+    fn event() -> Event {
+        Event {
+            id: uuid::Uuid::new_v4(),
+            created_time: SystemTime::now(),
+            data: EventKind::FileCreated {
+                path: FileOnMountPath {
+                    path: "a.txt".to_string(),
+                    mount_id: "test".to_string(),
+                },
+            },
+        }
+    }
+
+    fn metadata() -> Metadata {
+        Metadata {
+            source: "test".to_string(),
+        }
+    }
+
+    fn subscribe_request() -> SubscribeRequest {
+        SubscribeRequest {
+            id: uuid::Uuid::new_v4(),
+            from: None,
+        }
+    }
+
+    async fn start_server(rpc: TestRpc) -> Client {
+        start_server_with(rpc, std::convert::identity).await
     }
-}
 
-// todo this impl should be autogenerated!
-#[async_trait]
-impl Rpc for Client {
-    async fn subscribe(
-        &mut self,
-        request: SubscribeRequest,
-        metadata: Metadata,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>, RpcError> {
-        self.raw
-            .send_rpc_stream_request(
-                self.id.fetch_add(1, Ordering::AcqRel),
-                "subscribe",
-                &request,
-                &metadata,
-            )
+    async fn start_server_with(
+        rpc: TestRpc,
+        configure: impl FnOnce(Server<TestRpc>) -> Server<TestRpc>,
+    ) -> Client {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(rpc));
+        tokio::spawn(configure(server).run());
+
+        Client::with_connector(connector)
             .await
+            .unwrap()
+            .with_metadata(metadata())
     }
 
-    async fn send_event(&mut self, request: Event, metadata: Metadata) -> Result<(), RpcError> {
-        self.raw
-            .send_rpc(
-                self.id.fetch_add(1, Ordering::AcqRel),
-                "send_event",
-                &request,
-                &metadata,
-            )
+    #[tokio::test]
+    async fn open_streams_do_not_block_other_requests() {
+        let mut client = start_server(TestRpc::default()).await;
+        let _subscription = client.subscribe(subscribe_request()).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), client.send_event(event()))
             .await
+            .expect("send_event was blocked by the open subscription")
+            .unwrap();
     }
-}
 
-#[derive(Debug, Error)]
-pub enum RunError {
-    #[error("{0}")]
-    IoError(#[from] tokio::io::Error),
-}
+    #[tokio::test]
+    async fn slow_calls_do_not_block_other_clients() {
+        let (connector, listener) = rpc_support::transport::memory();
+        let started = Arc::new(Notify::new());
+        let rpc = TestRpc::default().with_send_event({
+            let started = started.clone();
+            move |_, _| {
+                started.notify_one();
+                std::future::pending()
+            }
+        });
+        tokio::spawn(Server::with_listener(listener, Arc::new(rpc)).run());
 
-#[derive(Debug, Error)]
-pub enum ClientError {
-    #[error("{0}")]
-    IoError(#[from] tokio::io::Error),
-    #[error("{0}")]
-    JsonError(#[from] serde_json::Error),
-    #[error("{0}")]
-    RpcError(#[from] RpcError),
-}
+        let mut slow_client = Client::with_connector(connector.clone())
+            .await
+            .unwrap()
+            .with_metadata(metadata());
+        tokio::spawn(async move { slow_client.send_event(event()).await });
+        started.notified().await;
+
+        let mut client = Client::with_connector(connector)
+            .await
+            .unwrap()
+            .with_metadata(metadata());
+        let events = futures::stream::iter([Ok(event())]);
+
+        tokio::time::timeout(Duration::from_secs(5), client.send_events(Box::pin(events)))
+            .await
+            .expect("send_events waited for the slow send_event of another client")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_fail_after_their_deadline() {
+        let mut client =
+            start_server(TestRpc::default().with_send_event(|_, _| std::future::pending())).await;
+        client.set_timeout(Some(Duration::from_millis(50)));
+
+        let result = client.send_event(event()).await;
+
+        assert!(matches!(result, Err(RpcError::DeadlineExceeded)));
+    }
+
+    #[tokio::test]
+    async fn dropping_a_stream_cancels_the_handler() {
+        struct NotifyOnDrop(Arc<Notify>);
+
+        impl Drop for NotifyOnDrop {
+            fn drop(&mut self) {
+                self.0.notify_one();
+            }
+        }
+
+        let subscription_dropped = Arc::new(Notify::new());
+        let rpc = TestRpc::default().with_subscribe({
+            let subscription_dropped = subscription_dropped.clone();
+            move |_, _| {
+                let guard = NotifyOnDrop(subscription_dropped.clone());
+                let events = futures::stream::iter([Ok(event())])
+                    .chain(futures::stream::pending())
+                    .map(move |event| {
+                        let _guard = &guard;
+                        event
+                    });
+
+                async { Ok(Box::pin(events) as Events) }
+            }
+        });
+        let mut client = start_server(rpc).await;
 
-impl<T> Server<T>
-where
-    T: Rpc + Send + Sync + 'static,
-{
-    /// # Errors
-    /// Will return an error when establishing the TCP Listener fails
-    pub async fn new(addr: &str, rpc: Arc<Mutex<T>>) -> Result<Self, RpcError> {
-        let tcp = Arc::new(Mutex::new(TcpListener::bind(addr).await?));
-        Ok(Server { tcp, rpc })
-    }
-
-    async fn handle_client(socket: TcpStream, rpc: Arc<Mutex<T>>) -> Result<(), ClientError> {
-        let mut socket = socket;
-        let (read, mut write) = socket.split();
-        let mut reader = BufReader::new(read);
-
-        loop {
-            let (payload_line, method_name, request_id, metadata) =
-                read_request(&mut reader).await?;
-
-            match method_name.as_str() {
-                "send_event" => {
-                    let result = rpc
-                        .lock()
-                        .await
-                        .send_event(serde_json::from_str(&payload_line)?, metadata)
-                        .await;
-
-                    send_response(&mut write, result, request_id, false).await?;
+        let mut subscription = client.subscribe(subscribe_request()).await.unwrap();
+        // Make sure the handler is running before cancelling it
+        subscription.next().await.unwrap().unwrap();
+        drop(subscription);
+
+        tokio::time::timeout(Duration::from_secs(5), subscription_dropped.notified())
+            .await
+            .expect("the server did not cancel the subscription");
+    }
+
+    #[tokio::test]
+    async fn streams_pause_until_the_client_consumes_them() {
+        // The initial credits of the stream
+        const CREDITS: usize = 32;
+
+        let produced = Arc::new(AtomicUsize::new(0));
+        let credits_used = Arc::new(Notify::new());
+        let rpc = TestRpc::default().with_subscribe({
+            let (produced, credits_used) = (produced.clone(), credits_used.clone());
+            move |_, _| {
+                let (produced, credits_used) = (produced.clone(), credits_used.clone());
+                let events = futures::stream::repeat_with(move || {
+                    if produced.fetch_add(1, Ordering::SeqCst) + 1 == CREDITS {
+                        credits_used.notify_one();
+                    }
+                    Ok(event())
+                });
+
+                async { Ok(Box::pin(events) as Events) }
+            }
+        });
+        let mut client = start_server(rpc).await;
+
+        let mut subscription = client.subscribe(subscribe_request()).await.unwrap();
+        subscription.next().await.unwrap().unwrap();
+        credits_used.notified().await;
+        // A round trip over the same connection, a server that ignored the credits would have
+        // produced more events by then
+        client.send_event(event()).await.unwrap();
+        let paused_at = produced.load(Ordering::SeqCst);
+
+        // The client didn't consume enough to grant more credits
+        assert_eq!(CREDITS, paused_at);
+
+        for _ in 0..paused_at {
+            subscription.next().await.unwrap().unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), client.send_event(event()))
+            .await
+            .expect("send_event was blocked by the paused subscription")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_streams_are_received_completely() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let rpc = TestRpc::default().with_send_events({
+            let received = received.clone();
+            move |mut events, _| {
+                let received = received.clone();
+                async move {
+                    while let Some(event) = events.next().await {
+                        event?;
+                        received.fetch_add(1, Ordering::SeqCst);
+                    }
+
+                    Ok(())
                 }
-                "subscribe" => {
-                    let result = rpc
-                        .lock()
-                        .await
-                        .subscribe(serde_json::from_str(&payload_line)?, metadata)
-                        .await;
-
-                    send_stream_response(&mut write, result, request_id).await?;
+            }
+        });
+        let mut client = start_server(rpc).await;
+
+        // More events than the initial credits of the stream
+        let events = futures::stream::iter((0..100).map(|_| Ok(event())));
+        client.send_events(Box::pin(events)).await.unwrap();
+
+        assert_eq!(100, received.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn interceptors_can_reject_calls() {
+        let mut client = start_server_with(TestRpc::default(), |server| {
+            server.with_interceptor(DenySendEvent)
+        })
+        .await;
+
+        let result = client.send_event(event()).await;
+        let mut subscription = client.subscribe(subscribe_request()).await.unwrap();
+
+        assert_eq!(ErrorCode::PermissionDenied, result.unwrap_err().code());
+        subscription.next().await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_with_invalid_metadata_fail_alone() {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(TestRpc::default()));
+        tokio::spawn(server.run());
+        let client = rpc_support::RawRpcClient::connect(
+            connector,
+            Framing::Binary,
+            Handshake::new(crate::SERVICE_NAME, crate::SCHEMA_HASH),
+            Compression::default(),
+        )
+        .await
+        .unwrap();
+
+        let without_source: Result<(), _> = client.send_rpc(0, "send_event", &event(), &()).await;
+        let with_source: Result<(), _> = client
+            .send_rpc(1, "send_event", &event(), &metadata())
+            .await;
+
+        assert_eq!(
+            ErrorCode::InvalidArgument,
+            without_source.unwrap_err().code()
+        );
+        with_source.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_interceptors_can_retry_calls() {
+        let (rpc, calls) = failing(2);
+        let mut client = start_server(rpc).await;
+        client.add_interceptor(RetryUnavailable);
+
+        client.send_event(event()).await.unwrap();
+
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn retried_events_are_handled_once() {
+        let (rpc, calls) = failing(2);
+        let mut client = start_server(rpc).await;
+        client.add_interceptor(
+            RetryPolicy::default().with_backoff(Duration::from_millis(1), Duration::from_millis(1)),
+        );
+
+        let event = event();
+        client.send_event(event.clone()).await.unwrap();
+        // Sent again by the application, e.g. after a restart
+        client.send_event(event).await.unwrap();
+
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn calls_are_retried_after_their_deadline() {
+        /// Answers failed calls only once the retry reached the handler, which waits for that
+        /// answer. The answer to the first attempt arrives while the retry waits for its own.
+        struct AnswerFailuresDuringRetry {
+            retried: Arc<Notify>,
+            failed: Arc<Notify>,
+        }
+
+        #[async_trait::async_trait]
+        impl Interceptor<Metadata> for AnswerFailuresDuringRetry {
+            async fn intercept(
+                &self,
+                call: &CallInfo<Metadata>,
+                next: Next<'_, Metadata>,
+            ) -> Result<(), RpcError> {
+                let result = next.run(call).await;
+                if result.is_err() {
+                    self.retried.notified().await;
+                    self.failed.notify_one();
                 }
-                // fixme do not panic here!
-                _ => panic!("Unknown method name: {}", method_name),
-            };
+
+                result
+            }
         }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (retried, failed) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let rpc = TestRpc::default().with_send_event({
+            let (calls, retried, failed) = (calls.clone(), retried.clone(), failed.clone());
+            move |_, _| {
+                let first = calls.fetch_add(1, Ordering::SeqCst) == 0;
+                let (retried, failed) = (retried.clone(), failed.clone());
+                async move {
+                    if first {
+                        std::future::pending::<()>().await;
+                    }
+                    retried.notify_one();
+                    failed.notified().await;
+
+                    Ok(())
+                }
+            }
+        });
+        let mut client = start_server_with(rpc, |server| {
+            server.with_interceptor(AnswerFailuresDuringRetry { retried, failed })
+        })
+        .await;
+        client.set_timeout(Some(Duration::from_millis(50)));
+        client.add_interceptor(
+            RetryPolicy::default()
+                .with_max_attempts(2)
+                .with_backoff(Duration::from_millis(1), Duration::from_millis(1)),
+        );
+
+        client.send_event(event()).await.unwrap();
+
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn calls_are_handled_in_the_trace_of_the_client() {
+        let traces = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let rpc = TestRpc::default().with_send_event({
+            let traces = traces.clone();
+            move |_, _| {
+                traces.lock().unwrap().push(TraceContext::current());
+                async { Ok(()) }
+            }
+        });
+        let mut client = start_server(rpc).await;
+        let root = TraceContext::new_root();
+
+        trace_context::scope(root.clone(), client.send_event(event()))
+            .await
+            .unwrap();
+
+        let handled_in = traces.lock().unwrap()[0].clone().unwrap();
+        assert_eq!(root.trace_id, handled_in.trace_id);
+        assert_eq!(Some(root.span_id), handled_in.parent_span_id);
     }
 
-    /// # Errors
-    /// Will return an error if the connection fails
-    pub async fn run(self) -> Result<(), RunError> {
-        loop {
-            let (socket, address) = self.tcp.lock().await.accept().await?;
-            info!("New client connected: {}", address);
+    #[tokio::test]
+    async fn servers_describe_their_schema() {
+        let mut client = start_server(TestRpc::default()).await;
 
-            let rpc = self.rpc.clone();
+        let schema = client.reflect().await.unwrap();
 
-            tokio::spawn(platform::async_infra::run_with_error_handling(
-                Self::handle_client(socket, rpc),
-            ));
+        assert_eq!("events", schema.service_name);
+        assert_eq!(crate::SCHEMA_HASH, schema.schema_hash);
+        let subscribe = schema.call("subscribe").unwrap();
+        assert_eq!("SubscribeRequest", subscribe.request);
+        assert!(subscribe.response_stream);
+        assert!(schema.structs.iter().any(|s| s.name == "Event"));
+    }
+
+    #[tokio::test]
+    async fn clients_of_other_services_are_rejected() {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(TestRpc::default()));
+        tokio::spawn(server.run());
+
+        let result = rpc_support::RawRpcClient::connect(
+            connector,
+            Framing::Binary,
+            Handshake::new("music", crate::SCHEMA_HASH),
+            Compression::default(),
+        )
+        .await;
+
+        let error = result.err().unwrap();
+        assert_eq!(ErrorCode::FailedPrecondition, error.code());
+        assert!(error.to_string().contains("the events service"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_open_streams_and_stops_the_server() {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(TestRpc::default()));
+        let shutdown = server.shutdown_handle();
+        let running = tokio::spawn(server.run());
+        let mut client = Client::with_connector(connector)
+            .await
+            .unwrap()
+            .with_metadata(metadata());
+
+        let mut subscription = client.subscribe(subscribe_request()).await.unwrap();
+        subscription.next().await.unwrap().unwrap();
+        shutdown.trigger();
+
+        let ended = tokio::time::timeout(Duration::from_secs(5), subscription.next())
+            .await
+            .expect("the subscription was not ended")
+            .unwrap();
+        assert_eq!(ErrorCode::Unavailable, ended.unwrap_err().code());
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("the server did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_payloads_are_compressed() {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(TestRpc::default()));
+        let metrics = server.metrics();
+        tokio::spawn(server.run());
+        let mut client = Client::with_connector(connector)
+            .await
+            .unwrap()
+            .with_metadata(metadata());
+
+        let mut large = event();
+        large.data = EventKind::FileCreated {
+            path: FileOnMountPath {
+                path: "a".repeat(1024 * 1024),
+                mount_id: "test".to_string(),
+            },
+        };
+        client.send_event(large).await.unwrap();
+
+        let received = metrics
+            .render()
+            .lines()
+            .find_map(|line| line.strip_prefix("rpc_server_received_bytes_total "))
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!(
+            received < 100 * 1024,
+            "the server received {} bytes",
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_clients_are_served_alongside_other_clients() {
+        let (connector, listener) = rpc_support::transport::memory();
+        let (websocket_connector, websocket_listener) = rpc_support::transport::memory();
+        let listener = Listeners::new()
+            .with(listener)
+            .with(WebSocketListener::new(websocket_listener));
+        let server = Server::with_listener(listener, Arc::new(TestRpc::default()));
+        tokio::spawn(server.run());
+
+        // The clients are kept, dropping the last connector of a listener stops the server
+        async fn subscribe(connector: impl Connector + 'static) -> Client {
+            let mut client = Client::with_connector(connector)
+                .await
+                .unwrap()
+                .with_metadata(metadata());
+
+            let mut subscription = client.subscribe(subscribe_request()).await.unwrap();
+            subscription.next().await.unwrap().unwrap();
+
+            client
         }
+
+        let _client = subscribe(connector).await;
+        let _websocket_client =
+            subscribe(WebSocketConnector::new(websocket_connector, "ws://events/")).await;
     }
-}
 
-#[cfg(test)]
-mod test {}
+    /// Resolves to in-memory servers, one endpoint per connector
+    struct Replicas(Vec<MemoryConnector>);
+
+    impl Display for Replicas {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "replicas")
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for Replicas {
+        async fn resolve(&self) -> std::io::Result<Vec<Endpoint>> {
+            Ok(self
+                .0
+                .iter()
+                .enumerate()
+                .map(|(i, connector)| Endpoint {
+                    name: format!("replica-{}", i),
+                    connector: Arc::new(connector.clone()),
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn pooled_clients_spread_calls_over_every_replica() {
+        let mut connectors = vec![];
+        let mut handled = vec![];
+        for _ in 0..2 {
+            let (connector, listener) = rpc_support::transport::memory();
+            let calls = Arc::new(AtomicUsize::new(0));
+            let rpc = TestRpc::default().with_send_event({
+                let calls = calls.clone();
+                move |_, _| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            });
+            handled.push(calls);
+            tokio::spawn(Server::with_listener(listener, Arc::new(rpc)).run());
+            connectors.push(connector);
+        }
+
+        let mut client =
+            Client::with_resolver(Replicas(connectors), Pool::default().with_connections(2))
+                .await
+                .unwrap()
+                .with_metadata(metadata());
+
+        for _ in 0..4 {
+            client.send_event(event()).await.unwrap();
+        }
+
+        for calls in handled {
+            assert_eq!(2, calls.load(Ordering::SeqCst));
+        }
+    }
+}
@@ -12,7 +12,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     let type_checker = TypeChecker::new();
     let typed_file = type_checker.check(&ast)?;
 
-    let rust = message_compiler::compiler_rust::compile(typed_file);
+    let service = message_compiler::compiler_rust::Service::new("music", &typed_file);
+    let rust = message_compiler::compiler_rust::compile(typed_file, &service);
     std::fs::write("src/structs.rs", rust).unwrap();
 
     Ok(())
@@ -5,18 +5,27 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {}
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrackData {
+    #[serde(with = "rpc_support::bytes_serializer")]
+    pub data: Vec<u8>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TrackPath {
     pub path: String,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TrackData {
-    pub data: String,
+pub enum StreamTrackError {
+    TrackNotFound {
+        path: String,
+    },
 }
 
+/// Calls are handled concurrently, implementations guard their mutable state themselves
 #[async_trait::async_trait]
 pub trait Rpc {
+    /// Fails with [`StreamTrackError`] as an application error
     async fn stream_track(
-        &mut self,
+        &self,
         request: TrackPath,
         metadata: Metadata,
     ) -> Result<
@@ -24,3 +33,406 @@ pub trait Rpc {
         RpcError,
     >;
 }
+
+impl From<StreamTrackError> for RpcError {
+    fn from(error: StreamTrackError) -> Self {
+        RpcError::application("StreamTrackError", &error)
+    }
+}
+
+impl StreamTrackError {
+    /// Returns the error, if `error` is a `StreamTrackError` raised by the server
+    #[must_use]
+    pub fn from_rpc_error(error: &RpcError) -> Option<Self> {
+        error.application_error("StreamTrackError")
+    }
+}
+
+/// Name of the service in the handshake of every connection
+pub const SERVICE_NAME: &str = "music";
+/// Hash of the schema this code was generated from, see [`rpc_support::handshake`]
+pub const SCHEMA_HASH: &str = "00e77a8e6717f326c2194ca9aa4363e6775b9f7c4b754ad6494aad30967fe002";
+/// The schema servers answer reflection calls with, see [`rpc_support::reflection`]
+pub const SCHEMA: &str = r#"{"service_name":"music","schema_hash":"00e77a8e6717f326c2194ca9aa4363e6775b9f7c4b754ad6494aad30967fe002","metadata":[],"structs":[{"name":"TrackData","fields":[{"name":"data","type":"bytes"}]},{"name":"TrackPath","fields":[{"name":"path","type":"string"}]}],"enums":[{"name":"StreamTrackError","variants":[{"name":"TrackNotFound","fields":[{"name":"path","type":"string"}]}]}],"calls":[{"name":"stream_track","request":"TrackPath","request_stream":false,"response":"TrackData","response_stream":true,"error":"StreamTrackError"}]}"#;
+
+fn handshake() -> rpc_support::handshake::Handshake {
+    rpc_support::handshake::Handshake::new(SERVICE_NAME, SCHEMA_HASH)
+}
+
+/// The calls of [`Rpc`] as a [`Client`] sends them, with the metadata of [`Client::with_metadata`]
+#[async_trait::async_trait]
+pub trait RpcClient {
+    async fn stream_track(&mut self, request: TrackPath) -> Result<
+        std::pin::Pin<Box<dyn Stream<Item = Result<TrackData, RpcError>> + Unpin + Send>>,
+        RpcError,
+    >;
+}
+
+pub struct Client {
+    id: std::sync::atomic::AtomicU64,
+    raw: rpc_support::RawRpcClient,
+    metadata: Option<Metadata>,
+}
+
+impl Client {
+    /// # Errors
+    /// Will return an error when the TCP connection fails.
+    pub async fn new(addr: &str) -> Result<Self, RpcError> {
+        Self::with_connector(rpc_support::transport::TcpConnector::new(addr)).await
+    }
+
+    /// Connects over any transport, e.g. a Unix socket or an in-memory connection
+    ///
+    /// # Errors
+    /// Will return an error when the connection fails.
+    pub async fn with_connector(
+        connector: impl rpc_support::transport::Connector + 'static,
+    ) -> Result<Self, RpcError> {
+        Self::with_compression(connector, rpc_support::compression::Compression::default()).await
+    }
+
+    /// Connects with other compression settings than the default ones, see [`rpc_support::compression`]
+    ///
+    /// # Errors
+    /// Will return an error when the connection fails.
+    pub async fn with_compression(
+        connector: impl rpc_support::transport::Connector + 'static,
+        compression: rpc_support::compression::Compression,
+    ) -> Result<Self, RpcError> {
+        Ok(Self {
+            raw: rpc_support::RawRpcClient::connect(
+                connector,
+                rpc_support::framing::Framing::Binary,
+                handshake(),
+                compression,
+            )
+            .await?,
+            id: std::sync::atomic::AtomicU64::new(0),
+            metadata: None,
+        })
+    }
+
+    /// Connects to every replica `addr` resolves to and spreads the calls over them, see [`rpc_support::pool`]
+    ///
+    /// # Errors
+    /// Will return an error when none of the replicas is reachable.
+    pub async fn with_pool(addr: &str, pool: rpc_support::pool::Pool) -> Result<Self, RpcError> {
+        Self::with_resolver(rpc_support::pool::DnsResolver::new(addr), pool).await
+    }
+
+    /// Connects to every endpoint `resolver` finds, see [`Client::with_pool`]
+    ///
+    /// # Errors
+    /// Will return an error when none of the endpoints is reachable.
+    pub async fn with_resolver(
+        resolver: impl rpc_support::pool::Resolver + 'static,
+        pool: rpc_support::pool::Pool,
+    ) -> Result<Self, RpcError> {
+        Ok(Self {
+            raw: rpc_support::RawRpcClient::connect_pool(
+                resolver,
+                pool,
+                rpc_support::framing::Framing::Binary,
+                handshake(),
+                rpc_support::compression::Compression::default(),
+            )
+            .await?,
+            id: std::sync::atomic::AtomicU64::new(0),
+            metadata: None,
+        })
+    }
+
+    /// Sends `metadata` with every call, interceptors like
+    /// [`rpc_support::interceptor::MetadataDefaults`] can only add fields to it
+    #[must_use]
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Time the server has to answer subsequent calls, see [`rpc_support::RawRpcClient::set_timeout`]
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.raw.set_timeout(timeout);
+    }
+
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.raw.is_connected()
+    }
+
+    /// Re-request active streams after a reconnect, see [`rpc_support::RawRpcClient::set_resubscribe`]
+    pub fn set_resubscribe(&mut self, resubscribe: bool) {
+        self.raw.set_resubscribe(resubscribe);
+    }
+
+    /// Runs subsequent calls through `interceptor`, e.g. [`rpc_support::interceptor::MetadataDefaults`]
+    pub fn add_interceptor(
+        &mut self,
+        interceptor: impl rpc_support::interceptor::ClientInterceptor + 'static,
+    ) {
+        self.raw.add_interceptor(interceptor);
+    }
+
+    /// Asks the server for its schema, see [`rpc_support::RawRpcClient::reflect`]
+    ///
+    /// # Errors
+    /// Will return an error when the connection fails.
+    pub async fn reflect(&mut self) -> Result<rpc_support::reflection::Schema, RpcError> {
+        self.raw.reflect(self.next_id()).await
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id.fetch_add(1, std::sync::atomic::Ordering::AcqRel)
+    }
+}
+
+#[async_trait::async_trait]
+impl RpcClient for Client {
+    async fn stream_track(&mut self, request: TrackPath) -> Result<
+        std::pin::Pin<Box<dyn Stream<Item = Result<TrackData, RpcError>> + Unpin + Send>>,
+        RpcError,
+    > {
+        self.raw
+            .send_rpc_stream_request(self.next_id(), "stream_track", &request, &self.metadata)
+            .await
+    }
+}
+
+pub struct Server<T>
+where
+    T: Rpc + Send + Sync,
+{
+    listener: Box<dyn rpc_support::transport::Listener>,
+    rpc: std::sync::Arc<T>,
+    interceptors: Vec<std::sync::Arc<dyn rpc_support::interceptor::Interceptor<Metadata>>>,
+    metrics: std::sync::Arc<rpc_support::metrics::ServerMetrics>,
+    metrics_addr: Option<String>,
+    shutdown: rpc_support::server::Shutdown,
+    shutdown_timeout: std::time::Duration,
+    compression: rpc_support::compression::Compression,
+    deduplication: std::sync::Arc<rpc_support::deduplication::Deduplication>,
+}
+
+impl<T> Server<T>
+where
+    T: Rpc + Send + Sync + 'static,
+{
+    /// # Errors
+    /// Will return an error when establishing the TCP Listener fails
+    pub async fn new(addr: &str, rpc: std::sync::Arc<T>) -> Result<Self, RpcError> {
+        Ok(Self::with_listener(
+            tokio::net::TcpListener::bind(addr).await?,
+            rpc,
+        ))
+    }
+
+    /// Serves clients of any transport, e.g. a Unix socket or an in-memory connection
+    pub fn with_listener(
+        listener: impl rpc_support::transport::Listener + 'static,
+        rpc: std::sync::Arc<T>,
+    ) -> Self {
+        Self {
+            listener: Box::new(listener),
+            rpc,
+            interceptors: Vec::new(),
+            metrics: std::sync::Arc::default(),
+            metrics_addr: None,
+            shutdown: rpc_support::server::Shutdown::new(),
+            shutdown_timeout: rpc_support::server::DEFAULT_SHUTDOWN_TIMEOUT,
+            compression: rpc_support::compression::Compression::default(),
+            deduplication: std::sync::Arc::default(),
+        }
+    }
+
+    /// Runs every call through `interceptor`, after the ones that were added before
+    #[must_use]
+    pub fn with_interceptor(
+        mut self,
+        interceptor: impl rpc_support::interceptor::Interceptor<Metadata> + 'static,
+    ) -> Self {
+        self.interceptors.push(std::sync::Arc::new(interceptor));
+        self
+    }
+
+    /// Serves the metrics of the server in the Prometheus text format at `/metrics` of `addr`
+    #[must_use]
+    pub fn with_metrics_endpoint(mut self, addr: &str) -> Self {
+        self.metrics_addr = Some(addr.to_string());
+        self
+    }
+
+    /// Metrics of all calls the server handled so far
+    #[must_use]
+    pub fn metrics(&self) -> std::sync::Arc<rpc_support::metrics::ServerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Triggering the returned handle makes [`Self::run`] stop accepting clients and return once
+    /// the running calls finished
+    #[must_use]
+    pub fn shutdown_handle(&self) -> rpc_support::server::Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Time running calls get to finish after a shutdown was triggered
+    #[must_use]
+    pub fn with_shutdown_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Algorithms the server accepts for compressing payloads and the size from which it
+    /// compresses responses, see [`rpc_support::compression`]
+    #[must_use]
+    pub fn with_compression(mut self, compression: rpc_support::compression::Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// How long the server remembers the results of `idempotent` calls to answer their retries,
+    /// see [`rpc_support::deduplication`]
+    #[must_use]
+    pub fn with_deduplication(
+        mut self,
+        deduplication: rpc_support::deduplication::Deduplication,
+    ) -> Self {
+        self.deduplication = std::sync::Arc::new(deduplication);
+        self
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_client(
+        socket: Box<dyn rpc_support::transport::Transport>,
+        peer: rpc_support::transport::Peer,
+        rpc: std::sync::Arc<T>,
+        interceptors: rpc_support::interceptor::Interceptors<Metadata>,
+        metrics: std::sync::Arc<rpc_support::metrics::ServerMetrics>,
+        shutdown: rpc_support::server::Shutdown,
+        compression: rpc_support::compression::Compression,
+        deduplication: std::sync::Arc<rpc_support::deduplication::Deduplication>,
+    ) -> Result<(), rpc_support::server::ClientError> {
+        let (read, mut write) = tokio::io::split(metrics.metered(socket));
+        let mut reader = tokio::io::BufReader::new(read);
+        let framing = rpc_support::framing::accept_framing(&mut reader, &mut write).await?;
+        let mut reader = rpc_support::framing::FrameReader::new(reader, framing);
+        let mut write = rpc_support::framing::FrameWriter::new(write, framing);
+        rpc_support::handshake::accept_handshake(&mut reader, &mut write, &handshake(), &compression)
+            .await?;
+
+        let writer = rpc_support::server::ResponseWriter::spawn(
+            write,
+            metrics.clone(),
+            shutdown.clone(),
+        );
+
+        let calls = rpc_support::server::ActiveCalls::new(peer, interceptors, metrics);
+
+        loop {
+            let request = tokio::select! {
+                request = rpc_support::read_request::<Metadata>(&mut reader) => request?,
+                () = shutdown.drained() => return Ok(()),
+            };
+
+            match request {
+                rpc_support::server::Request::Call(mut call) => {
+                    calls.open_input(&mut call, &writer);
+                    let info = calls.call_info(&call);
+                    calls.spawn(
+                        info,
+                        call.timeout,
+                        writer.clone(),
+                        Self::handle_request(
+                            rpc.clone(),
+                            writer.clone(),
+                            deduplication.clone(),
+                            *call,
+                        ),
+                    );
+                }
+                rpc_support::server::Request::Cancel { request_id } => calls.cancel(request_id),
+                rpc_support::server::Request::Credit {
+                    request_id,
+                    credits,
+                } => writer.grant_credits(request_id, credits),
+                rpc_support::server::Request::Item {
+                    request_id,
+                    payload,
+                } => calls.push_input(request_id, payload).await,
+                rpc_support::server::Request::EndOfInput { request_id } => {
+                    calls.end_input(request_id);
+                }
+                rpc_support::server::Request::Reflect { request_id } => {
+                    rpc_support::reflection::reply(&writer, SCHEMA, request_id);
+                }
+                rpc_support::server::Request::Rejected { request_id, error } => {
+                    rpc_support::server::reject(&writer, request_id, error);
+                }
+            }
+        }
+    }
+
+    async fn handle_request(
+        rpc: std::sync::Arc<T>,
+        writer: rpc_support::server::ResponseWriter,
+        _deduplication: std::sync::Arc<rpc_support::deduplication::Deduplication>,
+        call: rpc_support::server::Call<Metadata>,
+    ) -> Result<(), RpcError> {
+        match call.method_name.as_str() {
+            "stream_track" => {
+                let result = match writer.framing().decode_payload(&call.payload) {
+                    Ok(request) => rpc.stream_track(request, call.metadata).await,
+                    Err(e) => Err(e),
+                };
+
+                rpc_support::send_stream_response(&writer, result, call.request_id, call.credits).await
+            }
+            _ => {
+                let result: Result<(), RpcError> =
+                    Err(rpc_support::server::unknown_method(&call.method_name));
+
+                rpc_support::send_unary_response(&writer, result, call.request_id).await
+            }
+        }
+    }
+
+    /// Serves clients until a shutdown is triggered, see [`Self::shutdown_handle`]
+    ///
+    /// # Errors
+    /// Will return an error if the connection fails
+    pub async fn run(mut self) -> Result<(), rpc_support::server::RunError> {
+        let interceptors: rpc_support::interceptor::Interceptors<Metadata> =
+            std::mem::take(&mut self.interceptors).into();
+
+        if let Some(addr) = &self.metrics_addr {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tokio::spawn(platform::async_infra::run_with_error_handling(
+                rpc_support::metrics::serve(listener, self.metrics.clone()),
+            ));
+        }
+
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                () = self.shutdown.triggered() => break,
+            };
+            tracing::info!("New client connected: {}", peer);
+
+            tokio::spawn(platform::async_infra::run_with_error_handling(
+                Self::handle_client(
+                    socket,
+                    peer,
+                    self.rpc.clone(),
+                    interceptors.clone(),
+                    self.metrics.clone(),
+                    self.shutdown.clone(),
+                    self.compression.clone(),
+                    self.deduplication.clone(),
+                ),
+            ));
+        }
+
+        self.shutdown.drain(self.shutdown_timeout).await;
+
+        Ok(())
+    }
+}
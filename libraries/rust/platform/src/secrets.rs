@@ -37,13 +37,27 @@ impl<'a> SecretProvider<'a> {
     /// # Errors
     /// Will return an error if the secret does not exist or is not readable
     pub fn read(&self, name: &str) -> Result<Secret, Error> {
-        let mut pathbuf = PathBuf::new();
-        pathbuf.push(self.base_path);
-        pathbuf.push(name);
+        let pathbuf = self.path(name);
 
         let username = std::fs::read_to_string(pathbuf.as_path().join("username"))?;
         let password = std::fs::read_to_string(pathbuf.as_path().join("password"))?;
 
         Ok(Secret { username, password })
     }
+
+    /// Reads a single file of a secret, e.g. `tls.crt` of a TLS secret
+    ///
+    /// # Errors
+    /// Will return an error if the file does not exist or is not readable
+    pub fn read_file(&self, name: &str, file: &str) -> Result<Vec<u8>, Error> {
+        Ok(std::fs::read(self.path(name).join(file))?)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        let mut pathbuf = PathBuf::new();
+        pathbuf.push(self.base_path);
+        pathbuf.push(name);
+
+        pathbuf
+    }
 }
@@ -0,0 +1,153 @@
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt::Formatter;
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        write!(formatter, "bytes or a base64 encoded string")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        base64::decode(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        Ok(v)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut result = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(byte) = seq.next_element()? {
+            result.push(byte);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Human readable formats (JSON lines) get a base64 string, binary formats get the raw bytes
+///
+/// # Errors
+/// Can fail if the underlying serializer fails
+pub fn serialize<S>(val: &[u8], ser: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if ser.is_human_readable() {
+        ser.serialize_str(&base64::encode(val))
+    } else {
+        ser.serialize_bytes(val)
+    }
+}
+
+/// # Errors
+/// Can fail if the value is neither bytes nor a valid base64 string
+pub fn deserialize<'de, D>(des: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if des.is_human_readable() {
+        des.deserialize_str(BytesVisitor)
+    } else {
+        des.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// The same encoding for `Option<Vec<u8>>` fields
+pub mod optional {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct BorrowedBytes<'a>(#[serde(with = "super")] &'a [u8]);
+
+    #[derive(Deserialize)]
+    struct OwnedBytes(#[serde(with = "super")] Vec<u8>);
+
+    /// # Errors
+    /// Can fail if the underlying serializer fails
+    pub fn serialize<S>(val: &Option<Vec<u8>>, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match val {
+            Some(bytes) => ser.serialize_some(&BorrowedBytes(bytes)),
+            None => ser.serialize_none(),
+        }
+    }
+
+    /// # Errors
+    /// Can fail if the value is neither null, bytes nor a valid base64 string
+    pub fn deserialize<'de, D>(des: D) -> Result<Option<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<OwnedBytes>::deserialize(des)?.map(|OwnedBytes(bytes)| bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::framing::Framing;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Chunk {
+        #[serde(with = "crate::bytes_serializer")]
+        data: Vec<u8>,
+        #[serde(default, with = "crate::bytes_serializer::optional")]
+        extra: Option<Vec<u8>>,
+    }
+
+    #[test]
+    fn json_uses_base64() {
+        let chunk = Chunk {
+            data: vec![0, 1, 2, 255],
+            extra: None,
+        };
+
+        assert_eq!(
+            r#"{"data":"AAEC/w==","extra":null}"#,
+            serde_json::to_string(&chunk).unwrap()
+        );
+        assert_eq!(
+            chunk,
+            serde_json::from_str(r#"{"data":"AAEC/w=="}"#).unwrap()
+        );
+    }
+
+    #[test]
+    fn binary_payloads_carry_raw_bytes() {
+        let chunk = Chunk {
+            data: vec![7; 1024],
+            extra: Some(vec![1, 2]),
+        };
+
+        let encoded = Framing::Binary.encode_payload(&chunk).unwrap();
+
+        assert!(encoded.len() < 1024 + 32);
+        assert_eq!(
+            chunk,
+            Framing::Binary.decode_payload::<Chunk>(&encoded).unwrap()
+        );
+    }
+}
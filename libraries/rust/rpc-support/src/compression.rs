@@ -0,0 +1,140 @@
+use crate::rpc_error::RpcError;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Sections smaller than this are not worth compressing, most envelopes and small payloads
+pub const DEFAULT_THRESHOLD: usize = 16 * 1024;
+
+/// Algorithms payloads can be compressed with, negotiated per connection in the handshake
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    Zstd,
+    Deflate,
+}
+
+impl Algorithm {
+    /// # Errors
+    /// Can fail if the compressor fails
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, RpcError> {
+        Ok(match self {
+            Self::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+            Self::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        })
+    }
+
+    /// Decompresses at most `limit` bytes, protects against small sections that decompress to
+    /// huge ones
+    ///
+    /// # Errors
+    /// Can fail if `data` is malformed or decompresses to more than `limit` bytes
+    pub fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>, RpcError> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+            Self::Deflate => Box::new(flate2::read::DeflateDecoder::new(data)),
+        };
+
+        let mut result = vec![];
+        decoder.take(limit as u64 + 1).read_to_end(&mut result)?;
+
+        if result.len() > limit {
+            return Err(RpcError::Custom(format!(
+                "Section decompresses to more than the limit of {} bytes",
+                limit
+            )));
+        }
+
+        Ok(result)
+    }
+}
+
+/// Compression settings of one end of a connection. The client offers its algorithms in order of
+/// preference, the server picks the first one it supports as well. Each end compresses the
+/// sections it sends if they are at least `threshold` bytes large.
+///
+/// Only connections with binary framing are compressed, JSON lines stay readable for debugging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithms: Vec<Algorithm>,
+    pub threshold: usize,
+}
+
+impl Compression {
+    /// Never compresses, and neither does the other end of the connection
+    #[must_use]
+    pub const fn disabled() -> Self {
+        Self {
+            algorithms: Vec::new(),
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// The algorithm a server with these settings picks from the `offered` ones of a client
+    #[must_use]
+    pub fn negotiate(&self, offered: &[Algorithm]) -> Option<Algorithm> {
+        offered
+            .iter()
+            .copied()
+            .find(|algorithm| self.algorithms.contains(algorithm))
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![Algorithm::Zstd, Algorithm::Deflate],
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn algorithms_roundtrip() {
+        let data = "event ".repeat(1000).into_bytes();
+
+        for algorithm in [Algorithm::Zstd, Algorithm::Deflate] {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(compressed.len() < data.len() / 10);
+            assert_eq!(data, algorithm.decompress(&compressed, data.len()).unwrap());
+        }
+    }
+
+    #[test]
+    fn decompression_is_limited() {
+        let data = vec![0; 1024 * 1024];
+
+        for algorithm in [Algorithm::Zstd, Algorithm::Deflate] {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(algorithm.decompress(&compressed, 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn server_picks_the_first_offered_algorithm_it_supports() {
+        let server = Compression {
+            algorithms: vec![Algorithm::Deflate, Algorithm::Zstd],
+            threshold: DEFAULT_THRESHOLD,
+        };
+
+        assert_eq!(
+            Some(Algorithm::Zstd),
+            server.negotiate(&[Algorithm::Zstd, Algorithm::Deflate])
+        );
+        assert_eq!(None, Compression::disabled().negotiate(&[Algorithm::Zstd]));
+    }
+}
@@ -0,0 +1,416 @@
+use crate::compression::Compression;
+use crate::framing::{negotiate_framing, FrameReader, FrameWriter, Framing};
+use crate::handshake::{exchange_handshake, Handshake};
+use crate::rpc_error::RpcError;
+use crate::transport::{Connector, Transport};
+use crate::{ResponseEnvelope, RpcClientTaskError};
+use dashmap::DashMap;
+use platform::async_infra::run_with_error_handling;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{BufReader, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info, warn};
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+pub(crate) type ResponseSender = Sender<(ResponseEnvelope, Option<Vec<u8>>)>;
+pub(crate) type RequestSender = Sender<Vec<Vec<u8>>>;
+type Reader = FrameReader<BufReader<ReadHalf<Box<dyn Transport>>>>;
+type Writer = FrameWriter<WriteHalf<Box<dyn Transport>>>;
+type Connection = (Reader, Writer);
+
+pub(crate) struct ActiveStream {
+    pub sender: ResponseSender,
+    /// Sent again after a reconnect, if the client re-subscribes. Not set for streams that can't
+    /// be repeated, because they send a request stream.
+    pub request: Option<Vec<Vec<u8>>>,
+}
+
+/// Shared between a client and the task that owns its connection
+#[derive(Default)]
+pub(crate) struct ConnectionState {
+    pub waiting_responses: DashMap<u64, ResponseSender>,
+    pub active_streams: DashMap<u64, ActiveStream>,
+    /// Credits the server granted to the request streams of calls
+    pub request_credits: DashMap<u64, Arc<Semaphore>>,
+    pub connected: AtomicBool,
+    pub resubscribe: AtomicBool,
+}
+
+impl ConnectionState {
+    /// Fails every call waiting for a response and, unless the client re-subscribes, every
+    /// active stream. Request streams stop sending.
+    fn fail_pending_calls(&self) {
+        for credits in self.request_credits.iter() {
+            credits.close();
+        }
+
+        let waiting = self
+            .waiting_responses
+            .iter()
+            .map(|call| *call.key())
+            .collect::<Vec<_>>();
+
+        for request_id in waiting {
+            if let Some((_, sender)) = self.waiting_responses.remove(&request_id) {
+                // The channel has room for exactly the one response that never arrived
+                let _ = sender.try_send((connection_lost(request_id), None));
+            }
+        }
+
+        let resubscribe = self.resubscribe.load(Ordering::Acquire);
+        let streams = self
+            .active_streams
+            .iter()
+            .filter(|stream| !resubscribe || stream.request.is_none())
+            .map(|stream| *stream.key())
+            .collect::<Vec<_>>();
+
+        for request_id in streams {
+            if let Some((_, stream)) = self.active_streams.remove(&request_id) {
+                // Don't block on streams whose consumer is lagging behind
+                tokio::spawn(async move {
+                    let _ = stream
+                        .sender
+                        .send((connection_lost(request_id), None))
+                        .await;
+                });
+            }
+        }
+    }
+}
+
+const fn connection_lost(request_id: u64) -> ResponseEnvelope {
+    ResponseEnvelope {
+        request_id,
+        error: Some(RpcError::ConnectionLost),
+        stream_end: true,
+        credits: None,
+    }
+}
+
+/// # Errors
+/// Can fail if the server is not reachable, does not accept the requested framing or does not
+/// match `handshake`
+pub(crate) async fn connect(
+    connector: &dyn Connector,
+    framing: Framing,
+    handshake: &Handshake,
+    compression: &Compression,
+) -> Result<Connection, RpcError> {
+    let (read, mut write) =
+        tokio::io::split(connector.connect().await.map_err(RpcError::transport)?);
+    let mut read = BufReader::new(read);
+    negotiate_framing(&mut read, &mut write, framing).await?;
+
+    let mut reader = FrameReader::new(read, framing);
+    let mut writer = FrameWriter::new(write, framing);
+    exchange_handshake(&mut reader, &mut writer, handshake, compression).await?;
+
+    Ok((reader, writer))
+}
+
+/// Connects and spawns the task that owns the connection, requests are written once they're sent
+/// through the returned sender
+///
+/// # Errors
+/// Can fail if the server is not reachable, does not accept the requested framing or does not
+/// match `handshake`
+pub(crate) async fn open(
+    connector: Arc<dyn Connector>,
+    framing: Framing,
+    handshake: Handshake,
+    compression: Compression,
+) -> Result<(Arc<ConnectionState>, RequestSender), RpcError> {
+    let connection = connect(connector.as_ref(), framing, &handshake, &compression).await?;
+
+    let state = Arc::new(ConnectionState::default());
+    state.connected.store(true, Ordering::Release);
+
+    let (request_tx, request_rx) = tokio::sync::mpsc::channel(64);
+
+    tokio::task::spawn(run_with_error_handling(connection_task(
+        connector,
+        framing,
+        handshake,
+        compression,
+        state.clone(),
+        connection,
+        request_rx,
+    )));
+
+    Ok((state, request_tx))
+}
+
+/// Owns the connection of a client and writes every request sent through `requests`. When the
+/// connection is lost, pending calls fail with [`RpcError::ConnectionLost`] and the connection is
+/// re-established with exponential backoff. Ends once the client and all of its calls are dropped.
+async fn connection_task(
+    connector: Arc<dyn Connector>,
+    framing: Framing,
+    handshake: Handshake,
+    compression: Compression,
+    state: Arc<ConnectionState>,
+    mut connection: Connection,
+    mut requests: Receiver<Vec<Vec<u8>>>,
+) -> Result<(), RpcClientTaskError> {
+    // Streams of the first connection are sent through `requests` like any other call
+    let mut reconnected = false;
+
+    loop {
+        let (mut reader, mut writer) = connection;
+
+        let resent = if reconnected {
+            resend_streams(&mut writer, &state).await
+        } else {
+            Ok(())
+        };
+
+        let error = match resent {
+            Ok(()) => tokio::select! {
+                error = read_responses(&mut reader, &state) => error,
+                result = write_requests(&mut writer, &mut requests) => match result {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                },
+            },
+            Err(e) => e,
+        };
+
+        state.connected.store(false, Ordering::Release);
+        warn!("Lost connection to {}: {}", connector, error);
+
+        connection = match reconnect(
+            connector.as_ref(),
+            framing,
+            &handshake,
+            &compression,
+            &state,
+            &mut requests,
+        )
+        .await
+        {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        reconnected = true;
+
+        state.connected.store(true, Ordering::Release);
+    }
+}
+
+async fn reconnect(
+    connector: &dyn Connector,
+    framing: Framing,
+    handshake: &Handshake,
+    compression: &Compression,
+    state: &ConnectionState,
+    requests: &mut Receiver<Vec<Vec<u8>>>,
+) -> Option<Connection> {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+
+    loop {
+        // Requests that were queued before the disconnect was noticed can't be sent anymore
+        loop {
+            match requests.try_recv() {
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+
+        state.fail_pending_calls();
+
+        tokio::time::sleep(delay).await;
+
+        match connect(connector, framing, handshake, compression).await {
+            Ok(connection) => {
+                info!("Reconnected to {}", connector);
+
+                return Some(connection);
+            }
+            Err(e) => {
+                warn!("Reconnecting to {} failed: {}", connector, e);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+async fn resend_streams(
+    writer: &mut Writer,
+    state: &ConnectionState,
+) -> Result<(), RpcClientTaskError> {
+    let requests = state
+        .active_streams
+        .iter()
+        .filter_map(|stream| stream.request.clone())
+        .collect::<Vec<_>>();
+
+    for sections in requests {
+        writer
+            .write_frame(&sections.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn write_requests(
+    writer: &mut Writer,
+    requests: &mut Receiver<Vec<Vec<u8>>>,
+) -> Result<(), RpcClientTaskError> {
+    while let Some(sections) = requests.recv().await {
+        writer
+            .write_frame(&sections.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn read_responses(reader: &mut Reader, state: &ConnectionState) -> RpcClientTaskError {
+    loop {
+        if let Err(e) = read_response(reader, state).await {
+            return e;
+        }
+    }
+}
+
+async fn read_response(
+    reader: &mut Reader,
+    state: &ConnectionState,
+) -> Result<(), RpcClientTaskError> {
+    let response_envelope: ResponseEnvelope =
+        serde_json::from_slice(&reader.read_section().await?)?;
+    let request_id = response_envelope.request_id;
+
+    if let Some(credits) = response_envelope.credits {
+        if let Some(semaphore) = state.request_credits.get(&request_id) {
+            semaphore.add_permits(credits as usize);
+        }
+
+        return Ok(());
+    }
+
+    let response_line = if response_envelope.error.is_none() {
+        Some(reader.read_section().await?)
+    } else {
+        None
+    };
+
+    let sender = if let Some((_, sender)) = state.waiting_responses.remove(&request_id) {
+        sender
+    } else if let Some(sender) = state
+        .active_streams
+        .get(&request_id)
+        .map(|stream| stream.sender.clone())
+    {
+        if response_envelope.stream_end {
+            state.active_streams.remove(&request_id);
+        }
+
+        sender
+    } else {
+        // The server acknowledges calls the client already gave up on
+        if !matches!(
+            response_envelope.error,
+            Some(RpcError::Cancelled | RpcError::DeadlineExceeded)
+        ) {
+            error!("Found response, but no request. Request ID: {}", request_id);
+        }
+
+        return Ok(());
+    };
+
+    // The caller may have given up on the call in the meantime
+    if sender
+        .send((response_envelope, response_line))
+        .await
+        .is_err()
+    {
+        debug!("Dropping response for abandoned request {}", request_id);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compression::Compression;
+    use crate::framing::{accept_framing, FrameReader, FrameWriter, Framing};
+    use crate::handshake::{accept_handshake, Handshake};
+    use crate::rpc_error::RpcError;
+    use crate::transport::TcpConnector;
+    use crate::RawRpcClient;
+    use std::time::Duration;
+    use tokio::io::BufReader;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpListener;
+
+    async fn accept(
+        listener: &TcpListener,
+    ) -> (
+        FrameReader<BufReader<OwnedReadHalf>>,
+        FrameWriter<OwnedWriteHalf>,
+    ) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        let mut read = BufReader::new(read);
+        let framing = accept_framing(&mut read, &mut write).await.unwrap();
+
+        let mut reader = FrameReader::new(read, framing);
+        let mut writer = FrameWriter::new(write, framing);
+        accept_handshake(
+            &mut reader,
+            &mut writer,
+            &Handshake::new("test", ""),
+            &Compression::default(),
+        )
+        .await
+        .unwrap();
+
+        (reader, writer)
+    }
+
+    #[tokio::test]
+    async fn pending_calls_fail_and_the_client_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let call = tokio::spawn(async move {
+            let client = RawRpcClient::connect(
+                TcpConnector::new(&addr),
+                Framing::Binary,
+                Handshake::new("test", ""),
+                Compression::default(),
+            )
+            .await
+            .unwrap();
+            let result = client.send_rpc::<_, _, ()>(1, "call", &(), &()).await;
+
+            (client, result)
+        });
+
+        let (mut reader, write) = accept(&listener).await;
+        reader.read_section().await.unwrap();
+        drop((reader, write));
+
+        let (client, result) = call.await.unwrap();
+        assert!(matches!(result, Err(RpcError::ConnectionLost)));
+
+        let _connection = accept(&listener).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.is_connected() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the client did not reconnect");
+    }
+}
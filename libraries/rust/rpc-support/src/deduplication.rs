@@ -0,0 +1,181 @@
+use crate::rpc_error::RpcError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+/// Time the results of idempotent calls are kept, retries that arrive later are handled again
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+type Key = (String, String);
+
+/// Remembers the results of idempotent calls by their idempotency key, so the retries of a call
+/// get the result of its first attempt instead of being handled again. Retries that arrive while
+/// the first attempt is still running wait for it. Shared by every connection of a server.
+///
+/// Only results of calls that succeeded are kept, failed calls are handled again when retried.
+pub struct Deduplication {
+    retention: Duration,
+    results: Mutex<Results>,
+}
+
+#[derive(Default)]
+struct Results {
+    by_key: HashMap<Key, Arc<OnceCell<Value>>>,
+    /// Keys in the order they were first seen, with the time they expire at
+    expiry: VecDeque<(Instant, Key)>,
+}
+
+impl Deduplication {
+    #[must_use]
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            results: Mutex::default(),
+        }
+    }
+
+    /// Runs `call` unless an attempt of the call with the same `idempotency_key` succeeded
+    /// before, then its result is returned. Calls without a key always run.
+    ///
+    /// # Errors
+    /// Returns the error of `call`
+    pub async fn run<T, F>(
+        &self,
+        method_name: &str,
+        idempotency_key: Option<&str>,
+        call: F,
+    ) -> Result<T, RpcError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, RpcError>>,
+    {
+        let key = match idempotency_key {
+            Some(key) => (method_name.to_string(), key.to_string()),
+            None => return call.await,
+        };
+
+        let result = self.result(key);
+        let mut response = None;
+        let value = result
+            .get_or_try_init(|| async {
+                let value = call.await?;
+                let serialized = serde_json::to_value(&value)?;
+                response = Some(value);
+
+                Ok::<_, RpcError>(serialized)
+            })
+            .await?;
+
+        match response {
+            Some(response) => Ok(response),
+            None => {
+                debug!("Answering a retry of {} with its first result", method_name);
+
+                Ok(serde_json::from_value(value.clone())?)
+            }
+        }
+    }
+
+    fn result(&self, key: Key) -> Arc<OnceCell<Value>> {
+        let mut results = self.results.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+
+        while let Some((expires_at, _)) = results.expiry.front() {
+            if *expires_at > now {
+                break;
+            }
+
+            if let Some((_, expired)) = results.expiry.pop_front() {
+                results.by_key.remove(&expired);
+            }
+        }
+
+        if let Some(result) = results.by_key.get(&key) {
+            return result.clone();
+        }
+
+        let result = Arc::new(OnceCell::new());
+        results.by_key.insert(key.clone(), result.clone());
+        results.expiry.push_back((now + self.retention, key));
+
+        result
+    }
+}
+
+impl Default for Deduplication {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETENTION)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn retries_get_the_result_of_the_first_attempt() {
+        let deduplication = Deduplication::default();
+        let handled = AtomicUsize::new(0);
+        let call = || async { Ok(handled.fetch_add(1, Ordering::SeqCst)) };
+
+        assert_eq!(
+            0,
+            deduplication.run("call", Some("a"), call()).await.unwrap()
+        );
+        assert_eq!(
+            0,
+            deduplication.run("call", Some("a"), call()).await.unwrap()
+        );
+        assert_eq!(
+            1,
+            deduplication.run("call", Some("b"), call()).await.unwrap()
+        );
+        assert_eq!(
+            2,
+            deduplication.run("other", Some("a"), call()).await.unwrap()
+        );
+        assert_eq!(3, deduplication.run("call", None, call()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_are_handled_again() {
+        let deduplication = Deduplication::default();
+
+        let failed = deduplication
+            .run("call", Some("a"), async {
+                Err::<u8, _>(RpcError::unavailable("failed"))
+            })
+            .await;
+        let retried = deduplication.run("call", Some("a"), async { Ok(1) }).await;
+
+        assert!(failed.is_err());
+        assert_eq!(1, retried.unwrap());
+    }
+
+    #[tokio::test]
+    async fn results_expire_after_the_retention() {
+        let deduplication = Deduplication::new(Duration::ZERO);
+
+        assert_eq!(
+            0,
+            deduplication
+                .run("call", Some("a"), async { Ok(0) })
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            1,
+            deduplication
+                .run("call", Some("a"), async { Ok(1) })
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use crate::compression::Algorithm;
+use crate::rpc_error::RpcError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Sent by a client that wants to use binary framing, echoed back by the server to accept it.
+/// Starts with a NUL byte, so it can never be confused with the first line of a JSON-lines client.
+pub const BINARY_PREAMBLE: &[u8; 4] = b"\0APB";
+
+/// Upper bound for a single section, protects against allocating garbage lengths
+const MAX_SECTION_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Set in the length prefix of binary sections that are compressed with the algorithm negotiated
+/// in the handshake. Never part of a valid length, see [`MAX_SECTION_LENGTH`].
+const COMPRESSED: u32 = 1 << 31;
+
+/// How the sections of a frame (envelope, metadata, payload) are delimited on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Every section is a single `\n`-terminated line, usable with netcat for debugging
+    JsonLines,
+    /// Every section is prefixed with its length as a big-endian `u32`, payloads are encoded
+    /// with `MessagePack`, so `bytes` fields are transported without base64 inflation. Large
+    /// sections may be compressed, see [`crate::compression`].
+    Binary,
+}
+
+impl Framing {
+    /// # Errors
+    /// Can fail if the payload cannot be serialized
+    pub fn encode_payload<T>(self, payload: &T) -> Result<Vec<u8>, RpcError>
+    where
+        T: Serialize + ?Sized,
+    {
+        Ok(match self {
+            Self::JsonLines => serde_json::to_vec(payload)?,
+            Self::Binary => rmp_serde::to_vec_named(payload)?,
+        })
+    }
+
+    /// # Errors
+    /// Can fail if the payload is not valid for the requested type
+    pub fn decode_payload<T>(self, payload: &[u8]) -> Result<T, RpcError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(match self {
+            Self::JsonLines => serde_json::from_slice(payload)?,
+            Self::Binary => rmp_serde::from_slice(payload)?,
+        })
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    framing: Framing,
+    compression: Option<Algorithm>,
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    pub const fn new(reader: R, framing: Framing) -> Self {
+        Self {
+            reader,
+            framing,
+            compression: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Sets the algorithm compressed sections are decompressed with, see [`FrameWriter::set_compression`]
+    pub fn set_compression(&mut self, algorithm: Option<Algorithm>) {
+        self.compression = algorithm;
+    }
+
+    /// # Errors
+    /// Can fail if the connection was closed or the section is malformed
+    pub async fn read_section(&mut self) -> Result<Vec<u8>, RpcError> {
+        match self.framing {
+            Framing::JsonLines => {
+                let mut line = vec![];
+                if self
+                    .reader
+                    .read_until(b'\n', &mut line)
+                    .await
+                    .map_err(RpcError::transport)?
+                    == 0
+                {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+
+                Ok(line)
+            }
+            Framing::Binary => {
+                let prefix = self.reader.read_u32().await.map_err(RpcError::transport)?;
+                let length = (prefix & !COMPRESSED) as usize;
+                if length > MAX_SECTION_LENGTH {
+                    return Err(RpcError::Custom(format!(
+                        "Section of {} bytes exceeds the limit of {} bytes",
+                        length, MAX_SECTION_LENGTH
+                    )));
+                }
+
+                let mut section = vec![0; length];
+                self.reader
+                    .read_exact(&mut section)
+                    .await
+                    .map_err(RpcError::transport)?;
+
+                if prefix & COMPRESSED == 0 {
+                    return Ok(section);
+                }
+
+                self.compression
+                    .ok_or_else(|| {
+                        RpcError::Custom("Compressed section without negotiated compression".into())
+                    })?
+                    .decompress(&section, MAX_SECTION_LENGTH)
+            }
+        }
+    }
+}
+
+pub struct FrameWriter<W> {
+    writer: W,
+    framing: Framing,
+    compression: Option<Algorithm>,
+    compression_threshold: usize,
+}
+
+impl<W> FrameWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub const fn new(writer: W, framing: Framing) -> Self {
+        Self {
+            writer,
+            framing,
+            compression: None,
+            compression_threshold: 0,
+        }
+    }
+
+    #[must_use]
+    pub const fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Compresses binary sections of at least `threshold` bytes with `algorithm`, unless that
+    /// doesn't make them smaller. The reading end must use the same algorithm.
+    pub fn set_compression(&mut self, algorithm: Option<Algorithm>, threshold: usize) {
+        self.compression = algorithm;
+        self.compression_threshold = threshold;
+    }
+
+    /// Writes all sections of a frame in a single write, so frames never interleave
+    ///
+    /// # Errors
+    /// Can fail if writing to the connection fails, or if a section cannot be represented in the
+    /// current framing
+    pub async fn write_frame(&mut self, sections: &[&[u8]]) -> Result<(), RpcError> {
+        let mut buffer = vec![];
+
+        for section in sections {
+            match self.framing {
+                Framing::JsonLines => {
+                    if section.contains(&b'\n') {
+                        return Err(RpcError::Custom(
+                            "A JSON-lines section cannot contain a newline".into(),
+                        ));
+                    }
+
+                    buffer.extend_from_slice(section);
+                    buffer.push(b'\n');
+                }
+                Framing::Binary => {
+                    if section.len() > MAX_SECTION_LENGTH {
+                        return Err(RpcError::Custom("Section is too long".into()));
+                    }
+
+                    let compressed = match self.compression {
+                        Some(algorithm) if section.len() >= self.compression_threshold => {
+                            Some(algorithm.compress(section)?)
+                                .filter(|compressed| compressed.len() < section.len())
+                        }
+                        _ => None,
+                    };
+
+                    // Both lengths are below `MAX_SECTION_LENGTH`, so they fit the prefix
+                    match &compressed {
+                        Some(compressed) => {
+                            let prefix = compressed.len() as u32 | COMPRESSED;
+                            buffer.extend_from_slice(&prefix.to_be_bytes());
+                            buffer.extend_from_slice(compressed);
+                        }
+                        None => {
+                            buffer.extend_from_slice(&(section.len() as u32).to_be_bytes());
+                            buffer.extend_from_slice(section);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.writer
+            .write_all(&buffer)
+            .await
+            .map_err(RpcError::transport)?;
+        self.writer.flush().await.map_err(RpcError::transport)?;
+
+        Ok(())
+    }
+}
+
+/// Client side of the framing negotiation, must run before any request is sent
+///
+/// # Errors
+/// Can fail if the connection fails or the server does not accept the requested framing
+pub async fn negotiate_framing(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+) -> Result<(), RpcError> {
+    if framing == Framing::JsonLines {
+        return Ok(());
+    }
+
+    writer
+        .write_all(BINARY_PREAMBLE)
+        .await
+        .map_err(RpcError::transport)?;
+    writer.flush().await.map_err(RpcError::transport)?;
+
+    let mut response = [0; BINARY_PREAMBLE.len()];
+    reader
+        .read_exact(&mut response)
+        .await
+        .map_err(RpcError::transport)?;
+
+    if &response != BINARY_PREAMBLE {
+        return Err(RpcError::Custom(
+            "The server did not accept binary framing".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Server side of the framing negotiation. Clients that start with anything other than the
+/// binary preamble are assumed to speak JSON lines.
+///
+/// # Errors
+/// Can fail if the connection fails or the client sends an invalid preamble
+pub async fn accept_framing(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<Framing, RpcError> {
+    let buffer = reader.fill_buf().await.map_err(RpcError::transport)?;
+
+    if buffer.is_empty() {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+
+    if buffer[0] != BINARY_PREAMBLE[0] {
+        return Ok(Framing::JsonLines);
+    }
+
+    let mut preamble = [0; BINARY_PREAMBLE.len()];
+    reader
+        .read_exact(&mut preamble)
+        .await
+        .map_err(RpcError::transport)?;
+
+    if &preamble != BINARY_PREAMBLE {
+        return Err(RpcError::Custom("Invalid connection preamble".into()));
+    }
+
+    writer
+        .write_all(BINARY_PREAMBLE)
+        .await
+        .map_err(RpcError::transport)?;
+    writer.flush().await.map_err(RpcError::transport)?;
+
+    Ok(Framing::Binary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn roundtrip(framing: Framing, sections: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut buffer = vec![];
+        FrameWriter::new(&mut buffer, framing)
+            .write_frame(sections)
+            .await
+            .unwrap();
+
+        let mut reader = FrameReader::new(BufReader::new(buffer.as_slice()), framing);
+        let mut result = vec![];
+        for _ in sections {
+            result.push(reader.read_section().await.unwrap());
+        }
+
+        assert!(reader.read_section().await.is_err());
+
+        result
+    }
+
+    #[tokio::test]
+    async fn binary_sections_can_contain_newlines() {
+        let sections: [&[u8]; 3] = [b"{\"a\":1}", b"\n\n", &[0, 255, b'\n', 7]];
+
+        assert_eq!(
+            sections.to_vec(),
+            roundtrip(Framing::Binary, &sections).await
+        );
+    }
+
+    #[tokio::test]
+    async fn json_lines_sections_roundtrip() {
+        let sections: [&[u8]; 3] = [b"{\"a\":1}", b"{}", b"null"];
+
+        assert_eq!(
+            sections.to_vec(),
+            roundtrip(Framing::JsonLines, &sections).await
+        );
+    }
+
+    #[tokio::test]
+    async fn large_binary_sections_are_compressed() {
+        let large = "payload ".repeat(1000).into_bytes();
+        let sections: [&[u8]; 2] = [b"{\"request_id\":1}", &large];
+
+        let mut buffer = vec![];
+        let mut writer = FrameWriter::new(&mut buffer, Framing::Binary);
+        writer.set_compression(Some(Algorithm::Zstd), 1024);
+        writer.write_frame(&sections).await.unwrap();
+        assert!(buffer.len() < large.len() / 10);
+
+        let mut reader = FrameReader::new(BufReader::new(buffer.as_slice()), Framing::Binary);
+        reader.set_compression(Some(Algorithm::Zstd));
+        assert_eq!(sections[0], reader.read_section().await.unwrap());
+        assert_eq!(large, reader.read_section().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn json_lines_rejects_newlines() {
+        let mut buffer = vec![];
+        let result = FrameWriter::new(&mut buffer, Framing::JsonLines)
+            .write_frame(&[b"a\nb"])
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_detects_framing() {
+        let mut response = vec![];
+        let mut reader = BufReader::new(&b"\0APB{}"[..]);
+        assert_eq!(
+            Framing::Binary,
+            accept_framing(&mut reader, &mut response).await.unwrap()
+        );
+        assert_eq!(BINARY_PREAMBLE.to_vec(), response);
+
+        let mut response = vec![];
+        let mut reader = BufReader::new(&b"{\"method_name\":\"x\"}\n"[..]);
+        assert_eq!(
+            Framing::JsonLines,
+            accept_framing(&mut reader, &mut response).await.unwrap()
+        );
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::compression::{Algorithm, Compression};
+use crate::framing::{FrameReader, FrameWriter, Framing};
+use crate::rpc_error::{ErrorCode, RpcError};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use tracing::warn;
+
+/// Version of the wire protocol, incremented on incompatible changes to the envelopes or framing
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Sent by both ends of a connection once the framing is negotiated, before any request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    /// Name of the `.evd` file the code of the service was generated from. Empty for generic
+    /// tooling that connects to any service.
+    pub service_name: String,
+    /// Hash of the `.evd` file, differs if the ends were built from different versions of it.
+    /// Empty if unknown.
+    pub schema_hash: String,
+    /// Compression algorithms the client offers, in order of preference. The server answers with
+    /// the one it picked, or none. Filled in from the [`Compression`] of each end.
+    #[serde(default)]
+    pub compression: Vec<Algorithm>,
+}
+
+impl Handshake {
+    #[must_use]
+    pub fn new(service_name: impl Into<String>, schema_hash: impl Into<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            service_name: service_name.into(),
+            schema_hash: schema_hash.into(),
+            compression: vec![],
+        }
+    }
+
+    /// Handshake of a client that accepts any service, e.g. a generic command line client
+    #[must_use]
+    pub fn any_service() -> Self {
+        Self::new("", "")
+    }
+
+    /// Peers built from another version of the schema are accepted with a warning, calls whose
+    /// types didn't change keep working. An empty service name or schema hash matches any.
+    ///
+    /// # Errors
+    /// Fails if the peer speaks another protocol version or is another service
+    pub fn check(&self, peer: &Self) -> Result<(), RpcError> {
+        if peer.protocol_version != self.protocol_version {
+            return Err(mismatch(format!(
+                "The peer speaks protocol version {}, this end version {}",
+                peer.protocol_version, self.protocol_version
+            )));
+        }
+
+        let generic = self.service_name.is_empty() || peer.service_name.is_empty();
+        if !generic && peer.service_name != self.service_name {
+            return Err(mismatch(format!(
+                "The peer is the {} service, this end expects the {} service",
+                peer.service_name, self.service_name
+            )));
+        }
+
+        let unknown = self.schema_hash.is_empty() || peer.schema_hash.is_empty();
+        if !unknown && peer.schema_hash != self.schema_hash {
+            warn!(
+                "The peer was built from another version of the {} schema ({} instead of {}), calls whose types changed will fail",
+                self.service_name, peer.schema_hash, self.schema_hash
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn mismatch(message: String) -> RpcError {
+    RpcError::status(ErrorCode::FailedPrecondition, message)
+}
+
+/// Client side of the handshake, must run right after the framing negotiation. Offers the
+/// algorithms of `compression` and sets up `reader` and `writer` with the one the server picked.
+/// Returns the handshake of the server.
+///
+/// # Errors
+/// Can fail if the connection fails or the server doesn't match `ours`, see [`Handshake::check`]
+pub async fn exchange_handshake<R, W>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    ours: &Handshake,
+    compression: &Compression,
+) -> Result<Handshake, RpcError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Only binary connections are compressed
+    let offered = match writer.framing() {
+        Framing::JsonLines => vec![],
+        Framing::Binary => compression.algorithms.clone(),
+    };
+    let ours = Handshake {
+        compression: offered,
+        ..ours.clone()
+    };
+    writer.write_frame(&[&serde_json::to_vec(&ours)?]).await?;
+
+    // Servers that predate the handshake fail to parse it as a request and close the connection
+    let theirs = reader
+        .read_section()
+        .await
+        .and_then(|section| Ok(serde_json::from_slice::<Handshake>(&section)?))
+        .map_err(|e| {
+            mismatch(format!(
+                "The server did not answer the handshake, it is no {} server of protocol version {}: {}",
+                ours.service_name, ours.protocol_version, e
+            ))
+        })?;
+
+    ours.check(&theirs)?;
+
+    let algorithm = match theirs.compression.as_slice() {
+        [] => None,
+        [algorithm] if ours.compression.contains(algorithm) => Some(*algorithm),
+        picked => {
+            return Err(mismatch(format!(
+                "The server picked the compression {:?}, this end offered {:?}",
+                picked, ours.compression
+            )))
+        }
+    };
+    reader.set_compression(algorithm);
+    writer.set_compression(algorithm, compression.threshold);
+
+    Ok(theirs)
+}
+
+/// Server side of the handshake, must run right after the framing negotiation. Answers with
+/// `ours` in any case, so clients can tell what they connected to, and the first of the offered
+/// algorithms `compression` supports. Sets up `reader` and `writer` with that algorithm. Returns
+/// the handshake of the client.
+///
+/// # Errors
+/// Can fail if the connection fails or the client doesn't match `ours`, see [`Handshake::check`]
+pub async fn accept_handshake<R, W>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    ours: &Handshake,
+    compression: &Compression,
+) -> Result<Handshake, RpcError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let theirs = serde_json::from_slice::<Handshake>(&reader.read_section().await?)
+        .map_err(|e| mismatch(format!("The client did not start with a handshake: {}", e)));
+
+    let algorithm = match (&theirs, writer.framing()) {
+        (Ok(theirs), Framing::Binary) => compression.negotiate(&theirs.compression),
+        _ => None,
+    };
+
+    let ours = Handshake {
+        compression: algorithm.into_iter().collect(),
+        ..ours.clone()
+    };
+    writer.write_frame(&[&serde_json::to_vec(&ours)?]).await?;
+
+    let theirs = theirs?;
+    ours.check(&theirs)?;
+
+    reader.set_compression(algorithm);
+    writer.set_compression(algorithm, compression.threshold);
+
+    Ok(theirs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::BufReader;
+
+    async fn handshake(client: &Handshake, server: &Handshake) -> [Result<Handshake, RpcError>; 2] {
+        handshake_with(
+            Framing::JsonLines,
+            (client, &Compression::default()),
+            (server, &Compression::default()),
+        )
+        .await
+    }
+
+    async fn handshake_with(
+        framing: Framing,
+        (client, client_compression): (&Handshake, &Compression),
+        (server, server_compression): (&Handshake, &Compression),
+    ) -> [Result<Handshake, RpcError>; 2] {
+        let (client_socket, server_socket) = tokio::io::duplex(1024);
+        let (client_read, client_write) = tokio::io::split(client_socket);
+        let (server_read, server_write) = tokio::io::split(server_socket);
+
+        let mut client_reader = FrameReader::new(BufReader::new(client_read), framing);
+        let mut client_writer = FrameWriter::new(client_write, framing);
+        let mut server_reader = FrameReader::new(BufReader::new(server_read), framing);
+        let mut server_writer = FrameWriter::new(server_write, framing);
+
+        let (client, server) = tokio::join!(
+            exchange_handshake(
+                &mut client_reader,
+                &mut client_writer,
+                client,
+                client_compression
+            ),
+            accept_handshake(
+                &mut server_reader,
+                &mut server_writer,
+                server,
+                server_compression
+            ),
+        );
+
+        [client, server]
+    }
+
+    #[tokio::test]
+    async fn other_schema_versions_are_accepted() {
+        let [client, server] = handshake(
+            &Handshake::new("events", "a"),
+            &Handshake::new("events", "b"),
+        )
+        .await;
+
+        assert_eq!("b", client.unwrap().schema_hash);
+        assert_eq!("a", server.unwrap().schema_hash);
+    }
+
+    #[tokio::test]
+    async fn other_services_are_rejected_by_both_ends() {
+        let [client, server] = handshake(
+            &Handshake::new("music", "a"),
+            &Handshake::new("events", "a"),
+        )
+        .await;
+
+        for result in [client, server] {
+            let error = result.unwrap_err();
+            assert_eq!(ErrorCode::FailedPrecondition, error.code());
+            assert!(error.to_string().contains("events service"));
+        }
+    }
+
+    #[tokio::test]
+    async fn generic_clients_accept_any_service() {
+        let [client, server] =
+            handshake(&Handshake::any_service(), &Handshake::new("events", "a")).await;
+
+        assert_eq!("events", client.unwrap().service_name);
+        assert!(server.is_ok());
+    }
+
+    #[tokio::test]
+    async fn the_server_picks_the_compression() {
+        let handshake = Handshake::new("events", "a");
+        let server_compression = Compression {
+            algorithms: vec![Algorithm::Deflate],
+            ..Compression::default()
+        };
+
+        let [client, server] = handshake_with(
+            Framing::Binary,
+            (&handshake, &Compression::default()),
+            (&handshake, &server_compression),
+        )
+        .await;
+        assert_eq!(vec![Algorithm::Deflate], client.unwrap().compression);
+        assert_eq!(
+            vec![Algorithm::Zstd, Algorithm::Deflate],
+            server.unwrap().compression
+        );
+
+        let [client, _] = handshake_with(
+            Framing::JsonLines,
+            (&handshake, &Compression::default()),
+            (&handshake, &server_compression),
+        )
+        .await;
+        assert!(client.unwrap().compression.is_empty());
+    }
+}
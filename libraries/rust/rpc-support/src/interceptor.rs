@@ -0,0 +1,349 @@
+use crate::rpc_error::RpcError;
+use crate::trace_context::TraceContext;
+use crate::transport::Peer;
+use futures::future::BoxFuture;
+use serde_json::Value;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// What an [`Interceptor`] knows about a call
+pub struct CallInfo<TMetadata> {
+    pub method_name: String,
+    pub request_id: u64,
+    pub metadata: TMetadata,
+    pub peer: Arc<Peer>,
+    /// When the server read the request
+    pub received_at: Instant,
+    /// Context the call is handled in, a child of the one of the client
+    pub trace: TraceContext,
+}
+
+/// Wraps the handling of every call of a server, e.g. for logging, authentication or metrics.
+/// Interceptors run in the order they were added to the server, each one decides whether the
+/// call continues by running `next`.
+#[async_trait::async_trait]
+pub trait Interceptor<TMetadata>: Send + Sync {
+    /// Returns the status of the call. Errors returned without running `next` reject the call,
+    /// the client receives them instead of a response.
+    async fn intercept(
+        &self,
+        call: &CallInfo<TMetadata>,
+        next: Next<'_, TMetadata>,
+    ) -> Result<(), RpcError>;
+}
+
+pub type Interceptors<TMetadata> = Arc<[Arc<dyn Interceptor<TMetadata>>]>;
+
+type Handler<'a> = Pin<Box<dyn Future<Output = Result<(), RpcError>> + Send + 'a>>;
+
+/// The rest of the chain, ending with the handler of the call
+pub struct Next<'a, TMetadata> {
+    interceptors: &'a [Arc<dyn Interceptor<TMetadata>>],
+    handler: Handler<'a>,
+}
+
+impl<'a, TMetadata> Next<'a, TMetadata>
+where
+    TMetadata: Sync,
+{
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn Interceptor<TMetadata>>],
+        handler: Handler<'a>,
+    ) -> Self {
+        Self {
+            interceptors,
+            handler,
+        }
+    }
+
+    /// Returns the status the client received, an error for calls that failed
+    ///
+    /// # Errors
+    /// Returns the error the call failed with
+    pub async fn run(self, call: &CallInfo<TMetadata>) -> Result<(), RpcError> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                interceptor
+                    .intercept(call, Next::new(interceptors, self.handler))
+                    .await
+            }
+            None => self.handler.await,
+        }
+    }
+}
+
+/// Logs every call with its status and duration
+pub struct LoggingInterceptor;
+
+#[async_trait::async_trait]
+impl<TMetadata> Interceptor<TMetadata> for LoggingInterceptor
+where
+    TMetadata: Sync,
+{
+    async fn intercept(
+        &self,
+        call: &CallInfo<TMetadata>,
+        next: Next<'_, TMetadata>,
+    ) -> Result<(), RpcError> {
+        let result = next.run(call).await;
+        let elapsed = call.received_at.elapsed();
+
+        match &result {
+            Ok(()) => info!(
+                "{} #{} from {} finished after {:?}",
+                call.method_name, call.request_id, call.peer, elapsed
+            ),
+            Err(e) => warn!(
+                "{} #{} from {} failed after {:?}: {}",
+                call.method_name, call.request_id, call.peer, elapsed, e
+            ),
+        }
+
+        result
+    }
+}
+
+/// Fields of the metadata section of a call
+pub type MetadataFields = serde_json::Map<String, Value>;
+
+/// A call as the client is about to send it
+#[derive(Clone, Debug)]
+pub struct OutgoingCall {
+    pub method_name: String,
+    /// Id of the first attempt, the client sends every retry with an id of its own
+    pub request_id: u64,
+    pub metadata: MetadataFields,
+    /// Time the server has to answer, the timeout of the client unless an interceptor changes it
+    pub timeout: Option<Duration>,
+    /// Set for calls declared `idempotent`, the same for every attempt. Servers handle the
+    /// attempts of a call only once, so only these calls may be retried.
+    pub idempotency_key: Option<String>,
+}
+
+/// Wraps every call a client sends, e.g. to fill in its metadata or to retry it. Interceptors
+/// run in the order they were added to the client.
+#[async_trait::async_trait]
+pub trait ClientInterceptor: Send + Sync {
+    /// Sends the call by running `next`, possibly more than once. Streams are sent once their
+    /// call is, the items that follow don't pass through the interceptors.
+    async fn intercept(
+        &self,
+        call: &mut OutgoingCall,
+        next: ClientNext<'_>,
+    ) -> Result<(), RpcError>;
+}
+
+pub(crate) type SendCall<'a> =
+    dyn Fn(&OutgoingCall) -> BoxFuture<'a, Result<(), RpcError>> + Send + Sync + 'a;
+
+/// The rest of the chain, ending with sending the call
+pub struct ClientNext<'a> {
+    interceptors: &'a [Arc<dyn ClientInterceptor>],
+    send: &'a SendCall<'a>,
+}
+
+impl<'a> ClientNext<'a> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn ClientInterceptor>],
+        send: &'a SendCall<'a>,
+    ) -> Self {
+        Self { interceptors, send }
+    }
+
+    /// Every run sends `call` again. The request stream of a call can only be sent once, later
+    /// runs fail.
+    ///
+    /// # Errors
+    /// Returns the error the call failed with
+    pub async fn run(&self, call: &OutgoingCall) -> Result<(), RpcError> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let mut call = call.clone();
+                interceptor
+                    .intercept(&mut call, ClientNext::new(interceptors, self.send))
+                    .await
+            }
+            None => (self.send)(call).await,
+        }
+    }
+}
+
+/// Adds fields to the metadata of every call that doesn't set them itself, e.g. the name of the
+/// calling service
+#[derive(Default)]
+pub struct MetadataDefaults {
+    fields: Vec<(String, Box<dyn Fn() -> Value + Send + Sync>)>,
+}
+
+impl MetadataDefaults {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with(self, field: impl Into<String>, value: impl Into<Value>) -> Self {
+        let value = value.into();
+        self.with_generated(field, move || value.clone())
+    }
+
+    /// Generates the value for every call, e.g. a trace id
+    #[must_use]
+    pub fn with_generated(
+        mut self,
+        field: impl Into<String>,
+        generate: impl Fn() -> Value + Send + Sync + 'static,
+    ) -> Self {
+        self.fields.push((field.into(), Box::new(generate)));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl ClientInterceptor for MetadataDefaults {
+    async fn intercept(
+        &self,
+        call: &mut OutgoingCall,
+        next: ClientNext<'_>,
+    ) -> Result<(), RpcError> {
+        for (field, generate) in &self.fields {
+            call.metadata.entry(field.as_str()).or_insert_with(generate);
+        }
+
+        next.run(call).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Record {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Interceptor<()> for Record {
+        async fn intercept(&self, call: &CallInfo<()>, next: Next<'_, ()>) -> Result<(), RpcError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} before", self.name));
+            let result = next.run(call).await;
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} after", self.name));
+
+            result
+        }
+    }
+
+    #[tokio::test]
+    async fn interceptors_wrap_the_handler_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let interceptors: Vec<Arc<dyn Interceptor<()>>> = vec![
+            Arc::new(Record {
+                name: "outer",
+                log: log.clone(),
+            }),
+            Arc::new(Record {
+                name: "inner",
+                log: log.clone(),
+            }),
+        ];
+        let call = CallInfo {
+            method_name: "call".to_string(),
+            request_id: 1,
+            metadata: (),
+            peer: Arc::new(Peer::new("test")),
+            received_at: Instant::now(),
+            trace: TraceContext::new_root(),
+        };
+
+        let handler_log = log.clone();
+        let handler = Box::pin(async move {
+            handler_log.lock().unwrap().push("handler".to_string());
+
+            Err(RpcError::Cancelled)
+        });
+        let result = Next::new(&interceptors, handler).run(&call).await;
+
+        assert!(matches!(result, Err(RpcError::Cancelled)));
+        assert_eq!(
+            vec![
+                "outer before",
+                "inner before",
+                "handler",
+                "inner after",
+                "outer after"
+            ],
+            *log.lock().unwrap()
+        );
+    }
+
+    /// Sends calls a second time when they fail
+    struct RetryOnce;
+
+    #[async_trait::async_trait]
+    impl ClientInterceptor for RetryOnce {
+        async fn intercept(
+            &self,
+            call: &mut OutgoingCall,
+            next: ClientNext<'_>,
+        ) -> Result<(), RpcError> {
+            match next.run(call).await {
+                Ok(()) => Ok(()),
+                Err(_) => next.run(call).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn client_interceptors_fill_metadata_and_retry() {
+        let interceptors: Vec<Arc<dyn ClientInterceptor>> = vec![
+            Arc::new(RetryOnce),
+            Arc::new(
+                MetadataDefaults::new()
+                    .with("source", "test")
+                    .with("trace_id", "default"),
+            ),
+        ];
+        let mut metadata = MetadataFields::new();
+        metadata.insert("trace_id".to_string(), "set".into());
+        let call = OutgoingCall {
+            method_name: "call".to_string(),
+            request_id: 1,
+            metadata,
+            timeout: None,
+            idempotency_key: None,
+        };
+
+        let sent = Mutex::new(Vec::new());
+        let send = |call: &OutgoingCall| -> BoxFuture<'_, Result<(), RpcError>> {
+            let mut sent = sent.lock().unwrap();
+            sent.push(call.metadata.clone());
+            let attempt = sent.len();
+
+            Box::pin(async move {
+                match attempt {
+                    1 => Err(RpcError::unavailable("first attempt")),
+                    _ => Ok(()),
+                }
+            })
+        };
+        let result = ClientNext::new(&interceptors, &send).run(&call).await;
+
+        let sent = sent.into_inner().unwrap();
+        assert!(result.is_ok());
+        assert_eq!(2, sent.len());
+        assert_eq!(Some(&Value::from("test")), sent[1].get("source"));
+        assert_eq!(Some(&Value::from("set")), sent[1].get("trace_id"));
+    }
+}
@@ -1,24 +1,119 @@
+use crate::compression::Compression;
+use crate::connection::{ActiveStream, RequestSender};
+use crate::framing::{FrameReader, FrameWriter, Framing};
+use crate::handshake::Handshake;
+use crate::interceptor::{ClientInterceptor, ClientNext, MetadataFields, OutgoingCall};
+use crate::pool::{Connections, Pool, PooledConnection, Resolver};
 use crate::rpc_error::RpcError;
-use dashmap::DashMap;
+use crate::server::{shutting_down, Call, Request, ResponseWriter};
+use crate::trace_context::TraceContext;
+use crate::transport::Connector;
+use futures::future::{AbortHandle, Abortable, BoxFuture};
 use futures::{Stream, StreamExt};
-use platform::async_infra::run_with_error_handling;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::{error, info};
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::Semaphore;
+use tracing::{debug, error, trace, warn};
 
+pub mod bytes_serializer;
+pub mod compression;
+mod connection;
+pub mod deduplication;
+pub mod framing;
+pub mod handshake;
+pub mod interceptor;
+pub mod metrics;
+pub mod pool;
+pub mod reflection;
+pub mod retry;
 pub mod rpc_error;
+pub mod server;
 pub mod system_time_serializer;
+pub mod tls;
+pub mod trace_context;
+pub mod transport;
+pub mod websocket;
+
+/// Items of a stream the receiving side may have to buffer before it grants more. Bounds what is
+/// buffered for a slow consumer, so it can't hold up the other calls on the same connection.
+/// Request streams start with this many credits, response streams request them in their call.
+const STREAM_CREDITS: u32 = 32;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum RequestKind {
+    /// Followed by the metadata and payload sections, or only the metadata for request streams
+    #[default]
+    Call,
+    /// Followed by the payload section, an item of the request stream of a call
+    Item,
+    /// Sent without further sections once the request stream of a call is complete
+    EndOfInput,
+    /// Sent without further sections when the caller is no longer interested in the response
+    Cancel,
+    /// Sent without further sections when the caller consumed items of a stream, allows the
+    /// server to send `credits` more items
+    Credit,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct RequestEnvelope {
+    #[serde(default)]
     pub method_name: String,
     pub request_id: u64,
+    #[serde(default)]
+    pub kind: RequestKind,
+    /// Time the server has to answer. Relative, so the clocks of both sides don't have to agree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Items of a stream the server may send. Streams of clients that don't send it are not
+    /// flow controlled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credits: Option<u32>,
+    /// The requests of the call follow in [`RequestKind::Item`] frames
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub request_stream: bool,
+    /// Context of the caller, the server handles the call in a child span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
+    /// The same for every attempt of an idempotent call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+impl RequestEnvelope {
+    /// Envelope of the frames that refer to a call that was already sent
+    fn for_call(request_id: u64, kind: RequestKind) -> Self {
+        Self {
+            method_name: String::new(),
+            request_id,
+            kind,
+            timeout_ms: None,
+            credits: None,
+            request_stream: false,
+            trace: None,
+            idempotency_key: None,
+        }
+    }
+
+    fn cancel(request_id: u64) -> Self {
+        Self::for_call(request_id, RequestKind::Cancel)
+    }
+
+    fn credit(request_id: u64, credits: u32) -> Self {
+        Self {
+            credits: Some(credits),
+            ..Self::for_call(request_id, RequestKind::Credit)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -26,23 +121,82 @@ struct ResponseEnvelope {
     pub request_id: u64,
     pub error: Option<RpcError>,
     pub stream_end: bool,
+    /// Sent without a payload section, allows the client to send `credits` more items of the
+    /// request stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credits: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct StreamResponseEnvelope {
-    pub request_id: u64,
-    pub error: Option<RpcError>,
+impl ResponseEnvelope {
+    const fn credit(request_id: u64, credits: u32) -> Self {
+        Self {
+            request_id,
+            error: None,
+            stream_end: false,
+            credits: Some(credits),
+        }
+    }
 }
 
-type WaitingResponses = DashMap<u64, Sender<(ResponseEnvelope, Option<String>)>>;
-type ActiveStreams = DashMap<u64, Sender<(ResponseEnvelope, Option<String>)>>;
 type ResponseStream<TResponse> =
     Pin<Box<dyn Stream<Item = Result<TResponse, RpcError>> + Unpin + Send>>;
+type RequestStream<TRequest> =
+    Pin<Box<dyn Stream<Item = Result<TRequest, RpcError>> + Unpin + Send>>;
+type Response = (ResponseEnvelope, Option<Vec<u8>>);
 
 pub struct RawRpcClient {
-    waiting_responses: Arc<WaitingResponses>,
-    active_streams: Arc<ActiveStreams>,
-    request_tx: Sender<String>,
+    connections: Arc<Connections>,
+    framing: Framing,
+    timeout: Option<Duration>,
+    interceptors: Vec<Arc<dyn ClientInterceptor>>,
+    /// Request ids of retried attempts. Counts down from the top, callers count their ids up.
+    retry_ids: AtomicU64,
+}
+
+/// Removes a call from the pending calls once its future or stream is dropped and tells the
+/// server to cancel it, unless the response was already received completely
+struct CallGuard {
+    request_id: u64,
+    stream: bool,
+    connection: Arc<PooledConnection>,
+    finished: bool,
+    /// Task sending the request stream of a bidirectional call
+    sending: Option<AbortHandle>,
+}
+
+impl CallGuard {
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        let state = &self.connection.state;
+        if self.stream {
+            state.active_streams.remove(&self.request_id);
+        } else {
+            state.waiting_responses.remove(&self.request_id);
+        }
+        state.request_credits.remove(&self.request_id);
+        self.connection.load.fetch_sub(1, Ordering::AcqRel);
+        if let Some(sending) = &self.sending {
+            sending.abort();
+        }
+
+        if self.finished {
+            return;
+        }
+
+        match serde_json::to_vec(&RequestEnvelope::cancel(self.request_id)) {
+            Ok(envelope) => {
+                if let Err(e) = self.connection.request_tx.try_send(vec![envelope]) {
+                    debug!("Failed to cancel request {}: {}", self.request_id, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize cancel request: {}", e),
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -53,6 +207,8 @@ pub enum RpcClientTaskError {
     Mpsc(String),
     #[error("{0}")]
     Serde(#[from] serde_json::Error),
+    #[error("{0}")]
+    Rpc(#[from] RpcError),
 }
 
 impl<T> From<tokio::sync::mpsc::error::SendError<T>> for RpcClientTaskError {
@@ -61,93 +217,191 @@ impl<T> From<tokio::sync::mpsc::error::SendError<T>> for RpcClientTaskError {
     }
 }
 
-async fn client_response_task(
-    read: OwnedReadHalf,
-    waiting_responses: Arc<WaitingResponses>,
-    active_streams: Arc<ActiveStreams>,
-) -> Result<(), RpcClientTaskError> {
-    let mut reader = tokio::io::BufReader::new(read);
-
-    loop {
-        let mut response_envelope_line = String::new();
-        reader.read_line(&mut response_envelope_line).await?;
-        let response_envelope: ResponseEnvelope = serde_json::from_str(&response_envelope_line)?;
-
-        // todo pass the error
-        if let Some(ref _error) = response_envelope.error {
-            if let Some((_, sender)) = waiting_responses.remove(&response_envelope.request_id) {
-                sender.send((response_envelope, None)).await?;
-                continue;
-            }
-            error!(
-                "Found response, but no request. Request ID: {}",
-                response_envelope.request_id
-            );
+/// Owns the write half of a connection and writes every frame sent through `channel`
+async fn write_frames_task<W>(
+    mut writer: FrameWriter<W>,
+    mut channel: Receiver<Vec<Vec<u8>>>,
+) -> Result<(), RpcClientTaskError>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(sections) = channel.recv().await {
+        writer
+            .write_frame(&sections.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            .await?;
+    }
 
-            continue;
-        }
+    Ok(())
+}
 
-        let mut response_line = String::new();
-        reader.read_line(&mut response_line).await?;
-
-        if let Some((_, sender)) = waiting_responses.remove(&response_envelope.request_id) {
-            sender
-                .send((response_envelope, Some(response_line)))
-                .await?;
-        } else if let Some(sender) = active_streams.get(&response_envelope.request_id) {
-            sender
-                .send((response_envelope, Some(response_line)))
-                .await?;
-        } else {
-            error!(
-                "Found response, but no request. Request ID: {}",
-                response_envelope.request_id
-            );
+impl RawRpcClient {
+    /// Connects through `connector`. Lost connections are re-established in the background, calls
+    /// that were in flight fail with [`RpcError::ConnectionLost`]. Large payloads are compressed
+    /// with the first algorithm of `compression` the server supports.
+    ///
+    /// # Errors
+    /// Can fail if the server is not reachable, does not accept the requested framing or does not
+    /// match `handshake`, see [`Handshake::check`]
+    pub async fn connect(
+        connector: impl Connector + 'static,
+        framing: Framing,
+        handshake: Handshake,
+        compression: Compression,
+    ) -> Result<Self, RpcError> {
+        let connections = Connections::single(connector, framing, handshake, compression).await?;
+
+        Ok(Self::with_connections(connections, framing))
+    }
+
+    /// Connects to every endpoint `resolver` finds, e.g. a [`pool::DnsResolver`], and spreads
+    /// the calls over them as `pool` says. Streams stay on the connection they were opened on.
+    ///
+    /// # Errors
+    /// Can fail if none of the endpoints is reachable, see [`RawRpcClient::connect`]
+    pub async fn connect_pool(
+        resolver: impl Resolver + 'static,
+        pool: Pool,
+        framing: Framing,
+        handshake: Handshake,
+        compression: Compression,
+    ) -> Result<Self, RpcError> {
+        let connections =
+            Connections::resolve(resolver, pool, framing, handshake, compression).await?;
+
+        Ok(Self::with_connections(connections, framing))
+    }
+
+    fn with_connections(connections: Arc<Connections>, framing: Framing) -> Self {
+        RawRpcClient {
+            connections,
+            framing,
+            timeout: None,
+            interceptors: Vec::new(),
+            retry_ids: AtomicU64::new(u64::MAX),
         }
     }
-}
 
-async fn client_request_task(
-    mut writer: OwnedWriteHalf,
-    mut channel: Receiver<String>,
-) -> Result<(), RpcClientTaskError> {
-    while let Some(request_line) = channel.recv().await {
-        writer.write_all(request_line.as_bytes()).await?;
-        writer.flush().await?;
+    /// Whether any connection is established, calls fail fast with
+    /// [`RpcError::ConnectionLost`] otherwise
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.connections.is_connected()
     }
 
-    Ok(())
-}
+    /// When set, active streams are requested again after a reconnect instead of failing with
+    /// [`RpcError::ConnectionLost`]. The original request is repeated as is, so consumers may see
+    /// items again that they already received before the connection was lost.
+    pub fn set_resubscribe(&mut self, resubscribe: bool) {
+        self.connections.set_resubscribe(resubscribe);
+    }
 
-impl RawRpcClient {
-    pub fn new(stream: tokio::net::TcpStream) -> Self {
-        let (read, write) = stream.into_split();
-        let waiting_responses = Arc::new(DashMap::new());
-        let active_streams = Arc::new(DashMap::new());
+    /// Sets the time the server has to answer subsequent calls, streams have to end within it.
+    /// Calls that run out of time fail with [`RpcError::DeadlineExceeded`] and are cancelled.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
 
-        tokio::task::spawn(run_with_error_handling(client_response_task(
-            read,
-            waiting_responses.clone(),
-            active_streams.clone(),
-        )));
+    /// Runs every subsequent call through `interceptor`, after the ones that were added before
+    pub fn add_interceptor(&mut self, interceptor: impl ClientInterceptor + 'static) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
 
-        let (request_tx, request_rx) = tokio::sync::mpsc::channel(64);
+    /// Asks the server for the schema of its service, without metadata of the service. Works
+    /// with every generated server, regardless of the service the client was built for.
+    ///
+    /// # Errors
+    /// Can fail if the connection fails or the server predates reflection
+    pub async fn reflect(&self, id: u64) -> Result<reflection::Schema, RpcError> {
+        self.send_rpc(id, reflection::METHOD_NAME, &(), &()).await
+    }
 
-        tokio::task::spawn(run_with_error_handling(client_request_task(
-            write, request_rx,
-        )));
+    fn outgoing_call<TMetadata>(
+        &self,
+        id: u64,
+        method_name: &str,
+        metadata: &TMetadata,
+    ) -> Result<OutgoingCall, RpcError>
+    where
+        TMetadata: Serialize,
+    {
+        let metadata = match serde_json::to_value(metadata)? {
+            serde_json::Value::Object(fields) => fields,
+            serde_json::Value::Null => MetadataFields::new(),
+            _ => {
+                return Err(RpcError::invalid_argument(
+                    "Metadata has to be serialized as an object",
+                ))
+            }
+        };
 
-        RawRpcClient {
-            waiting_responses,
-            active_streams,
-            request_tx,
+        Ok(OutgoingCall {
+            method_name: method_name.to_string(),
+            request_id: id,
+            metadata,
+            timeout: self.timeout,
+            idempotency_key: None,
+        })
+    }
+
+    /// Runs `call` through the interceptors, `send` sends it and returns its response. Every
+    /// attempt after the first is sent with a request id of its own, so late answers to an
+    /// earlier attempt, e.g. to the cancellation of one that ran out of time, can't complete it.
+    async fn intercept<'a, T>(
+        &'a self,
+        call: OutgoingCall,
+        send: impl Fn(&OutgoingCall) -> BoxFuture<'a, Result<T, RpcError>> + Send + Sync + 'a,
+    ) -> Result<T, RpcError>
+    where
+        T: Send + 'a,
+    {
+        let response = std::sync::Mutex::new(None);
+        let attempts = AtomicU32::new(0);
+        let send_call = |call: &OutgoingCall| -> BoxFuture<'_, Result<(), RpcError>> {
+            let sending = if attempts.fetch_add(1, Ordering::AcqRel) == 0 {
+                send(call)
+            } else {
+                send(&OutgoingCall {
+                    request_id: self.retry_ids.fetch_sub(1, Ordering::AcqRel),
+                    ..call.clone()
+                })
+            };
+            let response = &response;
+
+            Box::pin(async move {
+                let result = sending.await?;
+                *response.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+
+                Ok(())
+            })
+        };
+
+        ClientNext::new(&self.interceptors, &send_call)
+            .run(&call)
+            .await?;
+
+        response
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .ok_or_else(|| RpcError::internal("The call was not sent by the interceptors"))
+    }
+
+    /// Counts the call towards the load of `connection` until the guard is dropped
+    fn guard(connection: &Arc<PooledConnection>, id: u64, stream: bool) -> CallGuard {
+        connection.load.fetch_add(1, Ordering::AcqRel);
+
+        CallGuard {
+            request_id: id,
+            stream,
+            connection: connection.clone(),
+            finished: false,
+            sending: None,
         }
     }
 
     /// # Errors
     /// Can fail if sending the request fails or if the call returns an error
     pub async fn send_rpc<TRequest, TMetadata, TResponse>(
-        &mut self,
+        &self,
         id: u64,
         method_name: &str,
         request: &TRequest,
@@ -158,69 +412,210 @@ impl RawRpcClient {
         TRequest: Serialize,
         TResponse: DeserializeOwned,
     {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-        self.waiting_responses.insert(id, tx);
+        let call = self.outgoing_call(id, method_name, metadata)?;
 
-        self.send_raw_request(
-            &RequestEnvelope {
-                method_name: method_name.to_string(),
-                request_id: id,
-            },
-            &metadata,
-            &request,
-        )
-        .await?;
+        self.send_unary(call, request).await
+    }
 
-        info!("Waiting for response");
-        let (response_envelope, response_line) = rx
-            .recv()
-            .await
-            .ok_or_else(|| RpcError::Custom("No response from client task".into()))?;
-        info!(
-            "Got response: {:?} {}",
-            response_envelope,
-            response_line.as_ref().unwrap_or(&String::new())
-        );
+    /// Sends a call declared `idempotent`, which interceptors like [`retry::RetryPolicy`] may
+    /// retry. Servers handle the attempts with the same `idempotency_key` only once, a key is
+    /// generated if there is none.
+    ///
+    /// # Errors
+    /// Can fail if sending the request fails or if the call returns an error
+    pub async fn send_idempotent_rpc<TRequest, TMetadata, TResponse>(
+        &self,
+        id: u64,
+        method_name: &str,
+        idempotency_key: Option<String>,
+        request: &TRequest,
+        metadata: &TMetadata,
+    ) -> Result<TResponse, RpcError>
+    where
+        TMetadata: Serialize,
+        TRequest: Serialize,
+        TResponse: DeserializeOwned,
+    {
+        let mut call = self.outgoing_call(id, method_name, metadata)?;
+        call.idempotency_key =
+            Some(idempotency_key.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()));
 
-        if let Some(error) = response_envelope.error {
-            return Err(error);
-        }
+        self.send_unary(call, request).await
+    }
+
+    async fn send_unary<TRequest, TResponse>(
+        &self,
+        call: OutgoingCall,
+        request: &TRequest,
+    ) -> Result<TResponse, RpcError>
+    where
+        TRequest: Serialize,
+        TResponse: DeserializeOwned,
+    {
+        let payload = self.framing.encode_payload(request)?;
+
+        let response = self
+            .intercept(call, |call| {
+                let sections = self.encode_call(call, Some(payload.clone()), None);
+                let (id, timeout) = (call.request_id, call.timeout);
+
+                Box::pin(async move {
+                    let sections = sections?;
+                    let connection = self.connections.pick()?;
+                    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+                    connection.state.waiting_responses.insert(id, tx);
+                    let guard = Self::guard(&connection, id, false);
+
+                    connection.send_request(sections).await?;
 
-        let response_line = response_line.ok_or_else(|| RpcError::Custom("No response".into()))?;
-        let response: TResponse = serde_json::from_str(&response_line)?;
+                    self.receive_response(guard, timeout, async { Ok(rx.recv().await) })
+                        .await
+                })
+            })
+            .await?;
 
-        Ok(response)
+        self.framing.decode_payload(&response)
     }
 
-    async fn send_raw_request<TMetadata, TRequest>(
-        &mut self,
-        envelope: &RequestEnvelope,
+    /// Sends every item of `requests`, the server answers once they're complete. An error in
+    /// `requests` cancels the call.
+    ///
+    /// # Errors
+    /// Can fail if sending the requests fails or if the call returns an error
+    pub async fn send_rpc_client_stream<TRequest, TMetadata, TResponse>(
+        &self,
+        id: u64,
+        method_name: &str,
+        requests: RequestStream<TRequest>,
         metadata: &TMetadata,
-        request: &TRequest,
-    ) -> Result<(), RpcError>
+    ) -> Result<TResponse, RpcError>
     where
         TMetadata: Serialize,
-        TRequest: Serialize,
+        TRequest: Serialize + Send,
+        TResponse: DeserializeOwned,
     {
-        let mut buffer = String::new();
-        buffer.push_str(&serde_json::to_string(envelope)?);
-        buffer.push('\n');
-        buffer.push_str(&serde_json::to_string(&metadata)?);
-        buffer.push('\n');
-        buffer.push_str(&serde_json::to_string(&request)?);
-        buffer.push('\n');
+        let call = self.outgoing_call(id, method_name, metadata)?;
+        let requests = std::sync::Mutex::new(Some(requests));
+
+        let response = self
+            .intercept(call, |call| {
+                let sections = self.encode_call(call, None, None);
+                let requests = requests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .take();
+                let (id, timeout) = (call.request_id, call.timeout);
+
+                Box::pin(async move {
+                    let sections = sections?;
+                    let requests = requests.ok_or_else(already_sent)?;
+                    let connection = self.connections.pick()?;
+                    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+                    connection.state.waiting_responses.insert(id, tx);
+                    let credits = request_credits(&connection, id);
+                    let guard = Self::guard(&connection, id, false);
+
+                    connection.send_request(sections).await?;
+
+                    let sending = send_request_items(
+                        self.framing,
+                        connection.request_tx.clone(),
+                        id,
+                        credits,
+                        requests,
+                    );
+
+                    self.receive_response(guard, timeout, async move {
+                        tokio::pin!(sending);
+                        let mut sent = false;
+
+                        // The server may answer before it consumed all requests, e.g. with an
+                        // error
+                        loop {
+                            tokio::select! {
+                                result = &mut sending, if !sent => {
+                                    result?;
+                                    sent = true;
+                                }
+                                response = rx.recv() => return Ok(response),
+                            }
+                        }
+                    })
+                    .await
+                })
+            })
+            .await?;
+
+        self.framing.decode_payload(&response)
+    }
 
-        self.request_tx.send(buffer).await?;
+    /// Returns the payload of the response
+    async fn receive_response(
+        &self,
+        mut guard: CallGuard,
+        timeout: Option<Duration>,
+        response: impl Future<Output = Result<Option<Response>, RpcError>>,
+    ) -> Result<Vec<u8>, RpcError> {
+        let request_id = guard.request_id;
+        trace!("Waiting for the response to request {}", request_id);
+        let result = async {
+            let response = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, response)
+                    .await
+                    .map_err(|_| RpcError::DeadlineExceeded)??,
+                None => response.await?,
+            };
+            let (response_envelope, response_line) = response.ok_or(RpcError::ConnectionLost)?;
+            guard.finish();
+            trace!("Got the response to request {}", request_id);
 
-        Ok(())
+            if let Some(error) = response_envelope.error {
+                return Err(error);
+            }
+
+            response_line.ok_or_else(|| RpcError::Custom("No response".into()))
+        }
+        .await;
+        guard.connection.record(&result);
+
+        result
+    }
+
+    /// Encodes the call, calls of request streams have no `payload`
+    fn encode_call(
+        &self,
+        call: &OutgoingCall,
+        payload: Option<Vec<u8>>,
+        credits: Option<u32>,
+    ) -> Result<Vec<Vec<u8>>, RpcError> {
+        let envelope = RequestEnvelope {
+            method_name: call.method_name.clone(),
+            request_id: call.request_id,
+            kind: RequestKind::Call,
+            timeout_ms: call
+                .timeout
+                .map(|t| u64::try_from(t.as_millis()).unwrap_or(u64::MAX)),
+            credits,
+            request_stream: payload.is_none(),
+            trace: Some(TraceContext::current().unwrap_or_else(TraceContext::new_root)),
+            idempotency_key: call.idempotency_key.clone(),
+        };
+
+        let mut sections = vec![
+            serde_json::to_vec(&envelope)?,
+            serde_json::to_vec(&call.metadata)?,
+        ];
+        sections.extend(payload);
+
+        Ok(sections)
     }
 
-    /// # Panics
-    /// FIXME make it never panic
+    /// Dropping the returned stream before it ended cancels the call on the server
+    ///
     /// # Errors
     /// Can fail if sending the request fails
     pub async fn send_rpc_stream_request<TRequest, TMetadata, TResponse>(
-        &mut self,
+        &self,
         id: u64,
         method_name: &str,
         request: &TRequest,
@@ -231,88 +626,317 @@ impl RawRpcClient {
         TMetadata: Serialize,
         TResponse: DeserializeOwned,
     {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
-        self.active_streams.insert(id, tx);
+        let payload = self.framing.encode_payload(request)?;
+        let call = self.outgoing_call(id, method_name, metadata)?;
 
-        self.send_raw_request(
-            &RequestEnvelope {
-                method_name: method_name.to_string(),
-                request_id: id,
+        self.intercept(call, |call| {
+            let sections = self.encode_call(call, Some(payload.clone()), Some(STREAM_CREDITS));
+            let (id, timeout) = (call.request_id, call.timeout);
+
+            Box::pin(async move {
+                let sections = sections?;
+                let guard = Self::guard(&self.connections.pick()?, id, true);
+
+                self.receive_stream(guard, sections, true, timeout).await
+            })
+        })
+        .await
+    }
+
+    /// Sends the items of `requests` while the responses arrive. An error in `requests` cancels
+    /// the call, dropping the returned stream stops sending them. These calls are never
+    /// re-subscribed, as the requests sent so far can't be repeated.
+    ///
+    /// # Errors
+    /// Can fail if sending the request fails
+    pub async fn send_rpc_bidi_stream<TRequest, TMetadata, TResponse>(
+        &self,
+        id: u64,
+        method_name: &str,
+        requests: RequestStream<TRequest>,
+        metadata: &TMetadata,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<TResponse, RpcError>> + Unpin + Send>>, RpcError>
+    where
+        TRequest: Serialize + Send + 'static,
+        TMetadata: Serialize,
+        TResponse: DeserializeOwned,
+    {
+        let call = self.outgoing_call(id, method_name, metadata)?;
+        let requests = std::sync::Mutex::new(Some(requests));
+
+        self.intercept(call, |call| {
+            let sections = self.encode_call(call, None, Some(STREAM_CREDITS));
+            let requests = requests
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            let (id, timeout) = (call.request_id, call.timeout);
+
+            Box::pin(async move {
+                let sections = sections?;
+                let requests = requests.ok_or_else(already_sent)?;
+                let connection = self.connections.pick()?;
+                let credits = request_credits(&connection, id);
+                let (sending, registration) = AbortHandle::new_pair();
+                let mut guard = Self::guard(&connection, id, true);
+                guard.sending = Some(sending);
+
+                let responses = self.receive_stream(guard, sections, false, timeout).await?;
+
+                let framing = self.framing;
+                let request_tx = connection.request_tx.clone();
+                tokio::spawn(Abortable::new(
+                    async move {
+                        let result =
+                            send_request_items(framing, request_tx.clone(), id, credits, requests)
+                                .await;
+
+                        if let Err(e) = result {
+                            warn!(
+                                "Cancelling request {}, sending its requests failed: {}",
+                                id, e
+                            );
+                            if let Ok(envelope) = serde_json::to_vec(&RequestEnvelope::cancel(id)) {
+                                let _ = request_tx.send(vec![envelope]).await;
+                            }
+                        }
+                    },
+                    registration,
+                ));
+
+                Ok(responses)
+            })
+        })
+        .await
+    }
+
+    /// Sends the call of a stream and returns its responses
+    async fn receive_stream<TResponse>(
+        &self,
+        mut guard: CallGuard,
+        sections: Vec<Vec<u8>>,
+        resubscribable: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<TResponse, RpcError>> + Unpin + Send>>, RpcError>
+    where
+        TResponse: DeserializeOwned,
+    {
+        let id = guard.request_id;
+        let connection = guard.connection.clone();
+
+        // After a re-subscribe, items of the previous connection may still be buffered
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2 * STREAM_CREDITS as usize + 1);
+        connection.state.active_streams.insert(
+            id,
+            ActiveStream {
+                sender: tx,
+                request: resubscribable.then(|| sections.clone()),
             },
-            &metadata,
-            &request,
-        )
-        .await?;
+        );
 
-        info!("Stream request sent");
+        connection.send_request(sections).await?;
 
-        let rx_stream = Box::pin(async_stream::stream! {
-            while let Some(response_line) = rx.recv().await {
-                info!("Got response line: {:?}", response_line);
+        trace!("Sent stream request {}", id);
+
+        let framing = self.framing;
+        let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+        let request_tx = connection.request_tx.clone();
+
+        let response_stream = Box::pin(async_stream::stream! {
+            let mut consumed = 0;
+
+            loop {
+                let response = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, rx.recv()).await {
+                        Ok(response) => response,
+                        Err(_) => {
+                            yield Err(RpcError::DeadlineExceeded);
+                            break;
+                        }
+                    },
+                    None => rx.recv().await,
+                };
+
+                let (response_envelope, contents) = match response {
+                    Some(response) => response,
+                    None => break,
+                };
+                trace!("Got a response to stream request {}", id);
+
+                if response_envelope.stream_end {
+                    guard.finish();
+
+                    if let Some(e) = response_envelope.error {
+                        yield Err(e);
+                    }
 
-                if response_line.0.stream_end {
                     break;
                 }
 
-                yield response_line;
+                yield match (response_envelope.error, contents) {
+                    (Some(e), _) => Err(e),
+                    (None, Some(contents)) => Ok(contents),
+                    (None, None) => Err(RpcError::Custom(format!(
+                        "No response for request {}",
+                        response_envelope.request_id
+                    ))),
+                };
+
+                // Grant credits in batches, once the consumer took the item
+                consumed += 1;
+                if consumed >= STREAM_CREDITS / 2 {
+                    match serde_json::to_vec(&RequestEnvelope::credit(id, consumed)) {
+                        Ok(envelope) => {
+                            if request_tx.send(vec![envelope]).await.is_err() {
+                                debug!("Failed to grant credits to request {}", id);
+                            }
+                        }
+                        Err(e) => error!("Failed to serialize credits: {}", e),
+                    }
+                    consumed = 0;
+                }
             }
 
-            info!("Stream ended");
+            debug!("Stream request {} ended", id);
         });
 
-        Ok(Box::pin(rx_stream.map(
-            move |(response_envelope, contents): (ResponseEnvelope, Option<String>)| {
-                match response_envelope.error {
-                    None => {
-                        let contents = contents.ok_or_else(|| {
-                            RpcError::Custom(format!(
-                                "No response for envelope {:?}",
-                                response_envelope
-                            ))
-                        })?;
-
-                        Ok(serde_json::from_str(&contents)?)
-                    }
-                    Some(e) => Err(e),
-                }
-            },
-        )))
+        Ok(Box::pin(
+            response_stream.map(move |contents| framing.decode_payload(&contents?)),
+        ))
     }
 }
 
+fn already_sent() -> RpcError {
+    RpcError::internal("The requests of a stream can only be sent once")
+}
+
+/// Credits the server granted to the request stream of a call on `connection`
+fn request_credits(connection: &PooledConnection, id: u64) -> Arc<Semaphore> {
+    let credits = Arc::new(Semaphore::new(STREAM_CREDITS as usize));
+    connection.state.request_credits.insert(id, credits.clone());
+
+    credits
+}
+
+/// Sends the items of a request stream as the server grants credits for them, followed by the
+/// end-of-input marker
+async fn send_request_items<TRequest>(
+    framing: Framing,
+    request_tx: RequestSender,
+    request_id: u64,
+    credits: Arc<Semaphore>,
+    mut requests: RequestStream<TRequest>,
+) -> Result<(), RpcError>
+where
+    TRequest: Serialize,
+{
+    let item_envelope =
+        serde_json::to_vec(&RequestEnvelope::for_call(request_id, RequestKind::Item))?;
+
+    while let Some(request) = requests.next().await {
+        let payload = framing.encode_payload(&request?)?;
+
+        // Closed once the connection is lost
+        credits
+            .acquire()
+            .await
+            .map_err(|_| RpcError::ConnectionLost)?
+            .forget();
+
+        request_tx
+            .send(vec![item_envelope.clone(), payload])
+            .await?;
+    }
+
+    request_tx
+        .send(vec![serde_json::to_vec(&RequestEnvelope::for_call(
+            request_id,
+            RequestKind::EndOfInput,
+        ))?])
+        .await?;
+
+    Ok(())
+}
+
 /**
  * # Errors
  * Can fail if the request cannot be read from the stream
  */
 pub async fn read_request<TMetadata>(
-    reader: &mut (impl AsyncBufRead + Unpin),
-) -> Result<(String, String, u64, TMetadata), RpcError>
+    reader: &mut FrameReader<impl AsyncBufRead + Unpin>,
+) -> Result<Request<TMetadata>, RpcError>
 where
     TMetadata: DeserializeOwned,
 {
-    let mut envelope_line = String::new();
-    let mut metadata_line = String::new();
-    let mut payload_line = String::new();
+    let envelope_section = reader.read_section().await?;
+    trace!("Envelope: {}", String::from_utf8_lossy(&envelope_section));
+
+    let envelope: RequestEnvelope = serde_json::from_slice(&envelope_section)?;
+    match envelope.kind {
+        RequestKind::Call => {}
+        RequestKind::Item => {
+            return Ok(Request::Item {
+                request_id: envelope.request_id,
+                payload: reader.read_section().await?,
+            })
+        }
+        RequestKind::EndOfInput => {
+            return Ok(Request::EndOfInput {
+                request_id: envelope.request_id,
+            })
+        }
+        RequestKind::Cancel => {
+            return Ok(Request::Cancel {
+                request_id: envelope.request_id,
+            })
+        }
+        RequestKind::Credit => {
+            return Ok(Request::Credit {
+                request_id: envelope.request_id,
+                credits: envelope.credits.unwrap_or_default(),
+            })
+        }
+    }
 
-    let mut reader = Box::pin(reader);
+    let metadata_section = reader.read_section().await?;
+    let payload_section = if envelope.request_stream {
+        Vec::new()
+    } else {
+        reader.read_section().await?
+    };
 
-    reader.read_line(&mut envelope_line).await?;
-    reader.read_line(&mut metadata_line).await?;
-    reader.read_line(&mut payload_line).await?;
+    trace!("Metadata: {}", String::from_utf8_lossy(&metadata_section));
+    trace!("Payload: {} bytes", payload_section.len());
 
-    info!("Envelope: {}", envelope_line);
-    info!("Metadata: {}", metadata_line);
-    info!("Payload: {}", payload_line);
+    if envelope.method_name == reflection::METHOD_NAME {
+        return Ok(Request::Reflect {
+            request_id: envelope.request_id,
+        });
+    }
 
-    let envelope: RequestEnvelope = serde_json::from_str(&envelope_line)?;
-    let metadata: TMetadata = serde_json::from_str(&metadata_line)?;
+    // Fails only this call, the next requests of the connection are still readable
+    let metadata: TMetadata = match serde_json::from_slice(&metadata_section) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            return Ok(Request::Rejected {
+                request_id: envelope.request_id,
+                error: RpcError::invalid_argument(format!("Invalid metadata: {}", e)),
+            })
+        }
+    };
 
-    Ok((
-        payload_line,
-        envelope.method_name,
-        envelope.request_id,
+    Ok(Request::Call(Box::new(Call {
+        payload: payload_section,
+        method_name: envelope.method_name,
+        request_id: envelope.request_id,
         metadata,
-    ))
+        timeout: envelope.timeout_ms.map(Duration::from_millis),
+        credits: envelope.credits,
+        request_stream: envelope.request_stream,
+        input: None,
+        trace: envelope.trace,
+        idempotency_key: envelope.idempotency_key,
+    })))
 }
 
 /**
@@ -320,7 +944,7 @@ where
  * Can fail if the response cannot be written to the stream
  */
 pub async fn send_response<TResponse>(
-    writer: &mut (impl AsyncWrite + Unpin),
+    writer: &ResponseWriter,
     response: Result<TResponse, RpcError>,
     request_id: u64,
     stream_end: bool, // todo: remove this argument from public API
@@ -328,45 +952,97 @@ pub async fn send_response<TResponse>(
 where
     TResponse: Serialize,
 {
-    let mut buffer = vec![];
+    // The request was fine if the response can't be serialized, the server failed
+    let payload = response.and_then(|response| {
+        writer.framing().encode_payload(&response).map_err(|e| {
+            error!(
+                "Failed to serialize the response to request {}: {}",
+                request_id, e
+            );
+            RpcError::internal(format!("The response could not be serialized: {}", e))
+        })
+    });
 
-    buffer.extend_from_slice(
-        serde_json::to_string(&ResponseEnvelope {
-            request_id,
-            error: response.as_ref().err().map(|e| (*e).clone()),
-            stream_end,
-        })?
-        .as_bytes(),
-    );
-    buffer.push(b'\n');
+    let envelope = serde_json::to_vec(&ResponseEnvelope {
+        request_id,
+        error: payload.as_ref().err().cloned(),
+        stream_end,
+        credits: None,
+    })?;
 
-    if let Ok(response) = response {
-        buffer.extend_from_slice(serde_json::to_string(&response)?.as_bytes());
-        buffer.push(b'\n');
+    match payload {
+        Ok(payload) => writer.write_frame(vec![envelope, payload]).await,
+        Err(_) => writer.write_frame(vec![envelope]).await,
     }
+}
 
-    writer.write_all(&buffer).await?;
-    Ok(())
+/// Sends the response of a unary call
+///
+/// # Errors
+/// Returns the error of `response` once it was sent, or fails if it can't be written
+pub async fn send_unary_response<TResponse>(
+    writer: &ResponseWriter,
+    response: Result<TResponse, RpcError>,
+    request_id: u64,
+) -> Result<(), RpcError>
+where
+    TResponse: Serialize,
+{
+    let status = response.as_ref().err().cloned();
+    send_response(writer, response, request_id, false).await?;
+
+    status.map_or(Ok(()), Err)
 }
 
+/// Sends the items of `response` as the client grants `credits` for them, the stream isn't polled
+/// while they're used up. Without credits, items are sent as fast as the stream produces them.
+///
 /// # Errors
-/// Can fail if the response cannot be written to the stream
+/// Returns the error the stream couldn't be opened with once it was sent, or fails if the
+/// response cannot be written to the stream
 pub async fn send_stream_response<TResponse>(
-    writer: &mut (impl AsyncWrite + Unpin),
+    writer: &ResponseWriter,
     response: Result<ResponseStream<TResponse>, RpcError>,
     request_id: u64,
+    credits: Option<u32>,
 ) -> Result<(), RpcError>
 where
     TResponse: Serialize,
 {
     if let Ok(mut response) = response {
-        while let Some(item) = response.next().await {
-            send_response(writer, item, request_id, false).await?;
+        let credits = credits.map(|credits| writer.flow_control(request_id, credits));
+        let _active = writer.metrics.stream_opened();
+
+        loop {
+            let next = async {
+                if let Some(credits) = &credits {
+                    credits.acquire().await;
+                }
+
+                response.next().await
+            };
+
+            let item = tokio::select! {
+                item = next => item,
+                () = writer.shutdown.triggered() => {
+                    let err = shutting_down();
+                    send_response(writer, Result::<(), _>::Err(err.clone()), request_id, true).await?;
+
+                    return Err(err);
+                }
+            };
+
+            match item {
+                Some(item) => send_response(writer, item, request_id, false).await?,
+                None => break,
+            }
         }
 
         send_response(writer, Ok(()), request_id, true).await?;
     } else if let Err(err) = response {
-        send_response(writer, Result::<(), _>::Err(err), request_id, true).await?;
+        send_response(writer, Result::<(), _>::Err(err.clone()), request_id, true).await?;
+
+        return Err(err);
     }
 
     Ok(())
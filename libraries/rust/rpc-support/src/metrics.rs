@@ -0,0 +1,333 @@
+use crate::rpc_error::{ErrorCode, RpcError};
+use crate::transport::Transport;
+use dashmap::DashMap;
+use platform::async_infra::run_with_error_handling;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds of the buckets of the call durations, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Time a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, the last one counts those above all bounds
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Metrics of all connections of a server, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct ServerMetrics {
+    /// By method and status, `None` for calls that succeeded
+    calls: DashMap<(String, Option<ErrorCode>), u64>,
+    durations: DashMap<String, Histogram>,
+    active_streams: AtomicI64,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl ServerMetrics {
+    pub(crate) fn record_call(
+        &self,
+        method_name: &str,
+        result: &Result<(), RpcError>,
+        duration: Duration,
+    ) {
+        let status = result.as_ref().err().map(RpcError::code);
+        *self
+            .calls
+            .entry((method_name.to_string(), status))
+            .or_default() += 1;
+
+        self.durations
+            .entry(method_name.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Counts the stream as active until the returned guard is dropped
+    pub(crate) fn stream_opened(self: &Arc<Self>) -> ActiveStream {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+
+        ActiveStream(self.clone())
+    }
+
+    /// Counts the bytes sent and received through `transport`
+    #[must_use]
+    pub fn metered(self: &Arc<Self>, transport: Box<dyn Transport>) -> Box<dyn Transport> {
+        Box::new(MeteredTransport {
+            inner: transport,
+            metrics: self.clone(),
+        })
+    }
+
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut result = String::new();
+
+        let mut calls = self
+            .calls
+            .iter()
+            .map(|entry| {
+                let (method_name, status) = entry.key();
+                let status = status.map_or_else(|| "Ok".to_string(), |code| format!("{:?}", code));
+
+                (escape(method_name), status, *entry.value())
+            })
+            .collect::<Vec<_>>();
+        calls.sort();
+
+        result += "# HELP rpc_server_calls_total Calls handled, by method and status\n";
+        result += "# TYPE rpc_server_calls_total counter\n";
+        for (method_name, status, count) in calls {
+            let _ = writeln!(
+                result,
+                "rpc_server_calls_total{{method=\"{}\",status=\"{}\"}} {}",
+                method_name, status, count
+            );
+        }
+
+        let mut methods = self
+            .durations
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        methods.sort();
+
+        result += "# HELP rpc_server_call_duration_seconds Time from reading a call until its response or stream was complete\n";
+        result += "# TYPE rpc_server_call_duration_seconds histogram\n";
+        for method_name in methods {
+            if let Some(histogram) = self.durations.get(&method_name) {
+                render_histogram(&mut result, &escape(&method_name), &histogram);
+            }
+        }
+
+        let _ = write!(
+            result,
+            "# HELP rpc_server_active_streams Response streams that are being sent\n\
+             # TYPE rpc_server_active_streams gauge\n\
+             rpc_server_active_streams {}\n\
+             # HELP rpc_server_received_bytes_total Bytes received from clients\n\
+             # TYPE rpc_server_received_bytes_total counter\n\
+             rpc_server_received_bytes_total {}\n\
+             # HELP rpc_server_sent_bytes_total Bytes sent to clients\n\
+             # TYPE rpc_server_sent_bytes_total counter\n\
+             rpc_server_sent_bytes_total {}\n",
+            self.active_streams.load(Ordering::Relaxed),
+            self.received_bytes.load(Ordering::Relaxed),
+            self.sent_bytes.load(Ordering::Relaxed),
+        );
+
+        result
+    }
+}
+
+fn render_histogram(result: &mut String, method_name: &str, histogram: &Histogram) {
+    let mut cumulative = 0;
+
+    for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+        cumulative += count;
+        let _ = writeln!(
+            result,
+            "rpc_server_call_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+            method_name, bound, cumulative
+        );
+    }
+
+    let _ = write!(
+        result,
+        "rpc_server_call_duration_seconds_bucket{{method=\"{0}\",le=\"+Inf\"}} {1}\n\
+         rpc_server_call_duration_seconds_sum{{method=\"{0}\"}} {2}\n\
+         rpc_server_call_duration_seconds_count{{method=\"{0}\"}} {1}\n",
+        method_name, histogram.count, histogram.sum
+    );
+}
+
+/// Escapes a label value, method names are chosen by the client
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub(crate) struct ActiveStream(Arc<ServerMetrics>);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct MeteredTransport {
+    inner: Box<dyn Transport>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl AsyncRead for MeteredTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        self.metrics
+            .received_bytes
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+
+        result
+    }
+}
+
+impl AsyncWrite for MeteredTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = result {
+            self.metrics
+                .sent_bytes
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Answers every HTTP request to `/metrics` on `listener` with the current `metrics`
+///
+/// # Errors
+/// Fails if accepting a connection fails
+pub async fn serve(listener: TcpListener, metrics: Arc<ServerMetrics>) -> Result<(), io::Error> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+
+        tokio::spawn(run_with_error_handling(respond(socket, metrics.clone())));
+    }
+}
+
+async fn respond(socket: TcpStream, metrics: Arc<ServerMetrics>) -> Result<(), io::Error> {
+    let (read, mut write) = socket.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let request_line = lines.next_line().await?.unwrap_or_default();
+
+        // The headers don't matter, but have to be read before answering
+        while let Some(header) = lines.next_line().await? {
+            if header.is_empty() {
+                break;
+            }
+        }
+
+        Ok::<_, io::Error>(request_line)
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No request received"))??;
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    write.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn calls_are_counted_by_method_and_status() {
+        let metrics = ServerMetrics::default();
+
+        metrics.record_call("a", &Ok(()), Duration::from_millis(20));
+        metrics.record_call("a", &Ok(()), Duration::from_secs(20));
+        metrics.record_call(
+            "a",
+            &Err(RpcError::not_found("missing")),
+            Duration::from_millis(1),
+        );
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("rpc_server_calls_total{method=\"a\",status=\"Ok\"} 2\n"));
+        assert!(rendered.contains("rpc_server_calls_total{method=\"a\",status=\"NotFound\"} 1\n"));
+        assert!(rendered
+            .contains("rpc_server_call_duration_seconds_bucket{method=\"a\",le=\"0.025\"} 2\n"));
+        assert!(rendered
+            .contains("rpc_server_call_duration_seconds_bucket{method=\"a\",le=\"10\"} 2\n"));
+        assert!(rendered.contains("rpc_server_call_duration_seconds_count{method=\"a\"} 3\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_http() {
+        let metrics = Arc::new(ServerMetrics::default());
+        let _stream = metrics.stream_opened();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, metrics));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nrpc_server_active_streams 1\n"));
+    }
+}
@@ -0,0 +1,572 @@
+use crate::compression::Compression;
+use crate::connection::{self, ConnectionState, RequestSender};
+use crate::framing::Framing;
+use crate::handshake::Handshake;
+use crate::rpc_error::{ErrorCode, RpcError};
+use crate::transport::{Connector, TcpConnector};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How calls are spread over the connections of a pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balancing {
+    /// Every call goes to the next connection
+    #[default]
+    RoundRobin,
+    /// Every call goes to the connection with the fewest calls and streams in flight
+    LeastLoaded,
+}
+
+/// Settings of a client that spreads its calls over every endpoint of a service, e.g. the
+/// replicas behind a headless Kubernetes service. Keeps `connections` connections to each
+/// endpoint and resolves the address again every `resolve_interval` to pick up replicas that
+/// were added or removed.
+///
+/// An endpoint is ejected for `ejection_time` once `max_failures` calls in a row failed because
+/// it was unavailable. Calls that ran out of time don't count, a replica that is slower than the
+/// deadline of the client is still available. Calls only go to ejected endpoints while there are
+/// no others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pool {
+    pub connections: usize,
+    pub balancing: Balancing,
+    pub max_failures: u32,
+    pub ejection_time: Duration,
+    pub resolve_interval: Duration,
+}
+
+impl Pool {
+    #[must_use]
+    pub const fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_balancing(mut self, balancing: Balancing) -> Self {
+        self.balancing = balancing;
+        self
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            balancing: Balancing::default(),
+            max_failures: DEFAULT_MAX_FAILURES,
+            ejection_time: DEFAULT_EJECTION_TIME,
+            resolve_interval: DEFAULT_RESOLVE_INTERVAL,
+        }
+    }
+}
+
+/// An endpoint of a service, told apart from the other endpoints by its name
+pub struct Endpoint {
+    pub name: String,
+    pub connector: Arc<dyn Connector>,
+}
+
+/// Finds the endpoints of a service, called again every [`Pool::resolve_interval`]
+#[async_trait::async_trait]
+pub trait Resolver: Display + Send + Sync {
+    async fn resolve(&self) -> std::io::Result<Vec<Endpoint>>;
+}
+
+/// Resolves a `host:port` address to every address of the host and connects to them over TCP
+pub struct DnsResolver {
+    addr: String,
+}
+
+impl DnsResolver {
+    #[must_use]
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+impl Display for DnsResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for DnsResolver {
+    async fn resolve(&self) -> std::io::Result<Vec<Endpoint>> {
+        Ok(tokio::net::lookup_host(&self.addr)
+            .await?
+            .map(|address| {
+                let name = address.to_string();
+
+                Endpoint {
+                    connector: Arc::new(TcpConnector::new(&name)),
+                    name,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Counts the calls to an endpoint that failed in a row, see [`Pool::max_failures`]
+struct Health {
+    endpoint: String,
+    failures: AtomicU32,
+    ejected_until: std::sync::Mutex<Option<Instant>>,
+    max_failures: u32,
+    ejection_time: Duration,
+}
+
+impl Health {
+    fn is_ejected(&self) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record<T>(&self, result: &Result<T, RpcError>) {
+        match result {
+            Err(
+                RpcError::ConnectionLost
+                | RpcError::Status {
+                    code: ErrorCode::Unavailable,
+                    ..
+                },
+            ) => {}
+            Err(RpcError::DeadlineExceeded) => return,
+            _ => {
+                self.failures.store(0, Ordering::Release);
+                return;
+            }
+        }
+
+        if self.failures.fetch_add(1, Ordering::AcqRel) + 1 >= self.max_failures {
+            self.failures.store(0, Ordering::Release);
+            *self
+                .ejected_until
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) =
+                Some(Instant::now() + self.ejection_time);
+
+            warn!(
+                "Ejecting {} for {:?}, {} calls in a row failed",
+                self.endpoint, self.ejection_time, self.max_failures
+            );
+        }
+    }
+}
+
+/// A connection of a pool, calls hold on to it until they're finished
+pub(crate) struct PooledConnection {
+    pub state: Arc<ConnectionState>,
+    pub request_tx: RequestSender,
+    /// Calls and streams in flight
+    pub load: AtomicUsize,
+    health: Arc<Health>,
+}
+
+impl PooledConnection {
+    pub fn is_connected(&self) -> bool {
+        self.state.connected.load(Ordering::Acquire)
+    }
+
+    /// Counts the result of a call towards the health of the endpoint
+    pub fn record<T>(&self, result: &Result<T, RpcError>) {
+        self.health.record(result);
+    }
+
+    pub async fn send_request(&self, sections: Vec<Vec<u8>>) -> Result<(), RpcError> {
+        // Fail fast instead of waiting for a reconnect that may take a while
+        if !self.is_connected() {
+            return Err(RpcError::ConnectionLost);
+        }
+
+        self.request_tx.send(sections).await?;
+
+        Ok(())
+    }
+}
+
+struct PooledEndpoint {
+    name: String,
+    health: Arc<Health>,
+    connections: Vec<Arc<PooledConnection>>,
+}
+
+/// The connections of a client, to one or more endpoints
+pub(crate) struct Connections {
+    endpoints: RwLock<Vec<PooledEndpoint>>,
+    next: AtomicUsize,
+    pool: Pool,
+    framing: Framing,
+    handshake: Handshake,
+    compression: Compression,
+    resubscribe: AtomicBool,
+}
+
+impl Connections {
+    fn new(pool: Pool, framing: Framing, handshake: Handshake, compression: Compression) -> Self {
+        Self {
+            endpoints: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            pool,
+            framing,
+            handshake,
+            compression,
+            resubscribe: AtomicBool::new(false),
+        }
+    }
+
+    /// One connection through `connector`, fails if it can't be established
+    pub async fn single(
+        connector: impl Connector + 'static,
+        framing: Framing,
+        handshake: Handshake,
+        compression: Compression,
+    ) -> Result<Arc<Self>, RpcError> {
+        let connections = Self::new(Pool::default(), framing, handshake, compression);
+        let endpoint = connections
+            .open_endpoint(Endpoint {
+                name: connector.to_string(),
+                connector: Arc::new(connector),
+            })
+            .await?;
+        connections.write().push(endpoint);
+
+        Ok(Arc::new(connections))
+    }
+
+    /// Connections to every endpoint `resolver` finds, fails if none of them can be reached.
+    /// Keeps resolving in the background until the connections are dropped.
+    pub async fn resolve(
+        resolver: impl Resolver + 'static,
+        pool: Pool,
+        framing: Framing,
+        handshake: Handshake,
+        compression: Compression,
+    ) -> Result<Arc<Self>, RpcError> {
+        let connections = Self::new(pool, framing, handshake, compression);
+
+        let mut last_error = RpcError::unavailable(format!("{} has no endpoints", resolver));
+        for endpoint in resolver.resolve().await.map_err(RpcError::transport)? {
+            match connections.open_endpoint(endpoint).await {
+                Ok(endpoint) => connections.write().push(endpoint),
+                Err(e) => last_error = e,
+            }
+        }
+
+        if connections.read().is_empty() {
+            return Err(last_error);
+        }
+
+        let connections = Arc::new(connections);
+        tokio::spawn(keep_resolving(
+            Arc::downgrade(&connections),
+            Box::new(resolver),
+            connections.pool.resolve_interval,
+        ));
+
+        Ok(connections)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Vec<PooledEndpoint>> {
+        self.endpoints
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Vec<PooledEndpoint>> {
+        self.endpoints
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Opens the connections to `endpoint`, fails only if none of them can be established
+    async fn open_endpoint(&self, endpoint: Endpoint) -> Result<PooledEndpoint, RpcError> {
+        let health = Arc::new(Health {
+            endpoint: endpoint.name.clone(),
+            failures: AtomicU32::new(0),
+            ejected_until: std::sync::Mutex::new(None),
+            max_failures: self.pool.max_failures,
+            ejection_time: self.pool.ejection_time,
+        });
+
+        let mut connections = vec![];
+        let mut last_error = None;
+
+        for _ in 0..self.pool.connections.max(1) {
+            match connection::open(
+                endpoint.connector.clone(),
+                self.framing,
+                self.handshake.clone(),
+                self.compression.clone(),
+            )
+            .await
+            {
+                Ok((state, request_tx)) => {
+                    state
+                        .resubscribe
+                        .store(self.resubscribe.load(Ordering::Acquire), Ordering::Release);
+
+                    connections.push(Arc::new(PooledConnection {
+                        state,
+                        request_tx,
+                        load: AtomicUsize::new(0),
+                        health: health.clone(),
+                    }));
+                }
+                Err(e) => {
+                    warn!("Connecting to {} failed: {}", endpoint.name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if connections.is_empty() => Err(e),
+            _ => Ok(PooledEndpoint {
+                name: endpoint.name,
+                health,
+                connections,
+            }),
+        }
+    }
+
+    /// Connects to endpoints that were added and drops the ones that were removed. Calls in
+    /// flight on removed endpoints still finish.
+    async fn refresh(&self, resolver: &dyn Resolver) {
+        let resolved = match resolver.resolve().await {
+            // Rather keep the known endpoints than drop all of them because of a DNS hiccup
+            Ok(resolved) if resolved.is_empty() => {
+                warn!(
+                    "{} resolved to no endpoints, keeping the known ones",
+                    resolver
+                );
+                return;
+            }
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!("Resolving {} failed: {}", resolver, e);
+                return;
+            }
+        };
+
+        let names = resolved
+            .iter()
+            .map(|endpoint| endpoint.name.clone())
+            .collect::<HashSet<_>>();
+        let mut known = self
+            .read()
+            .iter()
+            .map(|endpoint| endpoint.name.clone())
+            .collect::<HashSet<_>>();
+
+        let mut added = vec![];
+        for endpoint in resolved {
+            if known.insert(endpoint.name.clone()) {
+                if let Ok(endpoint) = self.open_endpoint(endpoint).await {
+                    info!("Connected to new endpoint {}", endpoint.name);
+                    added.push(endpoint);
+                }
+            }
+        }
+
+        let mut endpoints = self.write();
+        endpoints.retain(|endpoint| {
+            let resolved = names.contains(&endpoint.name);
+            if !resolved {
+                info!("Dropping removed endpoint {}", endpoint.name);
+            }
+
+            resolved
+        });
+        endpoints.extend(added);
+    }
+
+    /// The connection the next call is sent on
+    ///
+    /// # Errors
+    /// Fails with [`RpcError::ConnectionLost`] if no connection is established
+    pub fn pick(&self) -> Result<Arc<PooledConnection>, RpcError> {
+        let endpoints = self.read();
+        let connected = |ejected: bool| {
+            endpoints
+                .iter()
+                .filter(|endpoint| ejected || !endpoint.health.is_ejected())
+                .flat_map(|endpoint| endpoint.connections.iter())
+                .filter(|connection| connection.is_connected())
+                .collect::<Vec<_>>()
+        };
+
+        let mut candidates = connected(false);
+        if candidates.is_empty() {
+            candidates = connected(true);
+        }
+        if candidates.is_empty() {
+            return Err(RpcError::ConnectionLost);
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let rotated = (0..candidates.len()).map(|i| candidates[(start + i) % candidates.len()]);
+
+        let picked = match self.pool.balancing {
+            Balancing::RoundRobin => rotated.take(1).next(),
+            Balancing::LeastLoaded => {
+                rotated.min_by_key(|connection| connection.load.load(Ordering::Acquire))
+            }
+        };
+
+        picked.cloned().ok_or(RpcError::ConnectionLost)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.read()
+            .iter()
+            .flat_map(|endpoint| endpoint.connections.iter())
+            .any(|connection| connection.is_connected())
+    }
+
+    pub fn set_resubscribe(&self, resubscribe: bool) {
+        self.resubscribe.store(resubscribe, Ordering::Release);
+
+        for connection in self
+            .read()
+            .iter()
+            .flat_map(|endpoint| endpoint.connections.iter())
+        {
+            connection
+                .state
+                .resubscribe
+                .store(resubscribe, Ordering::Release);
+        }
+    }
+}
+
+async fn keep_resolving(
+    connections: Weak<Connections>,
+    resolver: Box<dyn Resolver>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match connections.upgrade() {
+            Some(connections) => connections.refresh(resolver.as_ref()).await,
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn connections(balancing: Balancing, endpoints: &[&str]) -> Connections {
+        let pool = Pool {
+            max_failures: 2,
+            ..Pool::default().with_balancing(balancing)
+        };
+        let connections = Connections::new(
+            pool.clone(),
+            Framing::Binary,
+            Handshake::new("test", ""),
+            Compression::disabled(),
+        );
+
+        for name in endpoints {
+            let health = Arc::new(Health {
+                endpoint: (*name).to_string(),
+                failures: AtomicU32::new(0),
+                ejected_until: std::sync::Mutex::new(None),
+                max_failures: pool.max_failures,
+                ejection_time: pool.ejection_time,
+            });
+            let state = Arc::new(ConnectionState::default());
+            state.connected.store(true, Ordering::Release);
+
+            connections.write().push(PooledEndpoint {
+                name: (*name).to_string(),
+                health: health.clone(),
+                connections: vec![Arc::new(PooledConnection {
+                    state,
+                    request_tx: tokio::sync::mpsc::channel(1).0,
+                    load: AtomicUsize::new(0),
+                    health,
+                })],
+            });
+        }
+
+        connections
+    }
+
+    fn endpoint(connection: &PooledConnection) -> String {
+        connection.health.endpoint.clone()
+    }
+
+    #[test]
+    fn round_robin_picks_every_connection_in_turn() {
+        let connections = connections(Balancing::RoundRobin, &["a", "b", "c"]);
+
+        let picked = (0..6)
+            .map(|_| endpoint(&connections.pick().unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["a", "b", "c", "a", "b", "c"], picked);
+    }
+
+    #[test]
+    fn least_loaded_picks_the_connection_with_the_fewest_calls() {
+        let connections = connections(Balancing::LeastLoaded, &["a", "b"]);
+
+        let busy = connections.pick().unwrap();
+        busy.load.store(3, Ordering::Release);
+
+        for _ in 0..3 {
+            assert_ne!(endpoint(&busy), endpoint(&connections.pick().unwrap()));
+        }
+    }
+
+    #[test]
+    fn failing_endpoints_are_ejected_until_no_other_is_left() {
+        let connections = connections(Balancing::RoundRobin, &["a", "b"]);
+
+        let failing = connections.pick().unwrap();
+        failing.record::<()>(&Err(RpcError::ConnectionLost));
+        failing.record::<()>(&Err(RpcError::ConnectionLost));
+
+        for _ in 0..3 {
+            assert_eq!("b", endpoint(&connections.pick().unwrap()));
+        }
+
+        connections.read()[1].connections[0]
+            .state
+            .connected
+            .store(false, Ordering::Release);
+        assert_eq!("a", endpoint(&connections.pick().unwrap()));
+    }
+
+    #[test]
+    fn application_errors_and_deadlines_do_not_eject_endpoints() {
+        let connections = connections(Balancing::RoundRobin, &["a", "b"]);
+
+        let (failing, slow) = (connections.pick().unwrap(), connections.pick().unwrap());
+        for _ in 0..3 {
+            failing.record::<()>(&Err(RpcError::not_found("No such event")));
+            slow.record::<()>(&Err(RpcError::DeadlineExceeded));
+        }
+
+        assert!(!failing.health.is_ejected());
+        assert!(!slow.health.is_ejected());
+    }
+}
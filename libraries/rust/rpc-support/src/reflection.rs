@@ -0,0 +1,75 @@
+use crate::rpc_error::RpcError;
+use crate::send_unary_response;
+use crate::server::ResponseWriter;
+use platform::async_infra::run_with_error_handling;
+use serde::{Deserialize, Serialize};
+
+/// Answered by every generated server with its [`Schema`]. Not a valid identifier in `.evd`
+/// files, so it never collides with a call of the service. Reflection calls don't need metadata
+/// and don't pass through the interceptors.
+pub const METHOD_NAME: &str = "rpc.reflect";
+
+/// The typed `.evd` file of a service. Types are written as in the `.evd` file, e.g. `u8`,
+/// `Event` or `instant?`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    pub service_name: String,
+    pub schema_hash: String,
+    pub metadata: Vec<Field>,
+    pub structs: Vec<Struct>,
+    pub enums: Vec<Enum>,
+    pub calls: Vec<Call>,
+}
+
+impl Schema {
+    #[must_use]
+    pub fn call(&self, name: &str) -> Option<&Call> {
+        self.calls.iter().find(|call| call.name == name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Enum {
+    pub name: String,
+    pub variants: Vec<Struct>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Call {
+    pub name: String,
+    pub request: String,
+    /// The client sends a stream of requests instead of a single one
+    pub request_stream: bool,
+    pub response: String,
+    pub response_stream: bool,
+    /// The application error declared with `throws`
+    pub error: Option<String>,
+    /// Declared with `idempotent`, the call may be retried
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub idempotent: bool,
+}
+
+/// Answers the reflection call `request_id` with `schema`, the JSON the message compiler
+/// generated for the service
+pub fn reply(writer: &ResponseWriter, schema: &'static str, request_id: u64) {
+    let writer = writer.clone();
+
+    tokio::spawn(run_with_error_handling(async move {
+        let schema = serde_json::from_str::<Schema>(schema).map_err(RpcError::from);
+
+        send_unary_response(&writer, schema, request_id).await
+    }));
+}
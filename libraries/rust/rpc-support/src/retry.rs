@@ -0,0 +1,149 @@
+use crate::interceptor::{ClientInterceptor, ClientNext, OutgoingCall};
+use crate::rpc_error::{ErrorCode, RpcError};
+use std::time::Duration;
+use tracing::warn;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Retries calls that failed with one of the `retryable` codes, up to `max_attempts` attempts in
+/// total. Waits `backoff` before the first retry and twice as long before every further one, at
+/// most `max_backoff`.
+///
+/// Only calls with an idempotency key are retried, the ones declared `idempotent`. Others may
+/// have been handled by the server already, e.g. when the connection was lost before the
+/// response arrived.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub retryable: Vec<ErrorCode>,
+}
+
+impl RetryPolicy {
+    #[must_use]
+    pub const fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    #[must_use]
+    pub fn with_retryable(mut self, retryable: Vec<ErrorCode>) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    fn should_retry(&self, call: &OutgoingCall, attempt: u32, error: &RpcError) -> bool {
+        call.idempotency_key.is_some()
+            && attempt < self.max_attempts
+            && self.retryable.contains(&error.code())
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Retries calls that failed because the server or the connection was unavailable, or that
+    /// ran out of time
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            retryable: vec![ErrorCode::Unavailable, ErrorCode::DeadlineExceeded],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ClientInterceptor for RetryPolicy {
+    async fn intercept(
+        &self,
+        call: &mut OutgoingCall,
+        next: ClientNext<'_>,
+    ) -> Result<(), RpcError> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+
+        loop {
+            match next.run(call).await {
+                Err(e) if self.should_retry(call, attempt, &e) => {
+                    warn!(
+                        "{} #{} failed in attempt {}, retrying in {:?}: {}",
+                        call.method_name, call.request_id, attempt, backoff, e
+                    );
+
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interceptor::MetadataFields;
+    use futures::future::BoxFuture;
+    use std::sync::{Arc, Mutex};
+
+    async fn attempts(
+        policy: RetryPolicy,
+        idempotency_key: Option<&str>,
+        error: impl Fn() -> RpcError + Send + Sync,
+    ) -> (Result<(), RpcError>, usize) {
+        let interceptors: Vec<Arc<dyn ClientInterceptor>> = vec![Arc::new(policy)];
+        let call = OutgoingCall {
+            method_name: "call".to_string(),
+            request_id: 1,
+            metadata: MetadataFields::new(),
+            timeout: None,
+            idempotency_key: idempotency_key.map(str::to_string),
+        };
+
+        let attempts = Mutex::new(0);
+        let send = |_: &OutgoingCall| -> BoxFuture<'_, Result<(), RpcError>> {
+            *attempts.lock().unwrap() += 1;
+            let error = error();
+
+            Box::pin(async move { Err(error) })
+        };
+        let result = ClientNext::new(&interceptors, &send).run(&call).await;
+
+        (result, attempts.into_inner().unwrap())
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::default()
+            .with_max_attempts(3)
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn idempotent_calls_are_retried_up_to_max_attempts() {
+        let (result, attempts) = attempts(policy(), Some("key"), || RpcError::ConnectionLost).await;
+
+        assert!(matches!(result, Err(RpcError::ConnectionLost)));
+        assert_eq!(3, attempts);
+    }
+
+    #[tokio::test]
+    async fn other_calls_and_errors_are_not_retried() {
+        let (_, attempts_without_key) = attempts(policy(), None, || RpcError::ConnectionLost).await;
+        let (_, attempts_of_other_errors) =
+            attempts(policy(), Some("key"), || RpcError::not_found("missing")).await;
+
+        assert_eq!(1, attempts_without_key);
+        assert_eq!(1, attempts_of_other_errors);
+    }
+}
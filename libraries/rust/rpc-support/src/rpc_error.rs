@@ -1,17 +1,170 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
 
+/// Kind of an error, stable across versions so callers can branch on it. The numeric values
+/// follow the gRPC status codes.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+    /// An error declared with `throws` in the `.evd` file
+    Application = 100,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Error)]
 pub enum RpcError {
     #[error("Serialization failed: {0}")]
     SerializationFailed(String),
+    /// An I/O error of a handler, see [`RpcError::transport`] for the ones of the connection
     #[error("IO failed: {0}")]
     IoError(String),
     #[error("{0}")]
     MpscError(String),
     #[error("{0}")]
     Custom(String),
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Connection lost")]
+    ConnectionLost,
+    #[error("{code:?}: {message}")]
+    Status {
+        code: ErrorCode,
+        message: String,
+        details: Option<serde_json::Value>,
+    },
+    #[error("{error_type}: {details}")]
+    Application {
+        error_type: String,
+        details: serde_json::Value,
+    },
+}
+
+impl RpcError {
+    #[must_use]
+    pub fn status(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Status {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    #[must_use]
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::status(ErrorCode::NotFound, message)
+    }
+
+    #[must_use]
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        Self::status(ErrorCode::InvalidArgument, message)
+    }
+
+    #[must_use]
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::status(ErrorCode::PermissionDenied, message)
+    }
+
+    #[must_use]
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::status(ErrorCode::Unavailable, message)
+    }
+
+    #[must_use]
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::status(ErrorCode::Internal, message)
+    }
+
+    /// An I/O error of the connection, the call may succeed when it's sent again. I/O errors of
+    /// handlers are [`RpcError::IoError`], which are internal.
+    #[must_use]
+    pub fn transport(e: std::io::Error) -> Self {
+        Self::unavailable(format!("IO failed: {}", e))
+    }
+
+    /// Attaches structured details to a [`RpcError::Status`], other errors are returned as is
+    #[must_use]
+    pub fn with_details(self, details: serde_json::Value) -> Self {
+        match self {
+            Self::Status { code, message, .. } => Self::Status {
+                code,
+                message,
+                details: Some(details),
+            },
+            other => other,
+        }
+    }
+
+    /// Wraps an application error, the generated code implements `From` for every error type
+    /// declared with `throws` using this
+    pub fn application<T>(error_type: &str, error: &T) -> Self
+    where
+        T: Serialize,
+    {
+        match serde_json::to_value(error) {
+            Ok(details) => Self::Application {
+                error_type: error_type.to_string(),
+                details,
+            },
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Returns the application error, if this is one of type `error_type`
+    #[must_use]
+    pub fn application_error<T>(&self, error_type: &str) -> Option<T>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            Self::Application {
+                error_type: actual,
+                details,
+            } if actual == error_type => serde_json::from_value(details.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::SerializationFailed(_) => ErrorCode::InvalidArgument,
+            Self::ConnectionLost => ErrorCode::Unavailable,
+            Self::IoError(_) | Self::MpscError(_) => ErrorCode::Internal,
+            Self::Custom(_) => ErrorCode::Unknown,
+            Self::DeadlineExceeded => ErrorCode::DeadlineExceeded,
+            Self::Cancelled => ErrorCode::Cancelled,
+            Self::Status { code, .. } => *code,
+            Self::Application { .. } => ErrorCode::Application,
+        }
+    }
+
+    #[must_use]
+    pub fn details(&self) -> Option<&serde_json::Value> {
+        match self {
+            Self::Status { details, .. } => details.as_ref(),
+            Self::Application { details, .. } => Some(details),
+            _ => None,
+        }
+    }
 }
 
 impl From<serde_json::Error> for RpcError {
@@ -20,6 +173,18 @@ impl From<serde_json::Error> for RpcError {
     }
 }
 
+impl From<rmp_serde::encode::Error> for RpcError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        RpcError::SerializationFailed(e.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for RpcError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        RpcError::SerializationFailed(e.to_string())
+    }
+}
+
 impl From<std::io::Error> for RpcError {
     fn from(e: std::io::Error) -> Self {
         RpcError::IoError(e.to_string())
@@ -31,3 +196,56 @@ impl<T> From<SendError<T>> for RpcError {
         Self::MpscError(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::rpc_error::{ErrorCode, RpcError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum TrackError {
+        NotFound { path: String },
+    }
+
+    #[test]
+    fn status_errors_keep_code_and_details_on_the_wire() {
+        let error =
+            RpcError::not_found("no such track").with_details(serde_json::json!({"path": "a.mp3"}));
+
+        let error: RpcError = serde_json::from_slice(&serde_json::to_vec(&error).unwrap()).unwrap();
+
+        assert_eq!(ErrorCode::NotFound, error.code());
+        assert_eq!(Some(&serde_json::json!({"path": "a.mp3"})), error.details());
+        assert_eq!(5, error.code() as u16);
+    }
+
+    #[test]
+    fn application_errors_can_be_extracted() {
+        let error = RpcError::application(
+            "TrackError",
+            &TrackError::NotFound {
+                path: "a.mp3".to_string(),
+            },
+        );
+
+        assert_eq!(ErrorCode::Application, error.code());
+        assert_eq!(
+            Some(TrackError::NotFound {
+                path: "a.mp3".to_string()
+            }),
+            error.application_error("TrackError")
+        );
+        assert_eq!(None, error.application_error::<TrackError>("OtherError"));
+    }
+
+    #[test]
+    fn only_io_errors_of_the_connection_are_unavailable() {
+        let io_error = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+
+        assert_eq!(ErrorCode::Internal, RpcError::from(io_error()).code());
+        assert_eq!(
+            ErrorCode::Unavailable,
+            RpcError::transport(io_error()).code()
+        );
+    }
+}
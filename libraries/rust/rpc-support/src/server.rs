@@ -0,0 +1,609 @@
+use crate::framing::{FrameWriter, Framing};
+use crate::interceptor::{CallInfo, Interceptors, Next};
+use crate::metrics::ServerMetrics;
+use crate::rpc_error::{ErrorCode, RpcError};
+use crate::trace_context::{self, TraceContext};
+use crate::transport::Peer;
+use crate::{
+    send_response, send_unary_response, write_frames_task, ResponseEnvelope, STREAM_CREDITS,
+};
+use dashmap::DashMap;
+use futures::future::{AbortHandle, Abortable, Aborted};
+use futures::{Stream, StreamExt};
+use platform::async_infra::run_with_error_handling;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncWrite;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::Instant;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// Time running calls get to finish once a shutdown was triggered, unless the server sets its own
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+tokio::task_local! {
+    static PEER: Arc<Peer>;
+}
+
+/// Returns the peer that sent the request currently being handled, including its identity if it
+/// authenticated with a client certificate. Only set inside `Rpc` handlers.
+#[must_use]
+pub fn peer() -> Option<Arc<Peer>> {
+    PEER.try_with(Clone::clone).ok()
+}
+
+#[derive(Debug, Error)]
+pub enum RunError {
+    #[error("{0}")]
+    IoError(#[from] tokio::io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("{0}")]
+    IoError(#[from] tokio::io::Error),
+    #[error("{0}")]
+    RpcError(#[from] RpcError),
+}
+
+pub enum Request<TMetadata> {
+    Call(Box<Call<TMetadata>>),
+    /// The client is no longer interested in the response of this request
+    Cancel {
+        request_id: u64,
+    },
+    /// The client consumed items of a stream, the server may send `credits` more
+    Credit {
+        request_id: u64,
+        credits: u32,
+    },
+    /// An item of the request stream of a call
+    Item {
+        request_id: u64,
+        payload: Vec<u8>,
+    },
+    /// The request stream of a call is complete
+    EndOfInput {
+        request_id: u64,
+    },
+    /// The client asks for the schema of the service, see [`crate::reflection`]
+    Reflect {
+        request_id: u64,
+    },
+    /// A call that cannot be handled, e.g. because its metadata is invalid. Only the call fails
+    /// with `error`, see [`reject`].
+    Rejected {
+        request_id: u64,
+        error: RpcError,
+    },
+}
+
+pub struct Call<TMetadata> {
+    pub payload: Vec<u8>,
+    pub method_name: String,
+    pub request_id: u64,
+    pub metadata: TMetadata,
+    pub timeout: Option<Duration>,
+    /// Initial credits of a stream, see [`crate::send_stream_response`]
+    pub credits: Option<u32>,
+    /// The requests follow in [`Request::Item`]s instead of the payload
+    pub request_stream: bool,
+    /// Set by [`ActiveCalls::open_input`] for request streams
+    pub input: Option<RequestInput>,
+    /// Context of the client, if it sent one
+    pub trace: Option<TraceContext>,
+    /// Tells the attempts of an idempotent call apart from other calls, see
+    /// [`crate::deduplication`]
+    pub idempotency_key: Option<String>,
+}
+
+/// Stops a server gracefully. Once triggered, the server stops accepting connections and calls,
+/// running unary calls may finish and open streams end with an `Unavailable` error.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    triggered: AtomicBool,
+    /// Set once the server stopped waiting for the running calls
+    abandoned: AtomicBool,
+    running: AtomicUsize,
+    changed: Notify,
+}
+
+impl Shutdown {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.state.triggered.store(true, Ordering::Release);
+        self.state.changed.notify_waiters();
+    }
+
+    /// Triggers the shutdown once the process receives SIGTERM or SIGINT
+    ///
+    /// # Errors
+    /// Fails if the signal handlers can't be installed
+    #[cfg(unix)]
+    pub fn trigger_on_signal(&self) -> Result<(), std::io::Error> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let shutdown = self.clone();
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+                _ = interrupt.recv() => info!("Received SIGINT, shutting down"),
+            }
+
+            shutdown.trigger();
+        });
+
+        Ok(())
+    }
+
+    /// Triggers the shutdown once the process receives Ctrl+C, the only signal outside of Unix
+    ///
+    /// # Errors
+    /// Never fails, the handler is installed by the spawned task
+    #[cfg(not(unix))]
+    pub fn trigger_on_signal(&self) -> Result<(), std::io::Error> {
+        let shutdown = self.clone();
+
+        tokio::spawn(async move {
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => {
+                    info!("Received Ctrl+C, shutting down");
+                    shutdown.trigger();
+                }
+                Err(e) => warn!("Failed to listen for Ctrl+C: {}", e),
+            }
+        });
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_triggered(&self) -> bool {
+        self.state.triggered.load(Ordering::Acquire)
+    }
+
+    pub async fn triggered(&self) {
+        self.wait_until(|state| state.triggered.load(Ordering::Acquire))
+            .await;
+    }
+
+    /// Waits until the shutdown was triggered and no calls are running anymore, or the server
+    /// stopped waiting for them
+    pub async fn drained(&self) {
+        self.wait_until(|state| {
+            state.triggered.load(Ordering::Acquire)
+                && (state.running.load(Ordering::Acquire) == 0
+                    || state.abandoned.load(Ordering::Acquire))
+        })
+        .await;
+    }
+
+    /// Waits for the running calls for at most `timeout`, the connections are closed afterwards
+    /// which aborts the calls that are still running
+    pub async fn drain(&self, timeout: Duration) {
+        info!(
+            "Waiting for {} running calls",
+            self.state.running.load(Ordering::Acquire)
+        );
+
+        if tokio::time::timeout(timeout, self.drained()).await.is_err() {
+            warn!(
+                "Aborting {} calls that are still running after {:?}",
+                self.state.running.load(Ordering::Acquire),
+                timeout
+            );
+        }
+
+        self.state.abandoned.store(true, Ordering::Release);
+        self.state.changed.notify_waiters();
+    }
+
+    /// Counts the call as running until the returned guard is dropped
+    pub(crate) fn track(&self) -> RunningCall {
+        self.state.running.fetch_add(1, Ordering::AcqRel);
+
+        RunningCall(self.state.clone())
+    }
+
+    async fn wait_until(&self, condition: impl Fn(&ShutdownState) -> bool) {
+        loop {
+            // Created before checking, so no notification in between is missed
+            let changed = self.state.changed.notified();
+
+            if condition(&self.state) {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+}
+
+pub(crate) struct RunningCall(Arc<ShutdownState>);
+
+impl Drop for RunningCall {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::AcqRel);
+        self.0.changed.notify_waiters();
+    }
+}
+
+#[must_use]
+pub fn shutting_down() -> RpcError {
+    RpcError::unavailable("The server is shutting down")
+}
+
+#[must_use]
+pub fn unknown_method(method_name: &str) -> RpcError {
+    RpcError::status(
+        ErrorCode::Unimplemented,
+        format!("Unknown method name: {}", method_name),
+    )
+}
+
+#[must_use]
+pub fn missing_request_stream(method_name: &str) -> RpcError {
+    RpcError::invalid_argument(format!("{} expects a request stream", method_name))
+}
+
+/// Answers the call `request_id` with `error` without handling it
+pub fn reject(writer: &ResponseWriter, request_id: u64, error: RpcError) {
+    let writer = writer.clone();
+
+    tokio::spawn(run_with_error_handling(async move {
+        send_unary_response::<()>(&writer, Err(error), request_id).await
+    }));
+}
+
+/// Handle to the single task that owns the write half of a connection. Every request is handled
+/// in its own task, their responses are multiplexed through this writer so frames never interleave.
+#[derive(Clone)]
+pub struct ResponseWriter {
+    frames: Sender<Vec<Vec<u8>>>,
+    framing: Framing,
+    /// Credits of the flow controlled streams of the connection
+    credits: Arc<DashMap<u64, Arc<Semaphore>>>,
+    pub(crate) metrics: Arc<ServerMetrics>,
+    pub(crate) shutdown: Shutdown,
+}
+
+impl ResponseWriter {
+    /// Spawns the writer task, it stops once every clone of the returned handle is dropped
+    pub fn spawn<W>(writer: FrameWriter<W>, metrics: Arc<ServerMetrics>, shutdown: Shutdown) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let framing = writer.framing();
+        let (frames, frames_rx) = tokio::sync::mpsc::channel(64);
+
+        tokio::spawn(run_with_error_handling(write_frames_task(
+            writer, frames_rx,
+        )));
+
+        Self {
+            frames,
+            framing,
+            credits: Arc::default(),
+            metrics,
+            shutdown,
+        }
+    }
+
+    #[must_use]
+    pub const fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// # Errors
+    /// Can fail if the writer task has stopped, usually because the connection was closed
+    pub async fn write_frame(&self, sections: Vec<Vec<u8>>) -> Result<(), RpcError> {
+        self.frames.send(sections).await?;
+
+        Ok(())
+    }
+
+    /// Allows the stream `request_id` to send `credits` more items. Streams that already ended are
+    /// ignored.
+    pub fn grant_credits(&self, request_id: u64, credits: u32) {
+        if let Some(semaphore) = self.credits.get(&request_id) {
+            semaphore.add_permits(credits as usize);
+        }
+    }
+
+    pub(crate) fn flow_control(&self, request_id: u64, credits: u32) -> StreamCredits {
+        let semaphore = Arc::new(Semaphore::new(credits as usize));
+        self.credits.insert(request_id, semaphore.clone());
+
+        StreamCredits {
+            request_id,
+            semaphore,
+            credits: self.credits.clone(),
+        }
+    }
+}
+
+/// Credits of a single stream, stops accepting grants once dropped
+pub(crate) struct StreamCredits {
+    request_id: u64,
+    semaphore: Arc<Semaphore>,
+    credits: Arc<DashMap<u64, Arc<Semaphore>>>,
+}
+
+impl StreamCredits {
+    /// Waits until the client granted a credit and uses it up
+    pub async fn acquire(&self) {
+        // The semaphore is never closed
+        if let Ok(permit) = self.semaphore.acquire().await {
+            permit.forget();
+        }
+    }
+}
+
+impl Drop for StreamCredits {
+    fn drop(&mut self) {
+        self.credits.remove(&self.request_id);
+    }
+}
+
+/// Calls of a single connection that are still running. Dropping it, e.g. because the connection
+/// was closed, aborts all of them.
+pub struct ActiveCalls<TMetadata> {
+    calls: Arc<DashMap<u64, AbortHandle>>,
+    /// Request streams of the calls, closed once the client sends the end-of-input marker
+    inputs: Arc<DashMap<u64, Sender<Vec<u8>>>>,
+    peer: Arc<Peer>,
+    interceptors: Interceptors<TMetadata>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl<TMetadata> ActiveCalls<TMetadata>
+where
+    TMetadata: Clone + Send + Sync + 'static,
+{
+    #[must_use]
+    pub fn new(
+        peer: Peer,
+        interceptors: Interceptors<TMetadata>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Self {
+        Self {
+            calls: Arc::default(),
+            inputs: Arc::default(),
+            peer: Arc::new(peer),
+            interceptors,
+            metrics,
+        }
+    }
+
+    #[must_use]
+    pub fn call_info(&self, call: &Call<TMetadata>) -> CallInfo<TMetadata> {
+        CallInfo {
+            method_name: call.method_name.clone(),
+            request_id: call.request_id,
+            metadata: call.metadata.clone(),
+            peer: self.peer.clone(),
+            received_at: Instant::now(),
+            trace: call
+                .trace
+                .as_ref()
+                .map_or_else(TraceContext::new_root, TraceContext::child),
+        }
+    }
+
+    /// Routes the items of the request stream of `call` to [`Call::input`], if it has one. Has
+    /// to happen before the next request of the connection is read.
+    pub fn open_input(&self, call: &mut Call<TMetadata>, writer: &ResponseWriter) {
+        if !call.request_stream {
+            return;
+        }
+
+        let (items_tx, items) = tokio::sync::mpsc::channel(STREAM_CREDITS as usize);
+        self.inputs.insert(call.request_id, items_tx);
+
+        call.input = Some(RequestInput {
+            request_id: call.request_id,
+            items,
+            writer: writer.clone(),
+            inputs: self.inputs.clone(),
+        });
+    }
+
+    /// Items of calls that already finished are dropped
+    pub async fn push_input(&self, request_id: u64, payload: Vec<u8>) {
+        let items = self.inputs.get(&request_id).map(|items| items.clone());
+
+        if let Some(items) = items {
+            // Never waits for clients that respect their credits
+            let _ = items.send(payload).await;
+        }
+    }
+
+    pub fn end_input(&self, request_id: u64) {
+        self.inputs.remove(&request_id);
+    }
+
+    /// Runs `call` through the interceptors in its own task until it finishes, runs out of time
+    /// or gets cancelled. `call` sends its response and returns the status the client received.
+    /// If it doesn't get to do that, the error that stopped it is sent as a terminal error. Calls
+    /// that arrive after a shutdown was triggered are rejected.
+    pub fn spawn<F>(
+        &self,
+        info: CallInfo<TMetadata>,
+        timeout: Option<Duration>,
+        writer: ResponseWriter,
+        call: F,
+    ) where
+        F: Future<Output = Result<(), RpcError>> + Send + 'static,
+    {
+        let request_id = info.request_id;
+
+        if writer.shutdown.is_triggered() {
+            tokio::spawn(run_with_error_handling(async move {
+                send_response(
+                    &writer,
+                    Result::<(), _>::Err(shutting_down()),
+                    request_id,
+                    true,
+                )
+                .await
+            }));
+
+            return;
+        }
+
+        let running = writer.shutdown.track();
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        self.calls.insert(request_id, abort_handle);
+        let calls = self.calls.clone();
+        let interceptors = self.interceptors.clone();
+        let metrics = self.metrics.clone();
+        let responded = Arc::new(AtomicBool::new(false));
+
+        let handler = {
+            let responded = responded.clone();
+
+            async move {
+                let call = Abortable::new(call, abort_registration);
+                let result = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, call)
+                        .await
+                        .map_err(|_| RpcError::DeadlineExceeded),
+                    None => Ok(call.await),
+                };
+
+                match result {
+                    Ok(Ok(result)) => {
+                        responded.store(true, Ordering::Release);
+                        result
+                    }
+                    Ok(Err(Aborted)) => Err(RpcError::Cancelled),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        let span = info_span!(
+            "rpc",
+            method = %info.method_name,
+            request_id,
+            trace_id = %info.trace.trace_id,
+            span_id = %info.trace.span_id,
+            parent_span_id = info.trace.parent_span_id.as_deref().unwrap_or_default(),
+        );
+        let trace = info.trace.clone();
+
+        let call = PEER.scope(self.peer.clone(), async move {
+            let _running = running;
+            let result = trace_context::scope(
+                trace,
+                Next::new(&interceptors, Box::pin(handler)).run(&info),
+            )
+            .await;
+
+            calls.remove(&request_id);
+            metrics.record_call(&info.method_name, &result, info.received_at.elapsed());
+
+            if responded.load(Ordering::Acquire) {
+                if let Err(e) = result {
+                    debug!("Request {} failed: {}", request_id, e);
+                }
+
+                return Ok(());
+            }
+
+            let error = result.err().unwrap_or_else(|| {
+                RpcError::internal("The call was not handled by the interceptors")
+            });
+            send_response(&writer, Result::<(), _>::Err(error), request_id, true).await
+        });
+
+        tokio::spawn(run_with_error_handling(call.instrument(span)));
+    }
+
+    pub fn cancel(&self, request_id: u64) {
+        self.inputs.remove(&request_id);
+
+        if let Some((_, abort_handle)) = self.calls.remove(&request_id) {
+            abort_handle.abort();
+        }
+    }
+}
+
+impl<TMetadata> Drop for ActiveCalls<TMetadata> {
+    fn drop(&mut self) {
+        for call in self.calls.iter() {
+            call.abort();
+        }
+    }
+}
+
+/// The request stream of a call, grants the client credits as its items are consumed
+pub struct RequestInput {
+    request_id: u64,
+    items: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    writer: ResponseWriter,
+    inputs: Arc<DashMap<u64, Sender<Vec<u8>>>>,
+}
+
+impl RequestInput {
+    /// Decodes the items, the stream ends with the end-of-input marker of the client
+    #[must_use]
+    pub fn into_stream<T>(
+        mut self,
+    ) -> Pin<Box<dyn Stream<Item = Result<T, RpcError>> + Unpin + Send>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let framing = self.writer.framing();
+
+        let payloads = Box::pin(async_stream::stream! {
+            let mut consumed = 0;
+
+            while let Some(payload) = self.items.recv().await {
+                yield payload;
+
+                consumed += 1;
+                if consumed >= STREAM_CREDITS / 2 {
+                    self.grant_credits(consumed).await;
+                    consumed = 0;
+                }
+            }
+        });
+
+        Box::pin(payloads.map(move |payload| framing.decode_payload(&payload)))
+    }
+
+    async fn grant_credits(&self, credits: u32) {
+        match serde_json::to_vec(&ResponseEnvelope::credit(self.request_id, credits)) {
+            // Fails only if the connection is closed, which ends the call anyway
+            Ok(envelope) => {
+                let _ = self.writer.write_frame(vec![envelope]).await;
+            }
+            Err(e) => tracing::error!("Failed to serialize credits: {}", e),
+        }
+    }
+}
+
+impl Drop for RequestInput {
+    fn drop(&mut self) {
+        self.inputs.remove(&self.request_id);
+    }
+}
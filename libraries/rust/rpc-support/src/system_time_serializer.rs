@@ -1,45 +1,222 @@
-use serde::de::{Error, Visitor};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{Error, MapAccess, SeqAccess, Visitor};
 use serde::ser::Error as SerError;
-use serde::{Deserializer, Serializer};
+use serde::{Deserialize, Deserializer, Serializer};
 use std::fmt::Formatter;
-use std::ops::Add;
 use std::time::{Duration, SystemTime};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
 struct TimestampVisitor;
 
 impl<'de> Visitor<'de> for TimestampVisitor {
-    type Value = u64;
+    type Value = SystemTime;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        write!(formatter, "u64")
+        write!(
+            formatter,
+            "an RFC 3339 timestamp, seconds since Unix Epoch or a serde `SystemTime`"
+        )
     }
 
+    /// Timestamps used to be whole seconds since Unix Epoch
     fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
     where
         E: Error,
     {
-        Ok(v)
+        SystemTime::UNIX_EPOCH
+            .checked_add(Duration::from_secs(v))
+            .ok_or_else(|| E::custom("The timestamp is out of range"))
+    }
+
+    /// Fields without this serializer use serde's `SystemTime` encoding,
+    /// `{"secs_since_epoch": .., "nanos_since_epoch": ..}`
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        SystemTime::deserialize(MapAccessDeserializer::new(map))
+    }
+
+    /// The same encoding in binary payloads, which write structs as arrays
+    fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        SystemTime::deserialize(SeqAccessDeserializer::new(seq))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        Ok(OffsetDateTime::parse(v, &Rfc3339)
+            .map_err(E::custom)?
+            .into())
     }
 }
 
+/// Writes an RFC 3339 timestamp in UTC with nanosecond precision, e.g.
+/// `2022-10-17T09:46:40.123456789Z`
+///
 /// # Errors
-/// Can fail if the `SystemTime` was before `UNIX_EPOCH`
+/// Can fail if the `SystemTime` was before `UNIX_EPOCH` or after the year 9999
 pub fn serialize<S>(val: &SystemTime, ser: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    ser.serialize_u64(
-        val.duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|_| S::Error::custom("The timestamp was before Unix Epoch?"))?
-            .as_secs(),
-    )
+    let since_epoch = val
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| S::Error::custom("The timestamp was before Unix Epoch?"))?;
+    let timestamp = time::Duration::try_from(since_epoch)
+        .ok()
+        .and_then(|duration| OffsetDateTime::UNIX_EPOCH.checked_add(duration))
+        .ok_or_else(|| S::Error::custom("The timestamp is out of range"))?;
+
+    ser.serialize_str(&timestamp.format(&Rfc3339).map_err(S::Error::custom)?)
 }
 
 /// # Errors
-/// Can fail if the value is not `u64`
+/// Can fail if the value is neither an RFC 3339 timestamp, `u64` nor a serde `SystemTime`
 pub fn deserialize<'de, D>(des: D) -> Result<SystemTime, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Ok(SystemTime::UNIX_EPOCH.add(Duration::from_secs(des.deserialize_u64(TimestampVisitor)?)))
+    des.deserialize_any(TimestampVisitor)
+}
+
+/// The same encoding for `Option<SystemTime>` fields
+pub mod optional {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::SystemTime;
+
+    #[derive(Serialize, Deserialize)]
+    struct Timestamp(#[serde(with = "super")] SystemTime);
+
+    /// # Errors
+    /// Can fail if the `SystemTime` was before `UNIX_EPOCH`
+    pub fn serialize<S>(val: &Option<SystemTime>, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match val {
+            Some(timestamp) => ser.serialize_some(&Timestamp(*timestamp)),
+            None => ser.serialize_none(),
+        }
+    }
+
+    /// # Errors
+    /// Can fail if the value is neither null, an RFC 3339 timestamp, `u64` nor a serde `SystemTime`
+    pub fn deserialize<'de, D>(des: D) -> Result<Option<SystemTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Timestamp>::deserialize(des)?.map(|Timestamp(timestamp)| timestamp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::framing::Framing;
+    use serde::{Deserialize, Serialize};
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Event {
+        #[serde(with = "crate::system_time_serializer")]
+        created_time: SystemTime,
+        #[serde(default, with = "crate::system_time_serializer::optional")]
+        from: Option<SystemTime>,
+    }
+
+    fn at(nanos: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos)
+    }
+
+    #[test]
+    fn timestamps_keep_nanoseconds() {
+        let event = Event {
+            created_time: at(1_666_000_000_123_456_789),
+            from: Some(at(1_666_000_000_000_000_001)),
+        };
+
+        let json = serde_json::to_string(&event).unwrap();
+        let binary = Framing::Binary.encode_payload(&event).unwrap();
+
+        assert_eq!(
+            r#"{"created_time":"2022-10-17T09:46:40.123456789Z","from":"2022-10-17T09:46:40.000000001Z"}"#,
+            json
+        );
+        assert_eq!(event, serde_json::from_str(&json).unwrap());
+        assert_eq!(
+            event,
+            Framing::Binary.decode_payload::<Event>(&binary).unwrap()
+        );
+    }
+
+    #[test]
+    fn whole_seconds_are_still_accepted() {
+        assert_eq!(
+            Event {
+                created_time: at(1_666_000_000_000_000_000),
+                from: None,
+            },
+            serde_json::from_str(r#"{"created_time":1666000000}"#).unwrap()
+        );
+        assert_eq!(
+            Event {
+                created_time: at(1_666_000_000_500_000_000),
+                from: Some(at(1_666_000_000_000_000_000)),
+            },
+            serde_json::from_str(
+                r#"{"created_time":"2022-10-17T11:46:40.5+02:00","from":1666000000}"#
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn serde_system_times_are_still_accepted() {
+        #[derive(Serialize)]
+        struct LegacyEvent {
+            created_time: SystemTime,
+            from: Option<SystemTime>,
+        }
+
+        let event = Event {
+            created_time: at(1_666_000_000_000_000_005),
+            from: Some(at(1_666_000_000_000_000_000)),
+        };
+        let legacy = LegacyEvent {
+            created_time: event.created_time,
+            from: event.from,
+        };
+
+        assert_eq!(
+            event,
+            serde_json::from_str(
+                r#"{"created_time":{"secs_since_epoch":1666000000,"nanos_since_epoch":5},"from":{"secs_since_epoch":1666000000,"nanos_since_epoch":0}}"#
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            event,
+            Framing::Binary
+                .decode_payload::<Event>(&Framing::Binary.encode_payload(&legacy).unwrap())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn timestamps_out_of_range_are_errors() {
+        let event = Event {
+            created_time: at(0) + Duration::from_secs(400_000_000_000),
+            from: None,
+        };
+
+        assert!(serde_json::to_string(&event).is_err());
+        assert!(
+            serde_json::from_str::<Event>(&format!(r#"{{"created_time":{}}}"#, u64::MAX)).is_err()
+        );
+    }
 }
@@ -0,0 +1,359 @@
+use crate::transport::{Connector, Listener, Peer, PeerIdentity, Transport};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use platform::secrets::SecretProvider;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+
+/// Files of a TLS secret, named like the keys of a Kubernetes TLS secret
+const CERTIFICATE_FILE: &str = "tls.crt";
+const PRIVATE_KEY_FILE: &str = "tls.key";
+const CA_FILE: &str = "ca.crt";
+
+/// Clients that don't finish the handshake in time are disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to read secret: {0}")]
+    Secret(#[from] platform::secrets::Error),
+    #[error("Failed to parse PEM file: {0}")]
+    Pem(#[from] std::io::Error),
+    #[error("{0} does not contain a private key")]
+    MissingPrivateKey(&'static str),
+    #[error("{0} does not contain a valid certificate")]
+    InvalidCertificate(&'static str),
+    #[error("Invalid server name: {0}")]
+    InvalidServerName(String),
+    #[error("{0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Loads the server configuration from the secret `name`. Its `tls.crt` and `tls.key` are the
+/// certificate chain and private key of the server. If it contains a `ca.crt`, clients must
+/// present a certificate signed by that CA (mutual TLS).
+///
+/// # Errors
+/// Will return an error if the secret can't be read or doesn't contain valid certificates
+pub fn server_config(secrets: &SecretProvider, name: &str) -> Result<Arc<ServerConfig>, TlsError> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match read_optional(secrets, name, CA_FILE)? {
+        Some(ca) => builder.with_client_cert_verifier(
+            rustls::server::AllowAnyAuthenticatedClient::new(root_store(&ca)?),
+        ),
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder.with_single_cert(
+        certificates(&secrets.read_file(name, CERTIFICATE_FILE)?)?,
+        private_key(&secrets.read_file(name, PRIVATE_KEY_FILE)?)?,
+    )?;
+
+    Ok(Arc::new(config))
+}
+
+/// Loads the client configuration from the secret `name`. The server certificate is verified
+/// against its `ca.crt`. If it contains a `tls.crt` and `tls.key`, the client authenticates
+/// itself with them.
+///
+/// # Errors
+/// Will return an error if the secret can't be read or doesn't contain valid certificates
+pub fn client_config(secrets: &SecretProvider, name: &str) -> Result<Arc<ClientConfig>, TlsError> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store(&secrets.read_file(name, CA_FILE)?)?);
+
+    let config = match read_optional(secrets, name, CERTIFICATE_FILE)? {
+        Some(certificate) => builder.with_single_cert(
+            certificates(&certificate)?,
+            private_key(&secrets.read_file(name, PRIVATE_KEY_FILE)?)?,
+        )?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+fn read_optional(
+    secrets: &SecretProvider,
+    name: &str,
+    file: &str,
+) -> Result<Option<Vec<u8>>, TlsError> {
+    match secrets.read_file(name, file) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(platform::secrets::Error::Io(e)) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<Certificate>, TlsError> {
+    let certificates = rustls_pemfile::certs(&mut &pem[..])?;
+
+    if certificates.is_empty() {
+        return Err(TlsError::InvalidCertificate(CERTIFICATE_FILE));
+    }
+
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKey, TlsError> {
+    for item in rustls_pemfile::read_all(&mut &pem[..])? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    Err(TlsError::MissingPrivateKey(PRIVATE_KEY_FILE))
+}
+
+fn root_store(pem: &[u8]) -> Result<RootCertStore, TlsError> {
+    let mut store = RootCertStore::empty();
+    let (added, _) = store.add_parsable_certificates(&rustls_pemfile::certs(&mut &pem[..])?);
+
+    if added == 0 {
+        return Err(TlsError::InvalidCertificate(CA_FILE));
+    }
+
+    Ok(store)
+}
+
+/// Wraps the connections of another connector, e.g. a [`crate::transport::TcpConnector`], in TLS
+pub struct TlsConnector {
+    inner: Box<dyn Connector>,
+    connector: tokio_rustls::TlsConnector,
+    server_name: ServerName,
+}
+
+impl TlsConnector {
+    /// `server_name` is the name the server certificate must be valid for
+    ///
+    /// # Errors
+    /// Will return an error if `server_name` is neither a valid DNS name nor an IP address
+    pub fn new(
+        inner: impl Connector + 'static,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self, TlsError> {
+        Ok(Self {
+            inner: Box::new(inner),
+            connector: config.into(),
+            server_name: ServerName::try_from(server_name)
+                .map_err(|_| TlsError::InvalidServerName(server_name.to_string()))?,
+        })
+    }
+}
+
+impl Display for TlsConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tls:{}", self.inner)
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for TlsConnector {
+    async fn connect(&self) -> std::io::Result<Box<dyn Transport>> {
+        let socket = self.inner.connect().await?;
+
+        Ok(Box::new(
+            self.connector
+                .connect(self.server_name.clone(), socket)
+                .await?,
+        ))
+    }
+}
+
+type Handshake = Pin<Box<dyn Future<Output = std::io::Result<(Box<dyn Transport>, Peer)>> + Send>>;
+
+/// Wraps the connections of another listener in TLS. The identity of clients that present a
+/// certificate is available through [`Peer::identity`].
+pub struct TlsListener {
+    inner: Box<dyn Listener>,
+    acceptor: tokio_rustls::TlsAcceptor,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl TlsListener {
+    #[must_use]
+    pub fn new(inner: impl Listener + 'static, config: Arc<ServerConfig>) -> Self {
+        Self {
+            inner: Box::new(inner),
+            acceptor: config.into(),
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+
+    fn handshake(&self, socket: Box<dyn Transport>, mut peer: Peer) -> Handshake {
+        let acceptor = self.acceptor.clone();
+
+        Box::pin(async move {
+            let socket = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out")
+                })??;
+
+            peer.identity = socket
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(<[Certificate]>::first)
+                .map(|certificate| peer_identity(&certificate.0));
+
+            Ok((Box::new(socket) as Box<dyn Transport>, peer))
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Listener for TlsListener {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)> {
+        // Handshakes run concurrently so a slow client can't hold up everyone else
+        loop {
+            tokio::select! {
+                accepted = self.inner.accept() => {
+                    let (socket, peer) = accepted?;
+                    let handshake = self.handshake(socket, peer);
+                    self.handshakes.push(handshake);
+                }
+                Some(result) = self.handshakes.next() => match result {
+                    Ok(connection) => return Ok(connection),
+                    Err(e) => warn!("TLS handshake failed: {}", e),
+                },
+            }
+        }
+    }
+}
+
+fn peer_identity(certificate: &[u8]) -> PeerIdentity {
+    // The certificate was already validated during the handshake
+    let name = x509_parser::parse_x509_certificate(certificate).map_or_else(
+        |_| String::new(),
+        |(_, parsed)| {
+            let subject = parsed.subject();
+
+            subject
+                .iter_common_name()
+                .next()
+                .and_then(|name| name.as_str().ok())
+                .map_or_else(|| subject.to_string(), ToString::to_string)
+        },
+    );
+
+    PeerIdentity {
+        name,
+        certificate: certificate.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::memory;
+    use crate::transport::test::assert_connected;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use std::path::{Path, PathBuf};
+    use tokio::io::AsyncReadExt;
+
+    fn certificate(name: &str, is_ca: bool) -> rcgen::Certificate {
+        let mut params = CertificateParams::new(vec![name.to_string()]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        if is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn write_secret(
+        base: &Path,
+        name: &str,
+        certificate: Option<(&rcgen::Certificate, &rcgen::Certificate)>,
+        ca: Option<&rcgen::Certificate>,
+    ) {
+        let path = base.join(name);
+        std::fs::create_dir_all(&path).unwrap();
+
+        if let Some((certificate, ca)) = certificate {
+            let pem = certificate.serialize_pem_with_signer(ca).unwrap();
+            std::fs::write(path.join(CERTIFICATE_FILE), pem).unwrap();
+            let key = certificate.serialize_private_key_pem();
+            std::fs::write(path.join(PRIVATE_KEY_FILE), key).unwrap();
+        }
+        if let Some(ca) = ca {
+            std::fs::write(path.join(CA_FILE), ca.serialize_pem().unwrap()).unwrap();
+        }
+    }
+
+    /// Creates the secrets `server`, `client` and `anonymous-client`, all signed by the same CA
+    fn secrets(test: &str) -> PathBuf {
+        let base =
+            std::env::temp_dir().join(format!("rpc-support-{}-{}", test, std::process::id()));
+        let ca = certificate("ca", true);
+
+        let server = certificate("localhost", false);
+        write_secret(&base, "server", Some((&server, &ca)), Some(&ca));
+        let client = certificate("svc-client", false);
+        write_secret(&base, "client", Some((&client, &ca)), Some(&ca));
+        write_secret(&base, "anonymous-client", None, Some(&ca));
+
+        base
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_identifies_the_client() {
+        let base = secrets("mtls");
+        let secrets = SecretProvider::new(base.to_str().unwrap());
+        let (connector, listener) = memory();
+
+        let connector = TlsConnector::new(
+            connector,
+            "localhost",
+            client_config(&secrets, "client").unwrap(),
+        )
+        .unwrap();
+        let mut listener = TlsListener::new(listener, server_config(&secrets, "server").unwrap());
+
+        let peer = assert_connected(&connector, &mut listener).await;
+
+        assert_eq!("svc-client", peer.identity.unwrap().name);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clients_without_certificate_are_rejected() {
+        let base = secrets("anonymous");
+        let secrets = SecretProvider::new(base.to_str().unwrap());
+        let (connector, listener) = memory();
+
+        let connector = TlsConnector::new(
+            connector,
+            "localhost",
+            client_config(&secrets, "anonymous-client").unwrap(),
+        )
+        .unwrap();
+        let mut listener = TlsListener::new(listener, server_config(&secrets, "server").unwrap());
+
+        let accepted = tokio::spawn(async move { listener.accept().await.map(|_| ()) });
+
+        // With TLS 1.3 the client only learns about the rejection once it reads
+        if let Ok(mut socket) = connector.connect().await {
+            let mut buffer = [0; 1];
+            assert!(socket.read(&mut buffer).await.is_err());
+        }
+
+        assert!(!accepted.is_finished());
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
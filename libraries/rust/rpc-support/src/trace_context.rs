@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Identifies a span of a trace across services. Clients send the context they run in with every
+/// call, the server handles it in a child span of the same trace.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 hex digits, shared by all spans of a trace
+    pub trace_id: String,
+    /// 16 hex digits
+    pub span_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new trace
+    #[must_use]
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: Uuid::new_v4().simple().to_string(),
+            span_id: new_span_id(),
+            parent_span_id: None,
+        }
+    }
+
+    #[must_use]
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            parent_span_id: Some(self.span_id.clone()),
+        }
+    }
+
+    /// Returns the context of the task, set inside `Rpc` handlers and [`scope`]
+    #[must_use]
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+}
+
+fn new_span_id() -> String {
+    let mut id = Uuid::new_v4().simple().to_string();
+    id.truncate(16);
+
+    id
+}
+
+/// Runs `future` in `context`, calls it sends continue its trace. Tasks spawned by it don't
+/// inherit the context.
+pub async fn scope<F>(context: TraceContext, future: F) -> F::Output
+where
+    F: Future,
+{
+    CURRENT.scope(context, future).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn children_continue_the_trace() {
+        let root = TraceContext::new_root();
+
+        let current = scope(root.child(), async { TraceContext::current() }).await;
+
+        let current = current.unwrap();
+        assert_eq!(root.trace_id, current.trace_id);
+        assert_eq!(Some(root.span_id), current.parent_span_id);
+        assert_eq!(16, current.span_id.len());
+        assert_eq!(None, TraceContext::current());
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// Buffer of each direction of an in-memory connection
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A bidirectional byte stream a connection runs on
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Opens connections for a client. Called again whenever the client reconnects.
+#[async_trait::async_trait]
+pub trait Connector: Display + Send + Sync {
+    async fn connect(&self) -> std::io::Result<Box<dyn Transport>>;
+}
+
+/// Accepts connections for a server
+#[async_trait::async_trait]
+pub trait Listener: Send {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)>;
+}
+
+/// The other end of a connection accepted by a [`Listener`]
+#[derive(Clone, Debug)]
+pub struct Peer {
+    /// Description of the remote address, only meant for logging
+    pub address: String,
+    /// Set if the peer authenticated itself with a client certificate
+    pub identity: Option<PeerIdentity>,
+}
+
+impl Peer {
+    #[must_use]
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            identity: None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.identity {
+            Some(identity) => write!(f, "{} ({})", self.address, identity.name),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Common name of the certificate subject, or the whole subject if it has none
+    pub name: String,
+    /// DER encoded certificate the peer presented
+    pub certificate: Vec<u8>,
+}
+
+pub struct TcpConnector {
+    addr: String,
+}
+
+impl TcpConnector {
+    #[must_use]
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+impl Display for TcpConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for TcpConnector {
+    async fn connect(&self) -> std::io::Result<Box<dyn Transport>> {
+        Ok(Box::new(tokio::net::TcpStream::connect(&self.addr).await?))
+    }
+}
+
+#[async_trait::async_trait]
+impl Listener for TcpListener {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)> {
+        let (socket, address) = TcpListener::accept(self).await?;
+
+        Ok((Box::new(socket), Peer::new(address.to_string())))
+    }
+}
+
+/// Connects to a Unix domain socket, only available on Unix platforms
+#[cfg(unix)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixConnector {
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[cfg(unix)]
+impl Display for UnixConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unix:{}", self.path.display())
+    }
+}
+
+#[cfg(unix)]
+#[async_trait::async_trait]
+impl Connector for UnixConnector {
+    async fn connect(&self) -> std::io::Result<Box<dyn Transport>> {
+        Ok(Box::new(tokio::net::UnixStream::connect(&self.path).await?))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait::async_trait]
+impl Listener for UnixListener {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)> {
+        let (socket, address) = UnixListener::accept(self).await?;
+
+        Ok((Box::new(socket), Peer::new(format!("{:?}", address))))
+    }
+}
+
+/// Accepts the connections of several listeners, e.g. to serve TCP and WebSocket clients with
+/// one server. Relies on the listeners not losing connections when an accept is cancelled, which
+/// holds for every listener of this crate.
+#[derive(Default)]
+pub struct Listeners {
+    listeners: Vec<Box<dyn Listener>>,
+}
+
+impl Listeners {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with(mut self, listener: impl Listener + 'static) -> Self {
+        self.listeners.push(Box::new(listener));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Listener for Listeners {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)> {
+        if self.listeners.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "No listeners to accept connections from",
+            ));
+        }
+
+        let accepts = self.listeners.iter_mut().map(|listener| listener.accept());
+        let (accepted, _, _) = futures::future::select_all(accepts).await;
+
+        accepted
+    }
+}
+
+/// Creates a connected pair for in-process connections, e.g. to wire a client and a server
+/// together in tests without binding a port. The connector can be cloned for multiple clients.
+#[must_use]
+pub fn memory() -> (MemoryConnector, MemoryListener) {
+    let (connections_tx, connections_rx) = tokio::sync::mpsc::channel(16);
+
+    (
+        MemoryConnector {
+            connections: connections_tx,
+        },
+        MemoryListener {
+            connections: connections_rx,
+        },
+    )
+}
+
+#[derive(Clone)]
+pub struct MemoryConnector {
+    connections: Sender<DuplexStream>,
+}
+
+impl Display for MemoryConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "memory")
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for MemoryConnector {
+    async fn connect(&self) -> std::io::Result<Box<dyn Transport>> {
+        let (client, server) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+
+        self.connections.send(server).await.map_err(|_| {
+            std::io::Error::new(ErrorKind::ConnectionRefused, "The listener was dropped")
+        })?;
+
+        Ok(Box::new(client))
+    }
+}
+
+pub struct MemoryListener {
+    connections: Receiver<DuplexStream>,
+}
+
+#[async_trait::async_trait]
+impl Listener for MemoryListener {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)> {
+        let socket = self.connections.recv().await.ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotConnected, "All connectors were dropped")
+        })?;
+
+        Ok((Box::new(socket), Peer::new("memory")))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    pub(crate) async fn assert_connected(
+        connector: &dyn Connector,
+        listener: &mut dyn Listener,
+    ) -> Peer {
+        let (mut client, (mut server, peer)) =
+            tokio::try_join!(connector.connect(), listener.accept()).unwrap();
+
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).await.unwrap();
+
+        assert_eq!(b"ping", &buffer);
+
+        peer
+    }
+
+    #[tokio::test]
+    async fn memory_transport_connects() {
+        let (connector, mut listener) = memory();
+
+        assert_connected(&connector, &mut listener).await;
+        assert_connected(&connector.clone(), &mut listener).await;
+    }
+
+    #[tokio::test]
+    async fn listeners_accept_from_all_of_their_listeners() {
+        let (first, first_listener) = memory();
+        let (second, second_listener) = memory();
+        let mut listeners = Listeners::new().with(first_listener).with(second_listener);
+
+        assert_connected(&second, &mut listeners).await;
+        assert_connected(&first, &mut listeners).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_transport_connects() {
+        let path = std::env::temp_dir().join(format!("rpc-support-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut listener = UnixListener::bind(&path).unwrap();
+
+        assert_connected(&UnixConnector::new(&path), &mut listener).await;
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use crate::transport::{Connector, Listener, Peer, Transport};
+use futures::stream::FuturesUnordered;
+use futures::{Sink, Stream, StreamExt};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::warn;
+
+/// Clients that don't finish the handshake in time are disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Carries a connection over WebSocket messages, so browsers can connect. Every flush sends what
+/// was written since the previous one as a single message, which makes every frame of the
+/// protocol one message. Incoming messages are read as one byte stream.
+///
+/// Servers answer in the message type of the first message of the client, so browser clients
+/// that send JSON lines as text messages receive text messages.
+struct WebSocketTransport {
+    socket: WebSocketStream<Box<dyn Transport>>,
+    received: Vec<u8>,
+    read: usize,
+    written: Vec<u8>,
+    text: Option<bool>,
+}
+
+impl WebSocketTransport {
+    const fn new(socket: WebSocketStream<Box<dyn Transport>>, text: Option<bool>) -> Self {
+        Self {
+            socket,
+            received: Vec::new(),
+            read: 0,
+            written: Vec::new(),
+            text,
+        }
+    }
+
+    fn message(&mut self) -> Message {
+        let written = std::mem::take(&mut self.written);
+
+        if self.text == Some(true) {
+            match String::from_utf8(written) {
+                Ok(text) => Message::Text(text),
+                Err(e) => Message::Binary(e.into_bytes()),
+            }
+        } else {
+            Message::Binary(written)
+        }
+    }
+}
+
+fn io_error(error: tungstenite::Error) -> std::io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            std::io::Error::from(ErrorKind::BrokenPipe)
+        }
+        other => std::io::Error::new(ErrorKind::InvalidData, other),
+    }
+}
+
+impl AsyncRead for WebSocketTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.read == self.received.len() {
+            let message = match ready!(Pin::new(&mut self.socket).poll_next(cx)) {
+                Some(message) => message.map_err(io_error)?,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            let (received, text) = match message {
+                Message::Text(text) => (text.into_bytes(), true),
+                Message::Binary(data) => (data, false),
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                // Pings are answered by the WebSocket implementation
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            };
+
+            self.text.get_or_insert(text);
+            self.received = received;
+            self.read = 0;
+        }
+
+        let length = buf.remaining().min(self.received.len() - self.read);
+        let start = self.read;
+        buf.put_slice(&self.received[start..start + length]);
+        self.read += length;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WebSocketTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.written.extend_from_slice(buf);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.written.is_empty() {
+            ready!(Pin::new(&mut self.socket).poll_ready(cx)).map_err(io_error)?;
+
+            let message = self.message();
+            Pin::new(&mut self.socket)
+                .start_send(message)
+                .map_err(io_error)?;
+        }
+
+        Pin::new(&mut self.socket).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        Pin::new(&mut self.socket).poll_close(cx).map_err(io_error)
+    }
+}
+
+/// Wraps the connections of another connector, e.g. a [`crate::transport::TcpConnector`], in
+/// WebSocket. Sends binary messages.
+pub struct WebSocketConnector {
+    inner: Box<dyn Connector>,
+    url: String,
+}
+
+impl WebSocketConnector {
+    /// `url` is requested in the handshake, e.g. `ws://events:8654/`
+    #[must_use]
+    pub fn new(inner: impl Connector + 'static, url: &str) -> Self {
+        Self {
+            inner: Box::new(inner),
+            url: url.to_string(),
+        }
+    }
+}
+
+impl Display for WebSocketConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.url, self.inner)
+    }
+}
+
+#[async_trait::async_trait]
+impl Connector for WebSocketConnector {
+    async fn connect(&self) -> std::io::Result<Box<dyn Transport>> {
+        let socket = self.inner.connect().await?;
+        let (socket, _) = tokio_tungstenite::client_async(self.url.as_str(), socket)
+            .await
+            .map_err(io_error)?;
+
+        Ok(Box::new(WebSocketTransport::new(socket, Some(false))))
+    }
+}
+
+type Handshake = Pin<Box<dyn Future<Output = std::io::Result<(Box<dyn Transport>, Peer)>> + Send>>;
+
+/// Wraps the connections of another listener in WebSocket, e.g. to serve browsers. Accepts
+/// every path. Clients send the same frames as over any other transport, one per message.
+pub struct WebSocketListener {
+    inner: Box<dyn Listener>,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl WebSocketListener {
+    #[must_use]
+    pub fn new(inner: impl Listener + 'static) -> Self {
+        Self {
+            inner: Box::new(inner),
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+
+    fn handshake(socket: Box<dyn Transport>, peer: Peer) -> Handshake {
+        Box::pin(async move {
+            let socket =
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_async(socket))
+                    .await
+                    .map_err(|_| {
+                        std::io::Error::new(ErrorKind::TimedOut, "WebSocket handshake timed out")
+                    })?
+                    .map_err(io_error)?;
+
+            let transport = WebSocketTransport::new(socket, None);
+
+            Ok((Box::new(transport) as Box<dyn Transport>, peer))
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Listener for WebSocketListener {
+    async fn accept(&mut self) -> std::io::Result<(Box<dyn Transport>, Peer)> {
+        // Handshakes run concurrently so a slow client can't hold up everyone else
+        loop {
+            tokio::select! {
+                accepted = self.inner.accept() => {
+                    let (socket, peer) = accepted?;
+                    self.handshakes.push(Self::handshake(socket, peer));
+                }
+                Some(result) = self.handshakes.next() => match result {
+                    Ok(connection) => return Ok(connection),
+                    Err(e) => warn!("WebSocket handshake failed: {}", e),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::test::assert_connected;
+    use futures::SinkExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn websocket_transport_connects() {
+        let (connector, listener) = crate::transport::memory();
+        let mut listener = WebSocketListener::new(listener);
+
+        assert_connected(
+            &WebSocketConnector::new(connector, "ws://localhost/"),
+            &mut listener,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn every_flush_is_a_message_of_the_type_the_client_sent() {
+        let (connector, listener) = crate::transport::memory();
+        let mut listener = WebSocketListener::new(listener);
+
+        let (client, (mut server, _)) = tokio::try_join!(
+            async {
+                let socket = connector.connect().await?;
+                let (client, _) = tokio_tungstenite::client_async("ws://localhost/", socket)
+                    .await
+                    .map_err(io_error)?;
+
+                Ok(client)
+            },
+            listener.accept()
+        )
+        .unwrap();
+        let mut client = client;
+
+        client.send(Message::Text("{}\n".into())).await.unwrap();
+        let mut received = [0; 3];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(b"{}\n", &received);
+
+        server.write_all(b"{\"a\":").await.unwrap();
+        server.write_all(b"1}\n").await.unwrap();
+        server.flush().await.unwrap();
+
+        assert_eq!(
+            Message::Text("{\"a\":1}\n".into()),
+            client.next().await.unwrap().unwrap()
+        );
+    }
+}
@@ -1,21 +1,27 @@
 use crate::file_status_store::FileStatusStore;
 use crate::mount::{Mount, PathInside};
-use crate::{create_event_metadata, HandleEventsError};
+use crate::HandleEventsError;
 use events::{Event, FileOnMountPath};
 use notify::event::{ModifyKind, RenameMode};
 use notify::{Event as DebouncedEvent, EventKind}; // fixme rename to NotifyEvent?
+use rpc_support::retry::RetryPolicy;
+use rpc_support::rpc_error::RpcError;
+use rpc_support::trace_context::{self, TraceContext};
 use std::path::Path;
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use time::OffsetDateTime;
 use tokio::sync::Mutex;
+use tracing::Instrument;
 use uuid::Uuid;
 
-pub struct FilesystemEventHandler<'a, T: events::Rpc + Sync + Send> {
+pub struct FilesystemEventHandler<'a, T: events::RpcClient + Sync + Send> {
     event_sender: Arc<Mutex<T>>,
     file_status_store: Arc<Mutex<dyn FileStatusStore + Send>>,
     mounts: &'a [Mount],
+    resend_backoff: Duration,
+    max_resend_backoff: Duration,
 }
 
 impl From<PathInside<'_>> for FileOnMountPath {
@@ -27,48 +33,95 @@ impl From<PathInside<'_>> for FileOnMountPath {
     }
 }
 
-impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
+impl<'a, T: events::RpcClient + Sync + Send> FilesystemEventHandler<'a, T> {
     pub fn new(
         event_sender: Arc<Mutex<T>>,
         file_status_store: Arc<Mutex<dyn FileStatusStore + Send>>,
         mounts: &'a [Mount],
     ) -> Self {
+        let retry_policy = RetryPolicy::default();
+
         Self {
             event_sender,
             file_status_store,
             mounts,
+            resend_backoff: retry_policy.backoff,
+            max_resend_backoff: retry_policy.max_backoff,
         }
     }
 
+    /// Waits `backoff` before sending an event again while the events service is unreachable,
+    /// and twice as long before every further attempt, at most `max_backoff`. Events are sent
+    /// until they arrive, the store is only updated then.
+    #[must_use]
+    pub fn with_resend_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.resend_backoff = backoff;
+        self.max_resend_backoff = max_backoff;
+        self
+    }
+
     pub async fn handle_events(
         &self,
         receiver: Receiver<notify::Result<DebouncedEvent>>,
     ) -> Result<(), HandleEventsError> {
         info!("Waiting for filesystem events");
         for item in receiver {
+            let item = item?;
+            // Every attempt sends the event with the same id, the events service handles it once
+            let id = Uuid::new_v4();
+            // Every change starts a trace, it continues in the events service and its subscribers
+            let trace = TraceContext::new_root();
+            let span = info_span!(
+                "filesystem_event",
+                trace_id = %trace.trace_id,
+                span_id = %trace.span_id,
+            );
+
             // TODO skip errors, but handle the rest
-            self.handle_event(item?).await?;
+            let mut backoff = self.resend_backoff;
+            loop {
+                match trace_context::scope(trace.clone(), self.handle_event(item.clone(), id))
+                    .instrument(span.clone())
+                    .await
+                {
+                    // The client reconnects in the background. The store is only updated once
+                    // the event was sent, so the event can be handled again until then.
+                    Err(HandleEventsError::Rpc(RpcError::ConnectionLost)) => {
+                        warn!(
+                            "Lost the connection to the events service, sending the event again in {:?}",
+                            backoff
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(self.max_resend_backoff);
+                    }
+                    result => {
+                        result?;
+                        break;
+                    }
+                }
+            }
         }
 
         Ok(())
     }
 
-    async fn handle_event(&self, item: DebouncedEvent) -> Result<(), HandleEventsError> {
+    /// Sends the event with `id` and only then updates the file status store
+    async fn handle_event(&self, item: DebouncedEvent, id: Uuid) -> Result<(), HandleEventsError> {
         info!("Handling filesystem event: {:?}", item);
         match item.kind {
             EventKind::Any | EventKind::Other => {
                 if let Some(path) = item.paths.first() {
-                    self.handle_file_modified(path).await?;
+                    self.handle_file_modified(path, id).await?;
                 }
             }
             EventKind::Create(_) => {
                 if let Some(path) = item.paths.first() {
-                    self.handle_file_created(path).await?;
+                    self.handle_file_created(path, id).await?;
                 }
             }
             EventKind::Remove(_) => {
                 if let Some(path) = item.paths.first() {
-                    self.handle_file_deleted(path).await?;
+                    self.handle_file_deleted(path, id).await?;
                 }
             }
             EventKind::Modify(kind) => {
@@ -76,13 +129,13 @@ impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
                     let path_from = item.paths.get(0).ok_or(HandleEventsError::MissingPath)?;
                     let path_to = item.paths.get(1).ok_or(HandleEventsError::MissingPath)?;
 
-                    self.handle_file_renamed(path_from, path_to).await?;
+                    self.handle_file_renamed(path_from, path_to, id).await?;
                 } else if kind == ModifyKind::Name(RenameMode::From) {
                     if let Some(path) = item.paths.first() {
-                        self.handle_file_deleted(path).await?;
+                        self.handle_file_deleted(path, id).await?;
                     }
                 } else if let Some(path) = item.paths.first() {
-                    self.handle_file_modified(path).await?;
+                    self.handle_file_modified(path, id).await?;
                 }
             }
             EventKind::Access(_) => {}
@@ -91,7 +144,12 @@ impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
         Ok(())
     }
 
-    async fn handle_file_renamed(&self, x: &Path, y: &Path) -> Result<(), HandleEventsError> {
+    async fn handle_file_renamed(
+        &self,
+        x: &Path,
+        y: &Path,
+        id: Uuid,
+    ) -> Result<(), HandleEventsError> {
         let (_, is_dir) = Self::modified_date(y)?;
 
         if is_dir {
@@ -101,60 +159,54 @@ impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
         let path_relative_from = PathInside::from_mount_list(self.mounts, x)?;
         let path_relative_to = PathInside::from_mount_list(self.mounts, y)?;
 
-        self.file_status_store
+        self.event_sender
             .lock()
             .await
-            .rename(&path_relative_from, &path_relative_to)
+            .send_event(Event {
+                id,
+                created_time: std::time::SystemTime::now(),
+                data: events::EventKind::FileMoved {
+                    from: path_relative_from.clone().into(),
+                    to: path_relative_to.clone().into(),
+                },
+            })
             .await?;
 
-        self.event_sender
+        self.file_status_store
             .lock()
             .await
-            .send_event(
-                Event {
-                    id: Uuid::new_v4(),
-                    created_time: std::time::SystemTime::now(),
-                    data: events::EventKind::FileMoved {
-                        from: path_relative_from.into(),
-                        to: path_relative_to.into(),
-                    },
-                },
-                create_event_metadata(),
-            )
+            .rename(&path_relative_from, &path_relative_to)
             .await?;
 
         Ok(())
     }
 
     // TODO only send events in case we had the file in the database (i.e. an event about creation was sent before)
-    async fn handle_file_deleted(&self, x: &Path) -> Result<(), HandleEventsError> {
+    async fn handle_file_deleted(&self, x: &Path, id: Uuid) -> Result<(), HandleEventsError> {
         let mount_relative_path = PathInside::from_mount_list(self.mounts, x)?;
 
-        self.file_status_store
+        self.event_sender
             .lock()
             .await
-            .delete(&mount_relative_path)
+            .send_event(Event {
+                created_time: SystemTime::now(),
+                id,
+                data: events::EventKind::FileDeleted {
+                    path: mount_relative_path.clone().into(),
+                },
+            })
             .await?;
 
-        self.event_sender
+        self.file_status_store
             .lock()
             .await
-            .send_event(
-                Event {
-                    created_time: SystemTime::now(),
-                    id: Uuid::new_v4(),
-                    data: events::EventKind::FileDeleted {
-                        path: mount_relative_path.into(),
-                    },
-                },
-                create_event_metadata(),
-            )
+            .delete(&mount_relative_path)
             .await?;
 
         Ok(())
     }
 
-    async fn handle_file_created(&self, x: &Path) -> Result<(), HandleEventsError> {
+    async fn handle_file_created(&self, x: &Path, id: Uuid) -> Result<(), HandleEventsError> {
         let mount_relative_path = PathInside::from_mount_list(self.mounts, x)?;
 
         let (modified_date, is_dir) = Self::modified_date(x)?;
@@ -163,31 +215,28 @@ impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
             return Ok(());
         }
 
-        self.file_status_store
+        self.event_sender
             .lock()
             .await
-            .sync(&mount_relative_path, modified_date)
+            .send_event(Event {
+                created_time: SystemTime::now(),
+                id,
+                data: events::EventKind::FileCreated {
+                    path: mount_relative_path.clone().into(),
+                },
+            })
             .await?;
 
-        self.event_sender
+        self.file_status_store
             .lock()
             .await
-            .send_event(
-                Event {
-                    created_time: SystemTime::now(),
-                    id: Uuid::new_v4(),
-                    data: events::EventKind::FileCreated {
-                        path: mount_relative_path.into(),
-                    },
-                },
-                create_event_metadata(),
-            )
+            .sync(&mount_relative_path, modified_date)
             .await?;
 
         Ok(())
     }
 
-    async fn handle_file_modified(&self, x: &Path) -> Result<(), HandleEventsError> {
+    async fn handle_file_modified(&self, x: &Path, id: Uuid) -> Result<(), HandleEventsError> {
         let mount_relative_path = PathInside::from_mount_list(self.mounts, x)?;
         let (modified_date, is_dir) = Self::modified_date(x)?;
 
@@ -195,25 +244,22 @@ impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
             return Ok(());
         }
 
-        self.file_status_store
+        self.event_sender
             .lock()
             .await
-            .sync(&mount_relative_path, modified_date)
+            .send_event(Event {
+                created_time: SystemTime::now(),
+                id,
+                data: events::EventKind::FileChanged {
+                    path: mount_relative_path.clone().into(),
+                },
+            })
             .await?;
 
-        self.event_sender
+        self.file_status_store
             .lock()
             .await
-            .send_event(
-                Event {
-                    created_time: SystemTime::now(),
-                    id: Uuid::new_v4(),
-                    data: events::EventKind::FileChanged {
-                        path: mount_relative_path.into(),
-                    },
-                },
-                create_event_metadata(),
-            )
+            .sync(&mount_relative_path, modified_date)
             .await?;
 
         Ok(())
@@ -232,35 +278,44 @@ impl<'a, T: events::Rpc + Sync + Send> FilesystemEventHandler<'a, T> {
 mod tests {
     use super::*;
     use crate::file_status_store::FileStatusSyncResult;
-    use events::{Metadata, SubscribeRequest};
+    use events::SubscribeRequest;
     use futures_lite::Stream;
     use notify::event::{CreateKind, DataChange, RemoveKind};
-    use rpc_support::rpc_error::RpcError;
     use serde_json::{json, to_value, Value};
     use std::path::PathBuf;
     use std::pin::Pin;
     use tempfile::TempDir;
 
+    #[derive(Default)]
     struct MockRpcClient {
         events: Vec<Value>,
+        lost_events: Vec<Value>,
+        connection_losses: usize,
     }
 
     #[async_trait]
-    impl events::Rpc for MockRpcClient {
-        async fn send_event(
-            &mut self,
-            request: Event,
-            _metadata: Metadata,
-        ) -> Result<(), RpcError> {
+    impl events::RpcClient for MockRpcClient {
+        async fn send_event(&mut self, request: Event) -> Result<(), RpcError> {
+            if self.connection_losses > 0 {
+                self.connection_losses -= 1;
+                self.lost_events.push(to_value(request).unwrap());
+                return Err(RpcError::ConnectionLost);
+            }
             self.events.push(to_value(request).unwrap());
 
             Ok(())
         }
 
+        async fn send_events(
+            &mut self,
+            _request: Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>,
+        ) -> Result<(), RpcError> {
+            todo!()
+        }
+
         async fn subscribe(
             &mut self,
             _request: SubscribeRequest,
-            _metadata: Metadata,
         ) -> Result<Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>, RpcError>
         {
             todo!()
@@ -302,25 +357,37 @@ mod tests {
         event: DebouncedEvent,
         sync_result: FileStatusSyncResult,
     ) -> Vec<Value> {
+        setup_with_client(temp, event, sync_result, MockRpcClient::default())
+            .await
+            .events
+    }
+
+    async fn setup_with_client(
+        temp: &TempDir,
+        event: DebouncedEvent,
+        sync_result: FileStatusSyncResult,
+        client: MockRpcClient,
+    ) -> MockRpcClient {
         let temp = temp.path();
 
         std::fs::create_dir(temp.join("b/")).unwrap();
         std::fs::write(temp.join("b/1"), "aaa").unwrap();
 
         let mounts = vec![Mount::new("mount_a".to_string(), PathBuf::from(temp))];
-        let event_sender = Arc::new(Mutex::new(MockRpcClient { events: vec![] }));
+        let event_sender = Arc::new(Mutex::new(client));
         let handler = FilesystemEventHandler::new(
             event_sender.clone(),
             Arc::new(Mutex::new(MockFileStatusStore { sync_result })),
             &mounts,
-        );
+        )
+        .with_resend_backoff(Duration::from_millis(1), Duration::from_millis(1));
         let (tx, rx) = std::sync::mpsc::channel();
 
         tx.send(Ok(event)).unwrap();
         drop(tx);
         handler.handle_events(rx).await.unwrap();
-        let events = event_sender.lock().await.events.clone();
-        events
+        drop(handler);
+        Arc::try_unwrap(event_sender).ok().unwrap().into_inner()
     }
 
     #[tokio::test]
@@ -425,4 +492,36 @@ mod tests {
             events[0].get("data").unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn events_are_sent_again_after_a_lost_connection() {
+        let temp = TempDir::new().unwrap();
+        let client = setup_with_client(
+            &temp,
+            DebouncedEvent::new(EventKind::Remove(RemoveKind::Any))
+                .add_path(temp.path().join("b/1")),
+            FileStatusSyncResult::Modified,
+            MockRpcClient {
+                connection_losses: 3,
+                ..MockRpcClient::default()
+            },
+        )
+        .await;
+        assert_eq!(3, client.lost_events.len());
+        assert_eq!(1, client.events.len());
+        for lost in &client.lost_events {
+            assert_eq!(lost.get("id"), client.events[0].get("id"));
+        }
+        assert_eq!(
+            &json!({
+                "FileDeleted": {
+                    "path": {
+                        "mount_id": "mount_a",
+                        "path": "b/1",
+                    },
+                }
+            }),
+            client.events[0].get("data").unwrap()
+        );
+    }
 }
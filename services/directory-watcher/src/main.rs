@@ -4,11 +4,12 @@ use crate::file_status_store::{Error, Postgres};
 use crate::filesystem_events::FilesystemEventHandler;
 use crate::mount::Mount;
 use crate::scan::Scanner;
-use events::Metadata;
 use native_tls::TlsConnector;
 use notify::{PollWatcher, RecursiveMode, Watcher};
 use platform::secrets::SecretProvider;
 use postgres_native_tls::MakeTlsConnector;
+use rpc_support::pool::Pool;
+use rpc_support::retry::RetryPolicy;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
@@ -27,10 +28,23 @@ extern crate tracing;
 #[macro_use]
 extern crate async_trait;
 
-fn create_event_metadata() -> Metadata {
-    Metadata {
+async fn connect_to_events(
+    retry_policy: RetryPolicy,
+) -> Result<events::Client, rpc_support::rpc_error::RpcError> {
+    // Shared by the scanner and the watcher, calls are spread over every replica of the service.
+    // The headless service resolves to all of them, the regular one only to its virtual IP.
+    let mut client = events::Client::with_pool(
+        "svc-events-replicas:7654",
+        Pool::default().with_connections(2),
+    )
+    .await?
+    .with_metadata(events::Metadata {
         source: "directory-watcher".to_string(),
-    }
+    });
+    // Events are sent with their id, so the server handles retries only once
+    client.add_interceptor(retry_policy);
+
+    Ok(client)
 }
 
 #[tokio::main]
@@ -39,13 +53,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _guard = tracing::subscriber::set_default(subscriber);
 
     let secret_provider = SecretProvider::new("/etc/svc-events/secrets/");
-    let es_watcher = events::Client::new("svc-events:7654").await?;
-    let es_scanner = events::Client::new("svc-events:7654").await?;
+    let retry_policy = RetryPolicy::default();
+    let events = Arc::new(Mutex::new(connect_to_events(retry_policy.clone()).await?));
     let configuration = platform::configuration::Configuration::new()?;
     let pg_client = Arc::new(Mutex::new(connect_to_postgres(&secret_provider).await?));
     let directories_from_env = configuration.get_string("$.mounts")?;
     let file_status_store = Arc::new(Mutex::new(Postgres::new(pg_client.clone())));
-    let mut scanner = Scanner::new(Arc::new(Mutex::new(es_scanner)), file_status_store.clone());
+    let mut scanner = Scanner::new(events.clone(), file_status_store.clone());
 
     info!("Initialization completed");
 
@@ -55,11 +69,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // The PollWatcher is used, because the inotify watcher does not work with NFS mounts.
     // todo asses performance impact, find a better solution?
     let mut watcher = PollWatcher::new(sender, notify::Config::default())?;
-    let filesystem_event_handler = FilesystemEventHandler::new(
-        Arc::new(Mutex::new(es_watcher)),
-        file_status_store.clone(),
-        &mounts,
-    );
+    let filesystem_event_handler =
+        FilesystemEventHandler::new(events, file_status_store.clone(), &mounts)
+            // Events the client gave up on are sent again, with the backoff of its retries
+            .with_resend_backoff(retry_policy.backoff, retry_policy.max_backoff);
 
     for mount in &mounts {
         watcher.watch(mount.path(), RecursiveMode::Recursive)?;
@@ -1,4 +1,3 @@
-use crate::create_event_metadata;
 use crate::file_status_store::{FileStatusStore, FileStatusSyncResult};
 use crate::mount::{Mount, PathInside};
 use async_walkdir::{DirEntry, WalkDir};
@@ -24,12 +23,12 @@ pub enum Error {
     Rpc(#[from] rpc_support::rpc_error::RpcError),
 }
 
-pub struct Scanner<T: events::Rpc + Sync + Send> {
+pub struct Scanner<T: events::RpcClient + Sync + Send> {
     event_sender: Arc<Mutex<T>>,
     file_status_store: Arc<Mutex<dyn FileStatusStore + Send>>,
 }
 
-impl<T: events::Rpc + Sync + Send> Scanner<T> {
+impl<T: events::RpcClient + Sync + Send> Scanner<T> {
     pub fn new(
         event_sender: Arc<Mutex<T>>,
         file_status_store: Arc<Mutex<dyn FileStatusStore + Send>>,
@@ -95,32 +94,26 @@ impl<T: events::Rpc + Sync + Send> Scanner<T> {
                 self.event_sender
                     .lock()
                     .await
-                    .send_event(
-                        Event {
-                            id: Uuid::new_v4(),
-                            created_time: std::time::SystemTime::now(),
-                            data: EventKind::FileCreated {
-                                path: mount_relative_path.into(),
-                            },
+                    .send_event(Event {
+                        id: Uuid::new_v4(),
+                        created_time: std::time::SystemTime::now(),
+                        data: EventKind::FileCreated {
+                            path: mount_relative_path.into(),
                         },
-                        create_event_metadata(),
-                    )
+                    })
                     .await?;
             }
             FileStatusSyncResult::Modified => {
                 self.event_sender
                     .lock()
                     .await
-                    .send_event(
-                        Event {
-                            id: Uuid::new_v4(),
-                            created_time: std::time::SystemTime::now(),
-                            data: EventKind::FileChanged {
-                                path: mount_relative_path.into(),
-                            },
+                    .send_event(Event {
+                        id: Uuid::new_v4(),
+                        created_time: std::time::SystemTime::now(),
+                        data: EventKind::FileChanged {
+                            path: mount_relative_path.into(),
                         },
-                        create_event_metadata(),
-                    )
+                    })
                     .await?;
             }
             FileStatusSyncResult::NotModified => {}
@@ -133,7 +126,7 @@ impl<T: events::Rpc + Sync + Send> Scanner<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use events::{Metadata, SubscribeRequest};
+    use events::SubscribeRequest;
     use futures_lite::Stream;
     use rpc_support::rpc_error::RpcError;
     use serde_json::{to_value, Value};
@@ -145,21 +138,23 @@ mod tests {
     }
 
     #[async_trait]
-    impl events::Rpc for MockRpcClient {
-        async fn send_event(
-            &mut self,
-            request: Event,
-            _metadata: Metadata,
-        ) -> Result<(), RpcError> {
+    impl events::RpcClient for MockRpcClient {
+        async fn send_event(&mut self, request: Event) -> Result<(), RpcError> {
             self.events.push(to_value(request).unwrap());
 
             Ok(())
         }
 
+        async fn send_events(
+            &mut self,
+            _request: Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>,
+        ) -> Result<(), RpcError> {
+            todo!()
+        }
+
         async fn subscribe(
             &mut self,
             _request: SubscribeRequest,
-            _metadata: Metadata,
         ) -> Result<Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>, RpcError>
         {
             todo!()
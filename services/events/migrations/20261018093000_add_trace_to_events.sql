@@ -0,0 +1,7 @@
+-- migrate:up
+
+ALTER TABLE events ADD COLUMN trace JSONB;
+
+-- migrate:down
+
+ALTER TABLE events DROP COLUMN trace;
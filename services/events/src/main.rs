@@ -3,7 +3,7 @@
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
-use tracing::{debug, info};
+use tracing::{debug, info, info_span, Instrument};
 
 use tokio_postgres::Client;
 
@@ -12,7 +12,11 @@ use futures_lite::stream::StreamExt;
 use futures_lite::Stream;
 use platform::async_infra::run_with_error_handling;
 use postgres_native_tls::MakeTlsConnector;
+use rpc_support::interceptor::LoggingInterceptor;
 use rpc_support::rpc_error::RpcError;
+use rpc_support::trace_context::TraceContext;
+use rpc_support::transport::Listeners;
+use rpc_support::websocket::WebSocketListener;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::mpsc::Sender;
@@ -37,10 +41,12 @@ struct RpcServer {
 struct SavedEvent {
     event: Event,
     timestamp: SystemTime,
+    /// Context of the call that sent the event
+    trace: Option<TraceContext>,
 }
 
 fn rpc_error_map(e: impl Error) -> RpcError {
-    RpcError::Custom(e.to_string())
+    RpcError::internal(e.to_string())
 }
 
 struct SubscriptionHandler {
@@ -68,22 +74,33 @@ impl SubscriptionHandler {
                 // FIXME: don't sleep here, wait for events in a different way instead!
                 let last_pushed_event_timestamp = *last_pushed_event_timestamp.lock().await;
                 for mut subscription in subscriptions.iter_mut() {
-                    if subscription.value().cursor.map_or(true, |cursor| {
-                        last_pushed_event_timestamp.map_or(true, |x| x > cursor)
-                    }) {
+                    let has_new_events =
+                        match (subscription.value().cursor, last_pushed_event_timestamp) {
+                            (Some(cursor), Some(last_pushed)) => last_pushed > cursor,
+                            _ => true,
+                        };
+
+                    if has_new_events {
                         let events =
                             Self::read_events(postgres.clone(), subscription.value().cursor)
                                 .await?;
 
-                        for event in events {
-                            info!(
-                                "Sending event {:?} to subscriber {}",
-                                event,
-                                subscription.key()
-                            );
-
-                            subscription.value().tx.send(event.event).await?;
-                            subscription.value_mut().cursor = Some(event.timestamp);
+                        for SavedEvent {
+                            event,
+                            timestamp,
+                            trace,
+                        } in events
+                        {
+                            let span = publish_span(subscription.key(), trace.as_ref());
+                            let tx = &subscription.value().tx;
+
+                            async {
+                                info!("Sending event {:?} to subscriber", event);
+                                tx.send(event).await
+                            }
+                            .instrument(span)
+                            .await?;
+                            subscription.value_mut().cursor = Some(timestamp);
                         }
                     }
                 }
@@ -97,7 +114,7 @@ impl SubscriptionHandler {
         postgres: Arc<Mutex<Client>>,
         since: Option<SystemTime>,
     ) -> Result<Vec<SavedEvent>, RpcError> {
-        let mut query = "SELECT data, created_timestamp FROM events".to_string();
+        let mut query = "SELECT data, created_timestamp, trace FROM events".to_string();
 
         let rows = if let Some(cursor) = since {
             query.push_str(" WHERE created_timestamp > $1");
@@ -123,10 +140,13 @@ impl SubscriptionHandler {
         let mut events = Vec::new();
         for row in rows {
             let data: serde_json::Value = row.get(0);
+            let trace: Option<serde_json::Value> = row.get(2);
 
             events.push(SavedEvent {
                 event: serde_json::from_value(data).map_err(rpc_error_map)?,
                 timestamp: row.get(1),
+                // Events saved before traces were recorded have none
+                trace: trace.and_then(|trace| serde_json::from_value(trace).ok()),
             });
         }
 
@@ -134,6 +154,19 @@ impl SubscriptionHandler {
     }
 }
 
+/// Span of sending an event to a subscriber, continues the trace of the call that sent the event
+fn publish_span(subscriber: &Uuid, trace: Option<&TraceContext>) -> tracing::Span {
+    let trace = trace.map_or_else(TraceContext::new_root, TraceContext::child);
+
+    info_span!(
+        "publish",
+        %subscriber,
+        trace_id = %trace.trace_id,
+        span_id = %trace.span_id,
+        parent_span_id = trace.parent_span_id.as_deref().unwrap_or_default(),
+    )
+}
+
 impl RpcServer {
     pub fn new(postgres: Arc<Mutex<tokio_postgres::Client>>) -> Self {
         let (subscription_handler, task) = SubscriptionHandler::new(postgres.clone());
@@ -145,15 +178,22 @@ impl RpcServer {
         }
     }
 
-    async fn save_event(&mut self, name: &str, message: Event) -> Result<(), RpcError> {
+    async fn save_event(&self, name: &str, message: Event) -> Result<(), RpcError> {
         let serde_value = serde_json::to_value(&message).map_err(rpc_error_map)?;
+        let trace = TraceContext::current()
+            .map(serde_json::to_value)
+            .transpose()
+            .map_err(rpc_error_map)?;
 
         self.postgres
             .lock()
             .await
             .execute(
-                "INSERT INTO events(id, created_timestamp, type, data) VALUES($1,$2,$3,$4)",
-                &[&message.id, &message.created_time, &name, &serde_value],
+                // Retries may reach another replica or a restarted server, which did not see the
+                // first attempt, the event is only saved once
+                "INSERT INTO events(id, created_timestamp, type, data, trace) VALUES($1,$2,$3,$4,$5) \
+                 ON CONFLICT (id) DO NOTHING",
+                &[&message.id, &message.created_time, &name, &serde_value, &trace],
             )
             .await
             .map_err(rpc_error_map)?;
@@ -170,7 +210,7 @@ impl RpcServer {
 #[async_trait]
 impl Rpc for RpcServer {
     async fn subscribe(
-        &mut self,
+        &self,
         request: SubscribeRequest,
         _metadata: Metadata,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>, RpcError> {
@@ -190,7 +230,19 @@ impl Rpc for RpcServer {
         Ok(Box::pin(stream))
     }
 
-    async fn send_event(&mut self, request: Event, _metadata: Metadata) -> Result<(), RpcError> {
+    async fn send_events(
+        &self,
+        mut request: Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>,
+        metadata: Metadata,
+    ) -> Result<(), RpcError> {
+        while let Some(event) = request.next().await {
+            self.send_event(event?, metadata.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_event(&self, request: Event, _metadata: Metadata) -> Result<(), RpcError> {
         let created_time = request.created_time;
         self.save_event(
             match request.data {
@@ -203,11 +255,14 @@ impl Rpc for RpcServer {
         )
         .await?;
 
-        *self
+        // Calls are handled concurrently, an older event may be saved after a newer one
+        let mut last_pushed = self
             .subscription_handler
             .last_pushed_event_timestamp
             .lock()
-            .await = Some(created_time);
+            .await;
+        *last_pushed = Some(last_pushed.map_or(created_time, |last| last.max(created_time)));
+        drop(last_pushed);
 
         Ok(())
     }
@@ -242,11 +297,98 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         run_with_error_handling(connection).await;
     });
 
-    let rpc_server = Arc::new(Mutex::new(RpcServer::new(Arc::new(Mutex::new(client)))));
+    let rpc_server = Arc::new(RpcServer::new(Arc::new(Mutex::new(client))));
 
     // todo make the bind addr/port configurable
-    let server = Server::new("0.0.0.0:7654", rpc_server).await?;
+    // Browsers subscribe over WebSocket, everyone else connects over TCP
+    let listener = Listeners::new()
+        .with(tokio::net::TcpListener::bind("0.0.0.0:7654").await?)
+        .with(WebSocketListener::new(
+            tokio::net::TcpListener::bind("0.0.0.0:8654").await?,
+        ));
+    let server = Server::with_listener(listener, rpc_server)
+        .with_interceptor(LoggingInterceptor)
+        .with_metrics_endpoint("0.0.0.0:9654");
+    server.shutdown_handle().trigger_on_signal()?;
     server.run().await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use events::{FileOnMountPath, RpcClient};
+
+    /// Connection string of the database the tests create their tables in
+    const DATABASE_VARIABLE: &str = "EVENTS_TEST_DATABASE";
+
+    async fn connect() -> Arc<Mutex<Client>> {
+        let config = std::env::var(DATABASE_VARIABLE).unwrap();
+        let (client, connection) = tokio_postgres::connect(&config, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        // Temporary tables shadow the real ones and are dropped with the connection
+        client
+            .batch_execute(
+                "CREATE TEMPORARY TABLE events (
+                    id UUID PRIMARY KEY,
+                    created_timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
+                    type TEXT NOT NULL,
+                    data JSONB NOT NULL,
+                    trace JSONB
+                )",
+            )
+            .await
+            .unwrap();
+
+        Arc::new(Mutex::new(client))
+    }
+
+    async fn start_server(postgres: Arc<Mutex<Client>>) -> events::Client {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(RpcServer::new(postgres)));
+        tokio::spawn(server.run());
+
+        events::Client::with_connector(connector)
+            .await
+            .unwrap()
+            .with_metadata(Metadata {
+                source: "test".to_string(),
+            })
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database, set EVENTS_TEST_DATABASE to its connection string"]
+    async fn retries_on_a_fresh_server_save_the_event_once() {
+        let postgres = connect().await;
+        let event = Event {
+            id: Uuid::new_v4(),
+            created_time: SystemTime::now(),
+            data: EventKind::FileCreated {
+                path: FileOnMountPath {
+                    path: "a.txt".to_string(),
+                    mount_id: "test".to_string(),
+                },
+            },
+        };
+
+        let mut client = start_server(postgres.clone()).await;
+        client.send_event(event.clone()).await.unwrap();
+
+        // The retry reaches a server that did not handle the first attempt
+        let mut retrying_client = start_server(postgres.clone()).await;
+        retrying_client.send_event(event).await.unwrap();
+
+        let count: i64 = postgres
+            .lock()
+            .await
+            .query_one("SELECT COUNT(*) FROM events", &[])
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(1, count);
+    }
+}
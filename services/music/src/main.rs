@@ -1,20 +1,46 @@
 use async_std::stream::StreamExt;
-use events::Rpc as EventsRpc;
-use music::structs::{Metadata, Rpc, TrackData, TrackPath};
+use events::RpcClient as EventsClient;
+use music::structs::{Metadata, Rpc, StreamTrackError, TrackData, TrackPath};
 use music::Server;
-use rpc_support::rpc_error::RpcError;
+use rpc_support::interceptor::LoggingInterceptor;
+use rpc_support::rpc_error::{ErrorCode, RpcError};
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use tokio_util::io::ReaderStream;
+use tracing::warn;
 use uuid::Uuid;
 
+/// Time between the attempts to subscribe again while the events service is unavailable
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+
 struct RpcServer {}
 
+/// Prints the events published after `last_seen` as they arrive, and moves `last_seen` along
+async fn follow_events(
+    client: &mut events::Client,
+    last_seen: &mut Option<SystemTime>,
+) -> Result<(), RpcError> {
+    let mut stream = client
+        .subscribe(events::SubscribeRequest {
+            id: Uuid::new_v4(),
+            from: *last_seen,
+        })
+        .await?;
+
+    while let Some(event) = stream.next().await {
+        let event = event?;
+        println!("got event: {:?}", event);
+        *last_seen = Some(event.created_time);
+    }
+
+    Ok(())
+}
+
 #[async_trait::async_trait]
 impl Rpc for RpcServer {
     async fn stream_track(
-        &mut self,
+        &self,
         request: TrackPath,
         _metadata: Metadata,
     ) -> Result<
@@ -26,15 +52,19 @@ impl Rpc for RpcServer {
         RpcError,
     > {
         let mut path = std::path::PathBuf::from("/mnt/the-nas/");
-        path.push(request.path);
+        path.push(&request.path);
 
-        let file = tokio::fs::File::open(path).await?;
+        let file = match tokio::fs::File::open(path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(StreamTrackError::TrackNotFound { path: request.path }.into());
+            }
+            file => file?,
+        };
         let reader = ReaderStream::new(file);
 
         Ok(Box::pin(reader.map(|buf| {
             Ok(TrackData {
-                // fixme this is very inefficient, we should just transport raw bytes, but the RPC system does not support that yet
-                data: base64::encode(&buf?),
+                data: buf?.to_vec(),
             })
         })))
     }
@@ -48,32 +78,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tokio::spawn(platform::async_infra::run_with_error_handling::<RpcError>(
         async move {
-            let mut client = events::Client::new("svc-events:7654")
-                .await
-                .map_err(RpcError::from)?;
-
-            let mut stream = client
-                .subscribe(
-                    events::SubscribeRequest {
-                        id: Uuid::new_v4(),
-                        from: None,
-                    },
-                    events::Metadata {
+            let mut client =
+                events::Client::new("svc-events:7654")
+                    .await?
+                    .with_metadata(events::Metadata {
                         source: "music".to_string(),
-                    },
-                )
-                .await?;
+                    });
+            // Subscribes again after the last event it saw, instead of repeating the first
+            // subscription with the whole history like `set_resubscribe` would
+            let mut last_seen = None;
 
-            while let Some(x) = stream.next().await {
-                println!("got event: {:?}", x);
+            loop {
+                match follow_events(&mut client, &mut last_seen).await {
+                    Err(e) if e.code() == ErrorCode::Unavailable => {
+                        warn!("Lost the subscription to the events service: {}", e);
+                        tokio::time::sleep(RESUBSCRIBE_INTERVAL).await;
+                    }
+                    result => return result,
+                }
             }
-
-            Ok(())
         },
     ));
 
     // todo make the bind addr/port configurable
-    let server = Server::new("0.0.0.0:7655", Arc::new(Mutex::new(RpcServer {}))).await?;
+    let server = Server::new("0.0.0.0:7655", Arc::new(RpcServer {}))
+        .await?
+        .with_interceptor(LoggingInterceptor)
+        .with_metrics_endpoint("0.0.0.0:9655");
+    server.shutdown_handle().trigger_on_signal()?;
     server.run().await?;
 
     Ok(())
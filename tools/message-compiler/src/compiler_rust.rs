@@ -1,14 +1,45 @@
-use crate::type_checking::{TypedField, TypedFieldType, TypedFile};
+use crate::reflection::{render_definitions, render_schema};
+use crate::type_checking::{TypedField, TypedFieldType, TypedFile, TypedRpcCall};
+use sha2::{Digest, Sha256};
+
+/// Identifies the generated code in the handshake of every connection
+pub struct Service {
+    pub name: String,
+    /// Hex encoded SHA-256 of the rendered schema, formatting and declaration order of the
+    /// `.evd` file don't change it
+    pub schema_hash: String,
+}
+
+impl Service {
+    /// `file` is the type checked `.evd` file
+    #[must_use]
+    pub fn new(name: &str, file: &TypedFile) -> Self {
+        Self {
+            name: name.to_string(),
+            schema_hash: Sha256::digest(render_definitions(file).as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
 
 #[must_use]
-pub fn compile(file: TypedFile) -> String {
+pub fn compile(file: TypedFile, service: &Service) -> String {
+    let schema = render_schema(&file, service);
     let TypedFile {
-        structs,
+        mut structs,
         meta,
-        rpc,
-        enums,
+        mut rpc,
+        mut enums,
     } = file;
 
+    // The type checker does not preserve the declaration order, sort everything to keep the
+    // generated code stable between builds
+    structs.sort_by(|a, b| a.name().cmp(b.name()));
+    enums.sort_by(|a, b| a.name().cmp(b.name()));
+    rpc.calls.sort_by(|a, b| a.name().cmp(b.name()));
+
     let mut result = String::new();
 
     result += "#[allow(unused)]\nuse async_std::stream::Stream;\n";
@@ -32,9 +63,12 @@ pub fn compile(file: TypedFile) -> String {
     }
 
     for e in enums {
+        let mut variants = e.variants().iter().collect::<Vec<_>>();
+        variants.sort_by(|a, b| a.name().cmp(b.name()));
+
         result += "#[derive(Serialize, Deserialize, Debug, Clone)]\n";
         result += &format!("pub enum {} {{\n", e.name());
-        for v in e.variants() {
+        for v in variants {
             result += "    ";
             result += v.name();
             result += " {\n";
@@ -46,43 +80,608 @@ pub fn compile(file: TypedFile) -> String {
 
     result += "\n";
 
+    result += "/// Calls are handled concurrently, implementations guard their mutable state themselves\n";
     result += "#[async_trait::async_trait]\n";
     result += "pub trait Rpc {\n";
 
     for r in rpc.calls() {
+        if let Some(error) = r.error() {
+            result += &format!(
+                "    /// Fails with [`{}`] as an application error\n",
+                to_rust_type(error)
+            );
+        }
         result += &format!(
             r#"    async fn {}(
-        &mut self,
+        &self,
         request: {},
         metadata: Metadata,
     ) -> {};
 "#,
             r.name(),
-            to_rust_type(r.request()),
-            if r.is_stream() {
-                format!(
-                    // {}
-                    "Result<\n        std::pin::Pin<Box<dyn Stream<Item = Result<{}, RpcError>> + Unpin + Send>>,\n        RpcError,\n    >",
-                    to_rust_type(r.response())
-                )
+            render_request_type(r),
+            render_return_type(r)
+        );
+    }
+    result += "}\n";
+
+    result += &render_application_errors(rpc.calls());
+    result += &render_service(service, &schema);
+    result += &render_client(rpc.calls());
+    result += &render_server(rpc.calls());
+
+    result
+}
+
+fn render_request_type(call: &TypedRpcCall) -> String {
+    if call.is_request_stream() {
+        format!(
+            "std::pin::Pin<Box<dyn Stream<Item = Result<{}, RpcError>> + Unpin + Send>>",
+            to_rust_type(call.request())
+        )
+    } else {
+        to_rust_type(call.request())
+    }
+}
+
+fn render_return_type(call: &TypedRpcCall) -> String {
+    if call.is_stream() {
+        format!(
+            "Result<\n        std::pin::Pin<Box<dyn Stream<Item = Result<{}, RpcError>> + Unpin + Send>>,\n        RpcError,\n    >",
+            to_rust_type(call.response())
+        )
+    } else {
+        format!("Result<{}, RpcError>", to_rust_type(call.response()))
+    }
+}
+
+fn render_application_errors(calls: &[TypedRpcCall]) -> String {
+    let mut error_types = calls
+        .iter()
+        .filter_map(|call| call.error().map(to_rust_type))
+        .collect::<Vec<_>>();
+    error_types.sort();
+    error_types.dedup();
+
+    let mut result = String::new();
+
+    for error_type in error_types {
+        result += &format!(
+            r#"
+impl From<{name}> for RpcError {{
+    fn from(error: {name}) -> Self {{
+        RpcError::application("{name}", &error)
+    }}
+}}
+
+impl {name} {{
+    /// Returns the error, if `error` is a `{name}` raised by the server
+    #[must_use]
+    pub fn from_rpc_error(error: &RpcError) -> Option<Self> {{
+        error.application_error("{name}")
+    }}
+}}
+"#,
+            name = error_type
+        );
+    }
+
+    result
+}
+
+fn render_service(service: &Service, schema: &str) -> String {
+    format!(
+        r##"
+/// Name of the service in the handshake of every connection
+pub const SERVICE_NAME: &str = "{}";
+/// Hash of the schema this code was generated from, see [`rpc_support::handshake`]
+pub const SCHEMA_HASH: &str = "{}";
+/// The schema servers answer reflection calls with, see [`rpc_support::reflection`]
+pub const SCHEMA: &str = r#"{}"#;
+
+fn handshake() -> rpc_support::handshake::Handshake {{
+    rpc_support::handshake::Handshake::new(SERVICE_NAME, SCHEMA_HASH)
+}}
+"##,
+        service.name, service.schema_hash, schema
+    )
+}
+
+fn render_client(calls: &[TypedRpcCall]) -> String {
+    let mut result = String::new();
+
+    result += r#"
+/// The calls of [`Rpc`] as a [`Client`] sends them, with the metadata of [`Client::with_metadata`]
+#[async_trait::async_trait]
+pub trait RpcClient {
+"#;
+
+    for r in calls {
+        result += &format!(
+            "    async fn {}(&mut self, request: {}) -> {};\n",
+            r.name(),
+            render_request_type(r),
+            render_return_type(r)
+        );
+    }
+
+    result += r#"}
+
+pub struct Client {
+    id: std::sync::atomic::AtomicU64,
+    raw: rpc_support::RawRpcClient,
+    metadata: Option<Metadata>,
+}
+
+impl Client {
+    /// # Errors
+    /// Will return an error when the TCP connection fails.
+    pub async fn new(addr: &str) -> Result<Self, RpcError> {
+        Self::with_connector(rpc_support::transport::TcpConnector::new(addr)).await
+    }
+
+    /// Connects over any transport, e.g. a Unix socket or an in-memory connection
+    ///
+    /// # Errors
+    /// Will return an error when the connection fails.
+    pub async fn with_connector(
+        connector: impl rpc_support::transport::Connector + 'static,
+    ) -> Result<Self, RpcError> {
+        Self::with_compression(connector, rpc_support::compression::Compression::default()).await
+    }
+
+    /// Connects with other compression settings than the default ones, see [`rpc_support::compression`]
+    ///
+    /// # Errors
+    /// Will return an error when the connection fails.
+    pub async fn with_compression(
+        connector: impl rpc_support::transport::Connector + 'static,
+        compression: rpc_support::compression::Compression,
+    ) -> Result<Self, RpcError> {
+        Ok(Self {
+            raw: rpc_support::RawRpcClient::connect(
+                connector,
+                rpc_support::framing::Framing::Binary,
+                handshake(),
+                compression,
+            )
+            .await?,
+            id: std::sync::atomic::AtomicU64::new(0),
+            metadata: None,
+        })
+    }
+
+    /// Connects to every replica `addr` resolves to and spreads the calls over them, see [`rpc_support::pool`]
+    ///
+    /// # Errors
+    /// Will return an error when none of the replicas is reachable.
+    pub async fn with_pool(addr: &str, pool: rpc_support::pool::Pool) -> Result<Self, RpcError> {
+        Self::with_resolver(rpc_support::pool::DnsResolver::new(addr), pool).await
+    }
+
+    /// Connects to every endpoint `resolver` finds, see [`Client::with_pool`]
+    ///
+    /// # Errors
+    /// Will return an error when none of the endpoints is reachable.
+    pub async fn with_resolver(
+        resolver: impl rpc_support::pool::Resolver + 'static,
+        pool: rpc_support::pool::Pool,
+    ) -> Result<Self, RpcError> {
+        Ok(Self {
+            raw: rpc_support::RawRpcClient::connect_pool(
+                resolver,
+                pool,
+                rpc_support::framing::Framing::Binary,
+                handshake(),
+                rpc_support::compression::Compression::default(),
+            )
+            .await?,
+            id: std::sync::atomic::AtomicU64::new(0),
+            metadata: None,
+        })
+    }
+
+    /// Sends `metadata` with every call, interceptors like
+    /// [`rpc_support::interceptor::MetadataDefaults`] can only add fields to it
+    #[must_use]
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Time the server has to answer subsequent calls, see [`rpc_support::RawRpcClient::set_timeout`]
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.raw.set_timeout(timeout);
+    }
+
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.raw.is_connected()
+    }
+
+    /// Re-request active streams after a reconnect, see [`rpc_support::RawRpcClient::set_resubscribe`]
+    pub fn set_resubscribe(&mut self, resubscribe: bool) {
+        self.raw.set_resubscribe(resubscribe);
+    }
+
+    /// Runs subsequent calls through `interceptor`, e.g. [`rpc_support::interceptor::MetadataDefaults`]
+    pub fn add_interceptor(
+        &mut self,
+        interceptor: impl rpc_support::interceptor::ClientInterceptor + 'static,
+    ) {
+        self.raw.add_interceptor(interceptor);
+    }
+
+    /// Asks the server for its schema, see [`rpc_support::RawRpcClient::reflect`]
+    ///
+    /// # Errors
+    /// Will return an error when the connection fails.
+    pub async fn reflect(&mut self) -> Result<rpc_support::reflection::Schema, RpcError> {
+        self.raw.reflect(self.next_id()).await
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id.fetch_add(1, std::sync::atomic::Ordering::AcqRel)
+    }
+}
+
+#[async_trait::async_trait]
+impl RpcClient for Client {
+"#;
+
+    for r in calls {
+        result += &format!(
+            r#"    async fn {name}(&mut self, request: {request}) -> {response} {{
+        self.raw
+            .{send}(self.next_id(), "{name}", {idempotency_key}{request_argument}, &self.metadata)
+            .await
+    }}
+"#,
+            name = r.name(),
+            request = render_request_type(r),
+            response = render_return_type(r),
+            send = match (r.is_request_stream(), r.is_stream()) {
+                _ if r.is_idempotent() => "send_idempotent_rpc",
+                (false, false) => "send_rpc",
+                (false, true) => "send_rpc_stream_request",
+                (true, false) => "send_rpc_client_stream",
+                (true, true) => "send_rpc_bidi_stream",
+            },
+            idempotency_key = match (r.is_idempotent(), r.idempotency_key()) {
+                (true, Some(key)) => format!("Some(request.{}.to_string()), ", key),
+                (true, None) => "None, ".to_string(),
+                (false, _) => String::new(),
+            },
+            request_argument = if r.is_request_stream() {
+                "request"
             } else {
-                format!("Result<{}, RpcError>", to_rust_type(r.response()))
-            }
+                "&request"
+            },
         );
     }
+
     result += "}\n";
 
     result
 }
 
+fn render_server(calls: &[TypedRpcCall]) -> String {
+    let mut result = String::new();
+
+    result += r#"
+pub struct Server<T>
+where
+    T: Rpc + Send + Sync,
+{
+    listener: Box<dyn rpc_support::transport::Listener>,
+    rpc: std::sync::Arc<T>,
+    interceptors: Vec<std::sync::Arc<dyn rpc_support::interceptor::Interceptor<Metadata>>>,
+    metrics: std::sync::Arc<rpc_support::metrics::ServerMetrics>,
+    metrics_addr: Option<String>,
+    shutdown: rpc_support::server::Shutdown,
+    shutdown_timeout: std::time::Duration,
+    compression: rpc_support::compression::Compression,
+    deduplication: std::sync::Arc<rpc_support::deduplication::Deduplication>,
+}
+
+impl<T> Server<T>
+where
+    T: Rpc + Send + Sync + 'static,
+{
+    /// # Errors
+    /// Will return an error when establishing the TCP Listener fails
+    pub async fn new(addr: &str, rpc: std::sync::Arc<T>) -> Result<Self, RpcError> {
+        Ok(Self::with_listener(
+            tokio::net::TcpListener::bind(addr).await?,
+            rpc,
+        ))
+    }
+
+    /// Serves clients of any transport, e.g. a Unix socket or an in-memory connection
+    pub fn with_listener(
+        listener: impl rpc_support::transport::Listener + 'static,
+        rpc: std::sync::Arc<T>,
+    ) -> Self {
+        Self {
+            listener: Box::new(listener),
+            rpc,
+            interceptors: Vec::new(),
+            metrics: std::sync::Arc::default(),
+            metrics_addr: None,
+            shutdown: rpc_support::server::Shutdown::new(),
+            shutdown_timeout: rpc_support::server::DEFAULT_SHUTDOWN_TIMEOUT,
+            compression: rpc_support::compression::Compression::default(),
+            deduplication: std::sync::Arc::default(),
+        }
+    }
+
+    /// Runs every call through `interceptor`, after the ones that were added before
+    #[must_use]
+    pub fn with_interceptor(
+        mut self,
+        interceptor: impl rpc_support::interceptor::Interceptor<Metadata> + 'static,
+    ) -> Self {
+        self.interceptors.push(std::sync::Arc::new(interceptor));
+        self
+    }
+
+    /// Serves the metrics of the server in the Prometheus text format at `/metrics` of `addr`
+    #[must_use]
+    pub fn with_metrics_endpoint(mut self, addr: &str) -> Self {
+        self.metrics_addr = Some(addr.to_string());
+        self
+    }
+
+    /// Metrics of all calls the server handled so far
+    #[must_use]
+    pub fn metrics(&self) -> std::sync::Arc<rpc_support::metrics::ServerMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Triggering the returned handle makes [`Self::run`] stop accepting clients and return once
+    /// the running calls finished
+    #[must_use]
+    pub fn shutdown_handle(&self) -> rpc_support::server::Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Time running calls get to finish after a shutdown was triggered
+    #[must_use]
+    pub fn with_shutdown_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Algorithms the server accepts for compressing payloads and the size from which it
+    /// compresses responses, see [`rpc_support::compression`]
+    #[must_use]
+    pub fn with_compression(mut self, compression: rpc_support::compression::Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// How long the server remembers the results of `idempotent` calls to answer their retries,
+    /// see [`rpc_support::deduplication`]
+    #[must_use]
+    pub fn with_deduplication(
+        mut self,
+        deduplication: rpc_support::deduplication::Deduplication,
+    ) -> Self {
+        self.deduplication = std::sync::Arc::new(deduplication);
+        self
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_client(
+        socket: Box<dyn rpc_support::transport::Transport>,
+        peer: rpc_support::transport::Peer,
+        rpc: std::sync::Arc<T>,
+        interceptors: rpc_support::interceptor::Interceptors<Metadata>,
+        metrics: std::sync::Arc<rpc_support::metrics::ServerMetrics>,
+        shutdown: rpc_support::server::Shutdown,
+        compression: rpc_support::compression::Compression,
+        deduplication: std::sync::Arc<rpc_support::deduplication::Deduplication>,
+    ) -> Result<(), rpc_support::server::ClientError> {
+        let (read, mut write) = tokio::io::split(metrics.metered(socket));
+        let mut reader = tokio::io::BufReader::new(read);
+        let framing = rpc_support::framing::accept_framing(&mut reader, &mut write).await?;
+        let mut reader = rpc_support::framing::FrameReader::new(reader, framing);
+        let mut write = rpc_support::framing::FrameWriter::new(write, framing);
+        rpc_support::handshake::accept_handshake(&mut reader, &mut write, &handshake(), &compression)
+            .await?;
+
+        let writer = rpc_support::server::ResponseWriter::spawn(
+            write,
+            metrics.clone(),
+            shutdown.clone(),
+        );
+
+        let calls = rpc_support::server::ActiveCalls::new(peer, interceptors, metrics);
+
+        loop {
+            let request = tokio::select! {
+                request = rpc_support::read_request::<Metadata>(&mut reader) => request?,
+                () = shutdown.drained() => return Ok(()),
+            };
+
+            match request {
+                rpc_support::server::Request::Call(mut call) => {
+                    calls.open_input(&mut call, &writer);
+                    let info = calls.call_info(&call);
+                    calls.spawn(
+                        info,
+                        call.timeout,
+                        writer.clone(),
+                        Self::handle_request(
+                            rpc.clone(),
+                            writer.clone(),
+                            deduplication.clone(),
+                            *call,
+                        ),
+                    );
+                }
+                rpc_support::server::Request::Cancel { request_id } => calls.cancel(request_id),
+                rpc_support::server::Request::Credit {
+                    request_id,
+                    credits,
+                } => writer.grant_credits(request_id, credits),
+                rpc_support::server::Request::Item {
+                    request_id,
+                    payload,
+                } => calls.push_input(request_id, payload).await,
+                rpc_support::server::Request::EndOfInput { request_id } => {
+                    calls.end_input(request_id);
+                }
+                rpc_support::server::Request::Reflect { request_id } => {
+                    rpc_support::reflection::reply(&writer, SCHEMA, request_id);
+                }
+                rpc_support::server::Request::Rejected { request_id, error } => {
+                    rpc_support::server::reject(&writer, request_id, error);
+                }
+            }
+        }
+    }
+
+    async fn handle_request(
+        rpc: std::sync::Arc<T>,
+        writer: rpc_support::server::ResponseWriter,
+        "#;
+    // Only idempotent calls are deduplicated
+    result += if calls.iter().any(TypedRpcCall::is_idempotent) {
+        "deduplication"
+    } else {
+        "_deduplication"
+    };
+    result += r#": std::sync::Arc<rpc_support::deduplication::Deduplication>,
+        call: rpc_support::server::Call<Metadata>,
+    ) -> Result<(), RpcError> {
+        match call.method_name.as_str() {
+"#;
+
+    for r in calls {
+        let handle = if r.is_idempotent() {
+            format!(
+                r#"{{
+                        let metadata = call.metadata;
+                        deduplication
+                            .run(&call.method_name, call.idempotency_key.as_deref(), async move {{
+                                rpc.{}(request, metadata).await
+                            }})
+                            .await
+                    }}"#,
+                r.name()
+            )
+        } else {
+            format!("rpc.{}(request, call.metadata).await,", r.name())
+        };
+
+        result += &format!(
+            r#"            "{name}" => {{
+                let result = match {request} {{
+                    Ok(request) => {handle}
+                    Err(e) => Err(e),
+                }};
+
+                {send}
+            }}
+"#,
+            name = r.name(),
+            handle = handle,
+            request = if r.is_request_stream() {
+                "call.input.map(rpc_support::server::RequestInput::into_stream).ok_or_else(|| rpc_support::server::missing_request_stream(&call.method_name))"
+            } else {
+                "writer.framing().decode_payload(&call.payload)"
+            },
+            send = if r.is_stream() {
+                "rpc_support::send_stream_response(&writer, result, call.request_id, call.credits).await"
+            } else {
+                "rpc_support::send_unary_response(&writer, result, call.request_id).await"
+            },
+        );
+    }
+
+    result += r#"            _ => {
+                let result: Result<(), RpcError> =
+                    Err(rpc_support::server::unknown_method(&call.method_name));
+
+                rpc_support::send_unary_response(&writer, result, call.request_id).await
+            }
+        }
+    }
+
+    /// Serves clients until a shutdown is triggered, see [`Self::shutdown_handle`]
+    ///
+    /// # Errors
+    /// Will return an error if the connection fails
+    pub async fn run(mut self) -> Result<(), rpc_support::server::RunError> {
+        let interceptors: rpc_support::interceptor::Interceptors<Metadata> =
+            std::mem::take(&mut self.interceptors).into();
+
+        if let Some(addr) = &self.metrics_addr {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tokio::spawn(platform::async_infra::run_with_error_handling(
+                rpc_support::metrics::serve(listener, self.metrics.clone()),
+            ));
+        }
+
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                () = self.shutdown.triggered() => break,
+            };
+            tracing::info!("New client connected: {}", peer);
+
+            tokio::spawn(platform::async_infra::run_with_error_handling(
+                Self::handle_client(
+                    socket,
+                    peer,
+                    self.rpc.clone(),
+                    interceptors.clone(),
+                    self.metrics.clone(),
+                    self.shutdown.clone(),
+                    self.compression.clone(),
+                    self.deduplication.clone(),
+                ),
+            ));
+        }
+
+        self.shutdown.drain(self.shutdown_timeout).await;
+
+        Ok(())
+    }
+}
+"#;
+
+    result
+}
+
 fn render_fields(fields: &[TypedField], public: bool, depth: usize) -> String {
     let mut result = String::new();
     let indent = (0..(depth * 4)).map(|_| " ").collect::<String>();
 
+    let mut fields = fields.iter().collect::<Vec<_>>();
+    fields.sort_by(|a, b| a.name().cmp(b.name()));
+
     for f in fields {
-        if let TypedFieldType::Instant = f.type_name() {
-            result += &indent;
-            result += "#[serde(with = \"rpc_support::system_time_serializer\")]\n";
+        match f.type_name() {
+            TypedFieldType::Instant => {
+                result += &indent;
+                result += "#[serde(with = \"rpc_support::system_time_serializer\")]\n";
+            }
+            TypedFieldType::Bytes => {
+                result += &indent;
+                result += "#[serde(with = \"rpc_support::bytes_serializer\")]\n";
+            }
+            TypedFieldType::Optional(type_) if matches!(**type_, TypedFieldType::Instant) => {
+                result += &indent;
+                result +=
+                    "#[serde(default, with = \"rpc_support::system_time_serializer::optional\")]\n";
+            }
+            TypedFieldType::Optional(type_) if matches!(**type_, TypedFieldType::Bytes) => {
+                result += &indent;
+                result += "#[serde(default, with = \"rpc_support::bytes_serializer::optional\")]\n";
+            }
+            _ => {}
         }
         result += &format!(
             "{}{}{}: {},\n",
@@ -109,8 +708,145 @@ fn to_rust_type(type_: &TypedFieldType) -> String {
         TypedFieldType::Instant => "std::time::SystemTime".to_string(),
         TypedFieldType::Guid => "::uuid::Uuid".to_string(),
         TypedFieldType::String => "String".to_string(),
+        TypedFieldType::Bytes => "Vec<u8>".to_string(),
         TypedFieldType::Void => "()".to_string(),
         TypedFieldType::OtherStruct(name) | TypedFieldType::Enum(name) => name.clone(),
         TypedFieldType::Optional(type_) => format!("Option<{}>", to_rust_type(type_)),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::compiler_rust::{compile, Service};
+    use crate::parsing::grammar::RFileParser;
+    use crate::type_checking::TypeChecker;
+
+    /// Generated code with its whitespace collapsed, so the checks don't depend on its formatting
+    struct Generated(String);
+
+    impl Generated {
+        fn compile(source: &str) -> Self {
+            let ast = RFileParser::new().parse(source).unwrap();
+            let file = TypeChecker::new().check(&ast).unwrap();
+            let service = Service::new("test", &file);
+
+            Self(collapse_whitespace(&compile(file, &service)))
+        }
+
+        fn count(&self, snippet: &str) -> usize {
+            self.0.matches(&collapse_whitespace(snippet)).count()
+        }
+
+        fn contains(&self, snippet: &str) -> bool {
+            self.count(snippet) > 0
+        }
+    }
+
+    fn collapse_whitespace(code: &str) -> String {
+        code.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    pub fn generates_client_and_server_for_every_call() {
+        let rust = Generated::compile(
+            "struct A { f: u8 } rpc { unary(A) -> A; streaming(A) -> stream A; }",
+        );
+
+        assert!(rust.contains("async fn unary(&mut self, request: A) -> Result<A, RpcError>;"));
+        assert!(rust.contains(".send_rpc(self.next_id(), \"unary\", &request, &self.metadata)"));
+        assert!(rust.contains(
+            ".send_rpc_stream_request(self.next_id(), \"streaming\", &request, &self.metadata)"
+        ));
+        assert!(rust.contains("\"unary\" => {"));
+        assert!(rust.contains("rpc.unary(request, call.metadata).await"));
+        assert!(rust.contains("\"streaming\" => {"));
+        assert!(rust.contains(
+            "rpc_support::send_stream_response(&writer, result, call.request_id, call.credits)"
+        ));
+    }
+
+    #[test]
+    pub fn schema_hash_only_depends_on_the_schema() {
+        let hash = |source: &str| {
+            let ast = RFileParser::new().parse(source).unwrap();
+            Service::new("test", &TypeChecker::new().check(&ast).unwrap()).schema_hash
+        };
+
+        let schema = hash("struct A { f: u8 } struct B { g: u8 } rpc { get(A) -> B; }");
+
+        assert_eq!(
+            schema,
+            hash("struct B {\r\n    g: u8\r\n}\r\nstruct A { f: u8, }\r\n\r\nrpc {\r\n    get(A) -> B;\r\n}\r\n")
+        );
+        assert_ne!(
+            schema,
+            hash("struct A { f: u16 } struct B { g: u8 } rpc { get(A) -> B; }")
+        );
+    }
+
+    #[test]
+    pub fn request_streams_are_sent_as_streams() {
+        let rust = Generated::compile(
+            "struct A { f: u8 } rpc { upload(stream A) -> A; chat(stream A) -> stream A; }",
+        );
+
+        // Both calls, in both traits and in the client
+        assert_eq!(
+            6,
+            rust.count(
+                "request: std::pin::Pin<Box<dyn Stream<Item = Result<A, RpcError>> + Unpin + Send>>"
+            )
+        );
+        assert!(rust.contains(
+            ".send_rpc_client_stream(self.next_id(), \"upload\", request, &self.metadata)"
+        ));
+        assert!(rust
+            .contains(".send_rpc_bidi_stream(self.next_id(), \"chat\", request, &self.metadata)"));
+        assert_eq!(
+            2,
+            rust.count("call.input.map(rpc_support::server::RequestInput::into_stream)")
+        );
+    }
+
+    #[test]
+    pub fn declared_errors_convert_to_rpc_errors() {
+        let rust = Generated::compile(
+            "struct A { f: u8 } enum Failure { NotFound } \
+             rpc { a(A) -> A throws Failure; b(A) -> stream A throws Failure; }",
+        );
+
+        assert_eq!(1, rust.count("impl From<Failure> for RpcError {"));
+        assert!(rust.contains("RpcError::application(\"Failure\", &error)"));
+        assert!(rust.contains("error.application_error(\"Failure\")"));
+    }
+
+    #[test]
+    pub fn bytes_fields_use_the_bytes_serializer() {
+        let rust = Generated::compile("struct A { data: bytes, thumbnail: bytes? }");
+
+        assert!(
+            rust.contains("#[serde(with = \"rpc_support::bytes_serializer\")] pub data: Vec<u8>,")
+        );
+        assert!(rust.contains(
+            "#[serde(default, with = \"rpc_support::bytes_serializer::optional\")] \
+             pub thumbnail: Option<Vec<u8>>,"
+        ));
+    }
+
+    #[test]
+    pub fn idempotent_calls_are_sent_with_a_key_and_deduplicated() {
+        let rust = Generated::compile(
+            "struct A { id: guid } \
+             rpc { put(A) -> A; idempotent(id) a(A) -> A; idempotent b(A) -> A; }",
+        );
+
+        assert!(rust.contains(".send_rpc(self.next_id(), \"put\", &request, &self.metadata)"));
+        assert!(rust.contains(
+            ".send_idempotent_rpc(self.next_id(), \"a\", Some(request.id.to_string()), &request, &self.metadata)"
+        ));
+        assert!(rust.contains(
+            ".send_idempotent_rpc(self.next_id(), \"b\", None, &request, &self.metadata)"
+        ));
+        assert_eq!(2, rust.count("deduplication .run("));
+    }
+}
@@ -1,5 +1,7 @@
 pub mod compiler_rust;
+pub mod loading;
 pub mod parsing;
+pub mod reflection;
 pub mod type_checking;
 
 #[macro_use]
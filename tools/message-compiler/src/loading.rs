@@ -0,0 +1,29 @@
+use crate::compiler_rust::Service;
+use crate::parsing::grammar::RFileParser;
+use crate::reflection::render_schema;
+use crate::type_checking::TypeChecker;
+use std::error::Error;
+use std::path::Path;
+
+/// Type checks the `.evd` file at `path` and renders its schema, for tools that work with any
+/// service. The service is named after the file.
+///
+/// # Errors
+/// Fails if the file can't be read or is not a valid `.evd` file
+pub fn load_schema(path: &Path) -> Result<String, Box<dyn Error>> {
+    let source = std::fs::read_to_string(path)?;
+    let ast = RFileParser::new()
+        .parse(&source)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    let typed_file = TypeChecker::new().check(&ast)?;
+
+    let service_name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    Ok(render_schema(
+        &typed_file,
+        &Service::new(&service_name, &typed_file),
+    ))
+}
@@ -1,5 +1,6 @@
 mod compiler_rust;
 mod parsing;
+mod reflection;
 mod type_checking;
 
 #[macro_use]
@@ -29,7 +30,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     )?;
     let type_checker = TypeChecker::new();
     let typed_file = type_checker.check(&ast)?;
-    let rust = compiler_rust::compile(typed_file);
+    let service = compiler_rust::Service::new("example", &typed_file);
+    let rust = compiler_rust::compile(typed_file, &service);
     println!("{}", rust);
 
     Ok(())
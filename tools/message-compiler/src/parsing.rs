@@ -86,8 +86,13 @@ impl<'input> EnumDefinitionRaw<'input> {
 pub struct RpcDefinitionRaw<'input> {
     pub(crate) name: IdentifierRaw<'input>,
     pub(crate) request: TypeRaw<'input>,
+    pub(crate) is_request_stream: bool,
     pub(crate) response: TypeRaw<'input>,
     pub(crate) is_stream: bool,
+    pub(crate) error: Option<TypeRaw<'input>>,
+    pub(crate) idempotent: bool,
+    /// Field of the request that identifies it, set with `idempotent(field)`
+    pub(crate) idempotency_key: Option<IdentifierRaw<'input>>,
 }
 
 impl<'input> RpcDefinitionRaw<'input> {
@@ -95,16 +100,30 @@ impl<'input> RpcDefinitionRaw<'input> {
     pub fn new(
         name: IdentifierRaw<'input>,
         request: TypeRaw<'input>,
+        is_request_stream: bool,
         response: TypeRaw<'input>,
         is_stream: bool,
+        error: Option<TypeRaw<'input>>,
     ) -> Self {
         Self {
             name,
             request,
+            is_request_stream,
             response,
             is_stream,
+            error,
+            idempotent: false,
+            idempotency_key: None,
         }
     }
+
+    /// Marks the call as safe to repeat, see [`crate::type_checking::TypedRpcCall::is_idempotent`]
+    #[must_use]
+    pub fn idempotent(mut self, key: Option<IdentifierRaw<'input>>) -> Self {
+        self.idempotent = true;
+        self.idempotency_key = key;
+        self
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -280,8 +299,10 @@ mod test {
                 Some(RpcRaw::new(vec![RpcDefinitionRaw::new(
                     IdentifierRaw::new("call"),
                     TypeRaw::new(IdentifierRaw::new("request"), false),
+                    false,
                     TypeRaw::new(IdentifierRaw::new("response"), false),
-                    false
+                    false,
+                    None
                 )]))
             )),
             r
@@ -316,11 +337,83 @@ mod test {
                 Some(RpcRaw::new(vec![RpcDefinitionRaw::new(
                     IdentifierRaw::new("call"),
                     TypeRaw::new(IdentifierRaw::new("request"), false),
+                    false,
                     TypeRaw::new(IdentifierRaw::new("response"), false),
-                    true
+                    true,
+                    None
                 )]))
             )),
             r
         );
     }
+
+    #[test]
+    pub fn can_parse_rpc_errors() {
+        let input = "struct request { f1: u32 } enum failure { A } rpc { call(request) -> stream request throws failure; }";
+        let r = parsing::grammar::RFileParser::new().parse(input).unwrap();
+
+        assert_eq!(
+            Some(&RpcRaw::new(vec![RpcDefinitionRaw::new(
+                IdentifierRaw::new("call"),
+                TypeRaw::new(IdentifierRaw::new("request"), false),
+                false,
+                TypeRaw::new(IdentifierRaw::new("request"), false),
+                true,
+                Some(TypeRaw::new(IdentifierRaw::new("failure"), false))
+            )])),
+            r.rpc()
+        );
+    }
+
+    #[test]
+    pub fn can_parse_rpc_request_streams() {
+        let input = "struct request { f1: u32 } rpc { upload(stream request) -> request; chat(stream request) -> stream request; }";
+        let r = parsing::grammar::RFileParser::new().parse(input).unwrap();
+
+        assert_eq!(
+            Some(&RpcRaw::new(vec![
+                RpcDefinitionRaw::new(
+                    IdentifierRaw::new("upload"),
+                    TypeRaw::new(IdentifierRaw::new("request"), false),
+                    true,
+                    TypeRaw::new(IdentifierRaw::new("request"), false),
+                    false,
+                    None
+                ),
+                RpcDefinitionRaw::new(
+                    IdentifierRaw::new("chat"),
+                    TypeRaw::new(IdentifierRaw::new("request"), false),
+                    true,
+                    TypeRaw::new(IdentifierRaw::new("request"), false),
+                    true,
+                    None
+                )
+            ])),
+            r.rpc()
+        );
+    }
+
+    #[test]
+    pub fn can_parse_idempotent_rpcs() {
+        let input = "struct request { id: guid } rpc { idempotent get(request) -> request; idempotent(id) put(request) -> request; }";
+        let r = parsing::grammar::RFileParser::new().parse(input).unwrap();
+
+        let definition = |name| {
+            RpcDefinitionRaw::new(
+                IdentifierRaw::new(name),
+                TypeRaw::new(IdentifierRaw::new("request"), false),
+                false,
+                TypeRaw::new(IdentifierRaw::new("request"), false),
+                false,
+                None,
+            )
+        };
+        assert_eq!(
+            Some(&RpcRaw::new(vec![
+                definition("get").idempotent(None),
+                definition("put").idempotent(Some(IdentifierRaw::new("id"))),
+            ])),
+            r.rpc()
+        );
+    }
 }
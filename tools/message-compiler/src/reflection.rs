@@ -0,0 +1,161 @@
+use crate::compiler_rust::Service;
+use crate::type_checking::{TypedField, TypedFieldType, TypedFile};
+
+/// Renders the schema a server returns from its reflection call, the JSON representation of
+/// `rpc_support::reflection::Schema`. Everything is sorted by name, so the result only depends
+/// on the content of the `.evd` file.
+#[must_use]
+pub fn render_schema(file: &TypedFile, service: &Service) -> String {
+    format!(
+        r#"{{"service_name":"{}","schema_hash":"{}",{}}}"#,
+        service.name,
+        service.schema_hash,
+        render_definitions(file)
+    )
+}
+
+/// The types and calls of the schema, without the service they belong to
+#[must_use]
+pub fn render_definitions(file: &TypedFile) -> String {
+    let mut structs = file.structs.iter().collect::<Vec<_>>();
+    structs.sort_by(|a, b| a.name().cmp(b.name()));
+    let mut enums = file.enums.iter().collect::<Vec<_>>();
+    enums.sort_by(|a, b| a.name().cmp(b.name()));
+    let mut calls = file.rpc.calls().iter().collect::<Vec<_>>();
+    calls.sort_by(|a, b| a.name().cmp(b.name()));
+
+    let structs = structs
+        .iter()
+        .map(|s| render_struct(s.name(), s.fields()))
+        .collect::<Vec<_>>();
+
+    let enums = enums
+        .iter()
+        .map(|e| {
+            let mut variants = e.variants().iter().collect::<Vec<_>>();
+            variants.sort_by(|a, b| a.name().cmp(b.name()));
+            let variants = variants
+                .iter()
+                .map(|v| render_struct(v.name(), v.fields()))
+                .collect::<Vec<_>>();
+
+            format!(
+                r#"{{"name":"{}","variants":[{}]}}"#,
+                e.name(),
+                variants.join(",")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let calls = calls
+        .iter()
+        .map(|c| {
+            format!(
+                r#"{{"name":"{}","request":"{}","request_stream":{},"response":"{}","response_stream":{},"error":{}{}}}"#,
+                c.name(),
+                render_type(c.request()),
+                c.is_request_stream(),
+                render_type(c.response()),
+                c.is_stream(),
+                c.error().map_or_else(
+                    || "null".to_string(),
+                    |error| format!(r#""{}""#, render_type(error))
+                ),
+                if c.is_idempotent() {
+                    r#","idempotent":true"#
+                } else {
+                    ""
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        r#""metadata":{},"structs":[{}],"enums":[{}],"calls":[{}]"#,
+        render_fields(file.meta.fields()),
+        structs.join(","),
+        enums.join(","),
+        calls.join(",")
+    )
+}
+
+fn render_struct(name: &str, fields: &[TypedField]) -> String {
+    format!(
+        r#"{{"name":"{}","fields":{}}}"#,
+        name,
+        render_fields(fields)
+    )
+}
+
+fn render_fields(fields: &[TypedField]) -> String {
+    let mut fields = fields.iter().collect::<Vec<_>>();
+    fields.sort_by(|a, b| a.name().cmp(b.name()));
+
+    let fields = fields
+        .iter()
+        .map(|f| {
+            format!(
+                r#"{{"name":"{}","type":"{}"}}"#,
+                f.name(),
+                render_type(f.type_name())
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("[{}]", fields.join(","))
+}
+
+/// The type as written in the `.evd` file
+fn render_type(type_: &TypedFieldType) -> String {
+    match type_ {
+        TypedFieldType::U8 => "u8".to_string(),
+        TypedFieldType::U16 => "u16".to_string(),
+        TypedFieldType::U32 => "u32".to_string(),
+        TypedFieldType::U64 => "u64".to_string(),
+        TypedFieldType::S8 => "s8".to_string(),
+        TypedFieldType::S16 => "s16".to_string(),
+        TypedFieldType::S32 => "s32".to_string(),
+        TypedFieldType::S64 => "s64".to_string(),
+        TypedFieldType::Instant => "instant".to_string(),
+        TypedFieldType::Guid => "guid".to_string(),
+        TypedFieldType::String => "string".to_string(),
+        TypedFieldType::Bytes => "bytes".to_string(),
+        TypedFieldType::Void => "void".to_string(),
+        TypedFieldType::OtherStruct(name) | TypedFieldType::Enum(name) => name.clone(),
+        TypedFieldType::Optional(type_) => format!("{}?", render_type(type_)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler_rust::Service;
+    use crate::parsing::grammar::RFileParser;
+    use crate::reflection::render_schema;
+    use crate::type_checking::TypeChecker;
+
+    #[test]
+    fn schema_describes_types_and_calls() {
+        let ast = RFileParser::new()
+            .parse(
+                "metadata { source: string } \
+                 struct B { b: u8? } struct A { a: instant, other: B } \
+                 enum E { One(x: guid), Two } \
+                 rpc { watch(stream A) -> stream B throws E; }",
+            )
+            .unwrap();
+        let file = TypeChecker::new().check(&ast).unwrap();
+        let schema = render_schema(&file, &Service::new("test", &file));
+
+        assert!(schema.starts_with(r#"{"service_name":"test","schema_hash":""#));
+        assert!(schema.contains(r#""metadata":[{"name":"source","type":"string"}]"#));
+        assert!(schema.contains(
+            r#""structs":[{"name":"A","fields":[{"name":"a","type":"instant"},{"name":"other","type":"B"}]},{"name":"B","fields":[{"name":"b","type":"u8?"}]}]"#
+        ));
+        assert!(schema.contains(
+            r#""enums":[{"name":"E","variants":[{"name":"One","fields":[{"name":"x","type":"guid"}]},{"name":"Two","fields":[]}]}]"#
+        ));
+        assert!(schema.contains(
+            r#""calls":[{"name":"watch","request":"A","request_stream":true,"response":"B","response_stream":true,"error":"E"}]"#
+        ));
+    }
+}
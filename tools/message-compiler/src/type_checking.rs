@@ -16,6 +16,15 @@ pub enum TypeCheckError {
         struct_name: String,
     },
     StructNotFound(String),
+    InvalidErrorType {
+        type_name: String,
+        rpc_name: String,
+    },
+    StreamingIdempotentRpc(String),
+    InvalidIdempotencyKey {
+        field_name: String,
+        rpc_name: String,
+    },
 }
 
 impl Display for TypeCheckError {
@@ -35,6 +44,27 @@ impl Display for TypeCheckError {
             TypeCheckError::StructNotFound(name) => {
                 write!(f, "A struct with name \"{}\" does not exist", name)
             }
+            TypeCheckError::InvalidErrorType {
+                type_name,
+                rpc_name,
+            } => write!(
+                f,
+                "The error type \"{}\" of rpc \"{}\" must be a struct or an enum",
+                type_name, rpc_name
+            ),
+            TypeCheckError::StreamingIdempotentRpc(rpc_name) => write!(
+                f,
+                "The rpc \"{}\" can't be idempotent, only calls without streams are",
+                rpc_name
+            ),
+            TypeCheckError::InvalidIdempotencyKey {
+                field_name,
+                rpc_name,
+            } => write!(
+                f,
+                "The idempotency key \"{}\" of rpc \"{}\" must be a guid, string or integer field of its request",
+                field_name, rpc_name
+            ),
         }
     }
 }
@@ -54,6 +84,7 @@ pub enum TypedFieldType {
     Instant,
     Guid,
     String,
+    Bytes,
     Void,
     OtherStruct(String),
     Enum(String),
@@ -145,6 +176,7 @@ enum TypeCheckableFieldType<'a> {
     Instant,
     Guid,
     String,
+    Bytes,
     Void,
     ToBeResolved(&'a str),
     Optional(Box<TypeCheckableFieldType<'a>>),
@@ -188,8 +220,12 @@ impl TypedMetadata {
 pub struct TypedRpcCall {
     name: String,
     request: TypedFieldType,
+    is_request_stream: bool,
     response: TypedFieldType,
     is_stream: bool,
+    error: Option<TypedFieldType>,
+    idempotent: bool,
+    idempotency_key: Option<String>,
 }
 
 impl TypedRpcCall {
@@ -203,6 +239,12 @@ impl TypedRpcCall {
         &self.request
     }
 
+    /// The client sends a stream of requests instead of a single one
+    #[must_use]
+    pub fn is_request_stream(&self) -> bool {
+        self.is_request_stream
+    }
+
     #[must_use]
     pub fn response(&self) -> &TypedFieldType {
         &self.response
@@ -212,6 +254,26 @@ impl TypedRpcCall {
     pub fn is_stream(&self) -> bool {
         self.is_stream
     }
+
+    /// The application error declared with `throws`, always a struct or an enum
+    #[must_use]
+    pub fn error(&self) -> Option<&TypedFieldType> {
+        self.error.as_ref()
+    }
+
+    /// Declared with `idempotent`, clients may retry the call and servers handle the retries
+    /// only once. Never set for calls with streams.
+    #[must_use]
+    pub fn is_idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Field of the request that tells retries apart from new calls, set with
+    /// `idempotent(field)`. Other idempotent calls are told apart by a key the client generates.
+    #[must_use]
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
 }
 
 pub struct TypedRpc {
@@ -286,6 +348,7 @@ impl<'input> TypeChecker<'input> {
             "instant" => TypeCheckableFieldType::Instant,
             "guid" => TypeCheckableFieldType::Guid,
             "string" => TypeCheckableFieldType::String,
+            "bytes" => TypeCheckableFieldType::Bytes,
             "void" => TypeCheckableFieldType::Void,
             other => TypeCheckableFieldType::ToBeResolved(other),
         };
@@ -329,6 +392,7 @@ impl<'input> TypeChecker<'input> {
             TypeCheckableFieldType::Instant => TypedFieldType::Instant,
             TypeCheckableFieldType::Guid => TypedFieldType::Guid,
             TypeCheckableFieldType::String => TypedFieldType::String,
+            TypeCheckableFieldType::Bytes => TypedFieldType::Bytes,
             TypeCheckableFieldType::Void => TypedFieldType::Void,
             TypeCheckableFieldType::ToBeResolved(type_name) => {
                 if self.structs.contains_key(*type_name) {
@@ -447,37 +511,110 @@ impl<'input> TypeChecker<'input> {
         }
 
         let meta_fields = self.type_check_fields(&metadata_fields)?;
-        let rpc = file.rpc().map(|rpc| {
-            let mut rpc_typed = HashMap::new();
-            for rpc_definition in &rpc.definitions {
-                let typed_rpc = TypedRpcCall {
-                    name: rpc_definition.name.0.to_string(),
-                    // todo no unwraps here!
-                    request: self
-                        .resolve_type(&Self::resolve_raw_type(&rpc_definition.request))
-                        .unwrap(),
-                    response: self
-                        .resolve_type(&Self::resolve_raw_type(&rpc_definition.response))
-                        .unwrap(),
-                    is_stream: rpc_definition.is_stream,
-                };
-                rpc_typed.insert(rpc_definition.name.0.to_string(), typed_rpc);
+        let mut rpc_typed = HashMap::new();
+        for rpc_definition in file.rpc().map_or(&[][..], |rpc| &rpc.definitions) {
+            let error = match &rpc_definition.error {
+                Some(error) => Some(self.resolve_error_type(error, rpc_definition.name.0)?),
+                None => None,
+            };
+
+            let request = self
+                .resolve_type(&Self::resolve_raw_type(&rpc_definition.request))
+                .unwrap();
+            if rpc_definition.idempotent
+                && (rpc_definition.is_request_stream || rpc_definition.is_stream)
+            {
+                return Err(TypeCheckError::StreamingIdempotentRpc(
+                    rpc_definition.name.0.to_string(),
+                ));
+            }
+            if let Some(key) = &rpc_definition.idempotency_key {
+                Self::check_idempotency_key(
+                    &structs_typed,
+                    &request,
+                    key.0,
+                    rpc_definition.name.0,
+                )?;
             }
-            rpc_typed
-        });
+
+            let typed_rpc = TypedRpcCall {
+                name: rpc_definition.name.0.to_string(),
+                // todo no unwraps here!
+                request,
+                is_request_stream: rpc_definition.is_request_stream,
+                response: self
+                    .resolve_type(&Self::resolve_raw_type(&rpc_definition.response))
+                    .unwrap(),
+                is_stream: rpc_definition.is_stream,
+                error,
+                idempotent: rpc_definition.idempotent,
+                idempotency_key: rpc_definition
+                    .idempotency_key
+                    .as_ref()
+                    .map(|key| key.0.to_string()),
+            };
+            rpc_typed.insert(rpc_definition.name.0.to_string(), typed_rpc);
+        }
 
         Ok(TypedFile {
-            structs: structs_typed.into_iter().map(|(_, v)| v).collect(),
-            enums: enums_typed.into_iter().map(|(_, v)| v).collect(),
+            structs: structs_typed.into_values().collect(),
+            enums: enums_typed.into_values().collect(),
             meta: TypedMetadata {
                 fields: meta_fields,
             },
             rpc: TypedRpc {
-                calls: rpc.map_or_else(Vec::new, |rpc| rpc.into_iter().map(|(_, v)| v).collect()),
+                calls: rpc_typed.into_values().collect(),
             },
         })
     }
 
+    fn resolve_error_type(
+        &self,
+        type_raw: &TypeRaw<'input>,
+        rpc_name: &str,
+    ) -> Result<TypedFieldType, TypeCheckError> {
+        match self.resolve_type(&Self::resolve_raw_type(type_raw))? {
+            type_ @ (TypedFieldType::OtherStruct(_) | TypedFieldType::Enum(_)) => Ok(type_),
+            _ => Err(TypeCheckError::InvalidErrorType {
+                type_name: type_raw.name.0.to_string(),
+                rpc_name: rpc_name.to_string(),
+            }),
+        }
+    }
+
+    fn check_idempotency_key(
+        structs: &HashMap<String, TypedStruct>,
+        request: &TypedFieldType,
+        key: &str,
+        rpc_name: &str,
+    ) -> Result<(), TypeCheckError> {
+        let field = match request {
+            TypedFieldType::OtherStruct(name) => structs
+                .get(name)
+                .and_then(|s| s.fields().iter().find(|field| field.name() == key)),
+            _ => None,
+        };
+
+        match field.map(TypedField::type_name) {
+            Some(
+                TypedFieldType::Guid
+                | TypedFieldType::String
+                | TypedFieldType::U8
+                | TypedFieldType::U16
+                | TypedFieldType::U32
+                | TypedFieldType::U64
+                | TypedFieldType::S8
+                | TypedFieldType::S16
+                | TypedFieldType::S32
+                | TypedFieldType::S64,
+            ) => Ok(()),
+            _ => Err(TypeCheckError::InvalidIdempotencyKey {
+                field_name: key.to_string(),
+                rpc_name: rpc_name.to_string(),
+            }),
+        }
+    }
+
     fn map_enum_variants(
         variants: &[EnumVariantRaw<'input>],
         name: &str,
@@ -497,3 +634,21 @@ impl<'input> TypeChecker<'input> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::parsing::grammar::RFileParser;
+    use crate::type_checking::TypeChecker;
+
+    #[test]
+    pub fn only_unary_calls_with_valid_keys_can_be_idempotent() {
+        let check = |evd| TypeChecker::new().check(&RFileParser::new().parse(evd).unwrap());
+
+        assert!(check("struct A { id: guid } rpc { idempotent a(A) -> stream A; }").is_err());
+        assert!(check("struct A { id: guid } rpc { idempotent(other) a(A) -> A; }").is_err());
+        assert!(
+            check("struct B { f: u8 } struct A { b: B } rpc { idempotent(b) a(A) -> A; }").is_err()
+        );
+        assert!(check("struct A { id: guid } rpc { idempotent(id) a(A) -> A; }").is_ok());
+    }
+}
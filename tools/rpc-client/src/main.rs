@@ -0,0 +1,318 @@
+use futures::{Stream, StreamExt};
+use rpc_support::compression::Compression;
+use rpc_support::framing::Framing;
+use rpc_support::handshake::Handshake;
+use rpc_support::reflection::{Call, Schema};
+use rpc_support::rpc_error::RpcError;
+use rpc_support::transport::TcpConnector;
+#[cfg(unix)]
+use rpc_support::transport::UnixConnector;
+use rpc_support::RawRpcClient;
+use serde_json::Value;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+const USAGE: &str = "\
+Usage: rpc-client <address> list [options]
+       rpc-client <address> call <method> [<request>] [options]
+
+<address> is host:port or unix:<path>. Calls take their request as JSON, read from stdin if it is
+omitted. Calls with a request stream read one JSON request per line from stdin. Responses are
+printed as one JSON document per line.
+
+Options:
+    --schema <file.evd>    Use a local schema instead of asking the server
+    --metadata <json>      Metadata of the call, e.g. {\"source\":\"cli\"}
+    --timeout <seconds>    Time the server has to answer";
+
+type JsonStream = Pin<Box<dyn Stream<Item = Result<Value, RpcError>> + Unpin + Send>>;
+
+enum Command {
+    List,
+    Call {
+        method_name: String,
+        request: Option<String>,
+    },
+}
+
+struct Options {
+    address: String,
+    command: Command,
+    schema: Option<PathBuf>,
+    metadata: Value,
+    timeout: Option<Duration>,
+}
+
+#[tokio::main]
+async fn main() {
+    // Logs go to stderr, so stdout only contains responses
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
+
+    let arguments: Vec<String> = std::env::args().collect();
+
+    if arguments
+        .iter()
+        .any(|argument| argument == "-h" || argument == "--help")
+    {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match parse_arguments(&arguments[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(options).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn parse_arguments(arguments: &[String]) -> Result<Options, String> {
+    let mut positional = vec![];
+    let mut schema = None;
+    let mut metadata = Value::Null;
+    let mut timeout = None;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        let mut value = || {
+            arguments
+                .next()
+                .ok_or_else(|| format!("{} requires a value", argument))
+        };
+
+        match argument.as_str() {
+            "--schema" => schema = Some(PathBuf::from(value()?)),
+            "--metadata" => {
+                metadata = serde_json::from_str(value()?)
+                    .map_err(|e| format!("Invalid metadata: {}", e))?;
+            }
+            "--timeout" => {
+                let seconds = value()?
+                    .parse::<f64>()
+                    .map_err(|e| format!("Invalid timeout: {}", e))?;
+                timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            _ if argument.starts_with("--") => {
+                return Err(format!("Unknown option {}", argument));
+            }
+            _ => positional.push(argument.clone()),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let address = positional.next().ok_or("Missing address")?;
+    let command = match positional.next().as_deref() {
+        Some("list") => Command::List,
+        Some("call") => Command::Call {
+            method_name: positional.next().ok_or("Missing method")?,
+            request: positional.next(),
+        },
+        Some(command) => return Err(format!("Unknown command {}", command)),
+        None => return Err("Missing command".to_string()),
+    };
+
+    if let Some(argument) = positional.next() {
+        return Err(format!("Unexpected argument {}", argument));
+    }
+
+    Ok(Options {
+        address,
+        command,
+        schema,
+        metadata,
+        timeout,
+    })
+}
+
+async fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let local_schema = options.schema.as_deref().map(load_schema).transpose()?;
+    // Without a schema, the client connects to whatever service listens at the address
+    let handshake = local_schema
+        .as_ref()
+        .map_or_else(Handshake::any_service, |schema| {
+            Handshake::new(&schema.service_name, &schema.schema_hash)
+        });
+
+    // JSON lines, so payloads can be passed through as they are
+    let mut client = match options.address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => {
+            RawRpcClient::connect(
+                UnixConnector::new(path),
+                Framing::JsonLines,
+                handshake,
+                Compression::disabled(),
+            )
+            .await?
+        }
+        _ => {
+            RawRpcClient::connect(
+                TcpConnector::new(&options.address),
+                Framing::JsonLines,
+                handshake,
+                Compression::disabled(),
+            )
+            .await?
+        }
+    };
+    client.set_timeout(options.timeout);
+
+    let schema = match local_schema {
+        Some(schema) => schema,
+        None => client.reflect(0).await?,
+    };
+
+    match options.command {
+        Command::List => {
+            for call in &schema.calls {
+                println!("{}", signature(call));
+            }
+        }
+        Command::Call {
+            method_name,
+            request,
+        } => {
+            let call = schema.call(&method_name).ok_or_else(|| {
+                format!(
+                    "The {} service has no method {}, see the list command",
+                    schema.service_name, method_name
+                )
+            })?;
+
+            send(&mut client, call, request, &options.metadata).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Type checks a local `.evd` file, the service is named after the file
+fn load_schema(path: &Path) -> Result<Schema, Box<dyn Error>> {
+    let schema = message_compiler::loading::load_schema(path)?;
+
+    Ok(serde_json::from_str(&schema)?)
+}
+
+/// The call as declared in the `.evd` file
+fn signature(call: &Call) -> String {
+    let stream = |is_stream| if is_stream { "stream " } else { "" };
+    let throws = call
+        .error
+        .as_ref()
+        .map(|error| format!(" throws {}", error))
+        .unwrap_or_default();
+
+    format!(
+        "{}({}{}) -> {}{}{}",
+        call.name,
+        stream(call.request_stream),
+        call.request,
+        stream(call.response_stream),
+        call.response,
+        throws
+    )
+}
+
+async fn send(
+    client: &mut RawRpcClient,
+    call: &Call,
+    request: Option<String>,
+    metadata: &Value,
+) -> Result<(), Box<dyn Error>> {
+    let id = 1;
+
+    match (call.request_stream, call.response_stream) {
+        (false, false) => {
+            let request = read_request(request).await?;
+            let response: Value = client.send_rpc(id, &call.name, &request, metadata).await?;
+            print(&response)?;
+        }
+        (false, true) => {
+            let request = read_request(request).await?;
+            let responses = client
+                .send_rpc_stream_request(id, &call.name, &request, metadata)
+                .await?;
+            print_stream(responses).await?;
+        }
+        (true, false) => {
+            let response: Value = client
+                .send_rpc_client_stream(id, &call.name, read_requests(request)?, metadata)
+                .await?;
+            print(&response)?;
+        }
+        (true, true) => {
+            let responses = client
+                .send_rpc_bidi_stream(id, &call.name, read_requests(request)?, metadata)
+                .await?;
+            print_stream(responses).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The request given on the command line, or the whole of stdin
+async fn read_request(request: Option<String>) -> Result<Value, Box<dyn Error>> {
+    let request = match request {
+        Some(request) => request,
+        None => {
+            let mut request = String::new();
+            tokio::io::stdin().read_to_string(&mut request).await?;
+
+            request
+        }
+    };
+
+    Ok(serde_json::from_str(&request)?)
+}
+
+/// One request per line of stdin, empty lines are skipped
+fn read_requests(request: Option<String>) -> Result<JsonStream, String> {
+    if request.is_some() {
+        return Err("Calls with a request stream read their requests from stdin".to_string());
+    }
+
+    let lines = BufReader::new(tokio::io::stdin()).lines();
+
+    let requests = futures::stream::unfold(lines, |mut lines| async move {
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => {
+                    let request = serde_json::from_str(&line).map_err(RpcError::from);
+                    return Some((request, lines));
+                }
+                Ok(None) => return None,
+                Err(e) => return Some((Err(e.into()), lines)),
+            }
+        }
+    });
+
+    Ok(Box::pin(requests.boxed()))
+}
+
+async fn print_stream(mut responses: JsonStream) -> Result<(), Box<dyn Error>> {
+    while let Some(response) = responses.next().await {
+        print(&response?)?;
+    }
+
+    Ok(())
+}
+
+fn print(response: &Value) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string(response)?);
+
+    Ok(())
+}
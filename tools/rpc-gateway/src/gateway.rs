@@ -0,0 +1,390 @@
+use futures::{Stream, StreamExt, TryStreamExt};
+use hyper::header::{HeaderValue, ACCEPT, ALLOW, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rpc_support::reflection::Schema;
+use rpc_support::rpc_error::{ErrorCode, RpcError};
+use rpc_support::RawRpcClient;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncBufReadExt;
+use tokio_util::io::StreamReader;
+
+/// Metadata of a call as a JSON object, overrides the default metadata of the gateway
+pub const METADATA_HEADER: &str = "x-rpc-metadata";
+
+type JsonStream = Pin<Box<dyn Stream<Item = Result<Value, RpcError>> + Unpin + Send>>;
+
+/// How the items of a response stream are sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamFormat {
+    /// Every item is a `data` event, an error is an `error` event that ends the stream
+    ServerSentEvents,
+    /// Every line is either `{"result": <item>}` or `{"error": <error>}`, which ends the stream
+    JsonLines,
+}
+
+impl StreamFormat {
+    fn of(request: &Request<Body>) -> Self {
+        let accepts_events = request
+            .headers()
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .any(|accept| accept.contains("text/event-stream"));
+
+        if accepts_events {
+            Self::ServerSentEvents
+        } else {
+            Self::JsonLines
+        }
+    }
+
+    const fn content_type(self) -> &'static str {
+        match self {
+            Self::ServerSentEvents => "text/event-stream",
+            Self::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    fn render(self, item: Result<Value, RpcError>) -> String {
+        match (self, item) {
+            (Self::ServerSentEvents, Ok(item)) => format!("data: {}\n\n", item),
+            (Self::ServerSentEvents, Err(e)) => {
+                format!("event: error\ndata: {}\n\n", error_body(&e))
+            }
+            (Self::JsonLines, Ok(item)) => format!("{}\n", json!({ "result": item })),
+            (Self::JsonLines, Err(e)) => format!("{}\n", json!({ "error": error_body(&e) })),
+        }
+    }
+}
+
+/// Exposes the calls of `schema` over HTTP and forwards them through `client`. Every call is a
+/// `POST /<method>` with the request as JSON body, or one request per line for calls with a
+/// request stream. Response streams are sent as server-sent events if the client accepts
+/// `text/event-stream`, as JSON lines otherwise.
+pub struct Gateway {
+    client: RawRpcClient,
+    schema: Schema,
+    metadata: Value,
+    id: AtomicU64,
+}
+
+impl Gateway {
+    /// `metadata` is sent with calls that don't set the [`METADATA_HEADER`]
+    #[must_use]
+    pub const fn new(client: RawRpcClient, schema: Schema, metadata: Value) -> Self {
+        Self {
+            client,
+            schema,
+            metadata,
+            id: AtomicU64::new(0),
+        }
+    }
+
+    /// Answers `request`, failed calls are answered with the HTTP status closest to their
+    /// [`ErrorCode`] and the error as JSON body
+    ///
+    /// # Errors
+    /// Never fails, the result is what `hyper` services return
+    pub async fn handle(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        Ok(self
+            .forward(request)
+            .await
+            .unwrap_or_else(|e| error_response(&e)))
+    }
+
+    async fn forward(&self, request: Request<Body>) -> Result<Response<Body>, RpcError> {
+        let method_name = request.uri().path().trim_start_matches('/');
+        let call = self.schema.call(method_name).ok_or_else(|| {
+            RpcError::status(
+                ErrorCode::Unimplemented,
+                format!(
+                    "The {} service has no method {}",
+                    self.schema.service_name, method_name
+                ),
+            )
+        })?;
+
+        if request.method() != Method::POST {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("POST"));
+
+            return Ok(response);
+        }
+
+        let metadata = match request.headers().get(METADATA_HEADER) {
+            Some(metadata) => serde_json::from_slice(metadata.as_bytes())?,
+            None => self.metadata.clone(),
+        };
+        let format = StreamFormat::of(&request);
+        let body = request.into_body();
+        let id = self.id.fetch_add(1, Ordering::AcqRel);
+
+        match (call.request_stream, call.response_stream) {
+            (false, false) => {
+                let request = read_request(body).await?;
+                let response: Value = self
+                    .client
+                    .send_rpc(id, &call.name, &request, &metadata)
+                    .await?;
+
+                Ok(json_response(&response))
+            }
+            (false, true) => {
+                let request = read_request(body).await?;
+                let responses = self
+                    .client
+                    .send_rpc_stream_request(id, &call.name, &request, &metadata)
+                    .await?;
+
+                Ok(stream_response(responses, format))
+            }
+            (true, false) => {
+                let response: Value = self
+                    .client
+                    .send_rpc_client_stream(id, &call.name, read_requests(body), &metadata)
+                    .await?;
+
+                Ok(json_response(&response))
+            }
+            (true, true) => {
+                let responses = self
+                    .client
+                    .send_rpc_bidi_stream(id, &call.name, read_requests(body), &metadata)
+                    .await?;
+
+                Ok(stream_response(responses, format))
+            }
+        }
+    }
+}
+
+async fn read_request(body: Body) -> Result<Value, RpcError> {
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| RpcError::invalid_argument(format!("Failed to read the request: {}", e)))?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// One request per line of `body`, empty lines are skipped
+fn read_requests(body: Body) -> JsonStream {
+    let body = body.map_err(std::io::Error::other);
+    let lines = StreamReader::new(body).lines();
+
+    let requests = futures::stream::unfold(lines, |mut lines| async move {
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => {
+                    let request = serde_json::from_str(&line).map_err(RpcError::from);
+                    return Some((request, lines));
+                }
+                Ok(None) => return None,
+                Err(e) => return Some((Err(e.into()), lines)),
+            }
+        }
+    });
+
+    Box::pin(requests.boxed())
+}
+
+fn json_response(body: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    response
+}
+
+/// Dropping the response, e.g. because the HTTP client went away, cancels the call
+fn stream_response(responses: JsonStream, format: StreamFormat) -> Response<Body> {
+    let chunks = responses.map(move |item| Ok::<_, Infallible>(format.render(item)));
+
+    let mut response = Response::new(Body::wrap_stream(chunks));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+
+    response
+}
+
+fn error_response(error: &RpcError) -> Response<Body> {
+    let mut response = json_response(&error_body(error));
+    *response.status_mut() = http_status(error.code());
+
+    response
+}
+
+fn error_body(error: &RpcError) -> Value {
+    json!({
+        "code": format!("{:?}", error.code()),
+        "message": error.to_string(),
+        "details": error.details(),
+    })
+}
+
+/// Close to the mapping of gRPC gateways, application errors are errors of the request
+const fn http_status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::InvalidArgument | ErrorCode::FailedPrecondition | ErrorCode::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        ErrorCode::Application => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+        ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::AlreadyExists | ErrorCode::Aborted => StatusCode::CONFLICT,
+        ErrorCode::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::Cancelled => StatusCode::REQUEST_TIMEOUT,
+        ErrorCode::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::Unknown | ErrorCode::Internal | ErrorCode::DataLoss => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use events::{Event, Metadata, Rpc, Server, SubscribeRequest};
+    use hyper::body::HttpBody;
+    use rpc_support::compression::Compression;
+    use rpc_support::framing::Framing;
+    use rpc_support::handshake::Handshake;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    const EVENT: &str = r#"{"id":"67e55044-10b1-426f-9247-bb680e5fe0c8","created_time":1666000000,"data":{"FileCreated":{"path":{"path":"a.txt","mount_id":"test"}}}}"#;
+
+    /// Subscriptions send the events that were sent before and end
+    #[derive(Default)]
+    struct TestRpc {
+        events: Mutex<Vec<Event>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Rpc for TestRpc {
+        async fn send_event(&self, request: Event, _metadata: Metadata) -> Result<(), RpcError> {
+            self.events.lock().unwrap().push(request);
+
+            Ok(())
+        }
+
+        async fn send_events(
+            &self,
+            mut request: Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>,
+            _metadata: Metadata,
+        ) -> Result<(), RpcError> {
+            while let Some(event) = request.next().await {
+                self.events.lock().unwrap().push(event?);
+            }
+
+            Ok(())
+        }
+
+        async fn subscribe(
+            &self,
+            _request: SubscribeRequest,
+            _metadata: Metadata,
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<Event, RpcError>> + Unpin + Send>>, RpcError>
+        {
+            Ok(Box::pin(futures::stream::iter(
+                self.events.lock().unwrap().clone().into_iter().map(Ok),
+            )))
+        }
+    }
+
+    async fn start_gateway() -> Gateway {
+        let (connector, listener) = rpc_support::transport::memory();
+        let server = Server::with_listener(listener, Arc::new(TestRpc::default()));
+        tokio::spawn(server.run());
+
+        let client = RawRpcClient::connect(
+            connector,
+            Framing::JsonLines,
+            Handshake::any_service(),
+            Compression::disabled(),
+        )
+        .await
+        .unwrap();
+        let schema = client.reflect(0).await.unwrap();
+
+        Gateway::new(client, schema, json!({ "source": "test" }))
+    }
+
+    fn post(method_name: &str, body: &str) -> Request<Body> {
+        Request::post(format!("/{}", method_name))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unary_calls_are_answered_with_json() {
+        let gateway = start_gateway().await;
+
+        let response = gateway.handle(post("send_event", EVENT)).await.unwrap();
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/json", response.headers()[CONTENT_TYPE]);
+        assert_eq!("null", body(response).await);
+    }
+
+    #[tokio::test]
+    async fn response_streams_are_sent_as_server_sent_events_or_json_lines() {
+        let gateway = start_gateway().await;
+        let events = format!("{}\n\n{}\n", EVENT, EVENT);
+        let response = gateway.handle(post("send_events", &events)).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let subscribe = r#"{"id":"67e55044-10b1-426f-9247-bb680e5fe0c8","from":null}"#;
+        let mut request = post("subscribe", subscribe);
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static("text/event-stream, */*"));
+        let mut response = gateway.handle(request).await.unwrap();
+        assert_eq!("text/event-stream", response.headers()[CONTENT_TYPE]);
+        let first = response.body_mut().data().await.unwrap().unwrap();
+        assert!(first.starts_with(b"data: {\""));
+        assert!(first.ends_with(b"}\n\n"));
+
+        let response = gateway.handle(post("subscribe", subscribe)).await.unwrap();
+        assert_eq!("application/x-ndjson", response.headers()[CONTENT_TYPE]);
+        let lines = body(response).await;
+        assert_eq!(2, lines.lines().count());
+        assert!(lines.lines().all(|line| line.starts_with(r#"{"result":{"#)));
+    }
+
+    #[tokio::test]
+    async fn errors_are_answered_with_their_http_status() {
+        let gateway = start_gateway().await;
+
+        let response = gateway.handle(post("unknown", "{}")).await.unwrap();
+        assert_eq!(StatusCode::NOT_IMPLEMENTED, response.status());
+
+        let response = gateway.handle(post("send_event", "{}")).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert!(body(response)
+            .await
+            .starts_with(r#"{"code":"InvalidArgument""#));
+
+        let request = Request::get("/send_event").body(Body::empty()).unwrap();
+        let response = gateway.handle(request).await.unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+    }
+}
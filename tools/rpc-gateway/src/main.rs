@@ -0,0 +1,196 @@
+mod gateway;
+
+use crate::gateway::Gateway;
+use hyper::service::{make_service_fn, service_fn};
+use rpc_support::compression::Compression;
+use rpc_support::framing::Framing;
+use rpc_support::handshake::Handshake;
+use rpc_support::reflection::Schema;
+use rpc_support::server::Shutdown;
+use rpc_support::transport::TcpConnector;
+#[cfg(unix)]
+use rpc_support::transport::UnixConnector;
+use rpc_support::RawRpcClient;
+use serde_json::Value;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: rpc-gateway <listen-address> <backend-address> [options]
+
+Exposes the RPC service at <backend-address> over HTTP at <listen-address>, e.g. 0.0.0.0:8080.
+<backend-address> is host:port or unix:<path>. Every call is a POST /<method> with a JSON body, or
+one JSON request per line for calls with a request stream. Response streams are sent as
+server-sent events to clients that accept text/event-stream, as JSON lines otherwise.
+
+Options:
+    --schema <file.evd>    Use a local schema instead of asking the backend
+    --metadata <json>      Metadata of calls without an X-Rpc-Metadata header
+    --timeout <seconds>    Time the backend has to answer";
+
+struct Options {
+    listen_address: SocketAddr,
+    backend_address: String,
+    schema: Option<PathBuf>,
+    metadata: Value,
+    timeout: Option<Duration>,
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let arguments: Vec<String> = std::env::args().collect();
+
+    if arguments
+        .iter()
+        .any(|argument| argument == "-h" || argument == "--help")
+    {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match parse_arguments(&arguments[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(options).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn parse_arguments(arguments: &[String]) -> Result<Options, String> {
+    let mut positional = vec![];
+    let mut schema = None;
+    let mut metadata = Value::Null;
+    let mut timeout = None;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        let mut value = || {
+            arguments
+                .next()
+                .ok_or_else(|| format!("{} requires a value", argument))
+        };
+
+        match argument.as_str() {
+            "--schema" => schema = Some(PathBuf::from(value()?)),
+            "--metadata" => {
+                metadata = serde_json::from_str(value()?)
+                    .map_err(|e| format!("Invalid metadata: {}", e))?;
+            }
+            "--timeout" => {
+                let seconds = value()?
+                    .parse::<f64>()
+                    .map_err(|e| format!("Invalid timeout: {}", e))?;
+                timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            _ if argument.starts_with("--") => {
+                return Err(format!("Unknown option {}", argument));
+            }
+            _ => positional.push(argument.clone()),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let listen_address = positional
+        .next()
+        .ok_or("Missing listen address")?
+        .parse()
+        .map_err(|e| format!("Invalid listen address: {}", e))?;
+    let backend_address = positional.next().ok_or("Missing backend address")?;
+
+    if let Some(argument) = positional.next() {
+        return Err(format!("Unexpected argument {}", argument));
+    }
+
+    Ok(Options {
+        listen_address,
+        backend_address,
+        schema,
+        metadata,
+        timeout,
+    })
+}
+
+async fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let local_schema = match &options.schema {
+        Some(path) => Some(serde_json::from_str::<Schema>(
+            &message_compiler::loading::load_schema(path)?,
+        )?),
+        None => None,
+    };
+    // Without a schema, the gateway forwards to whatever service listens at the address
+    let handshake = local_schema
+        .as_ref()
+        .map_or_else(Handshake::any_service, |schema| {
+            Handshake::new(&schema.service_name, &schema.schema_hash)
+        });
+
+    // JSON lines, so payloads can be passed through as they are
+    let mut client = match options.backend_address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => {
+            RawRpcClient::connect(
+                UnixConnector::new(path),
+                Framing::JsonLines,
+                handshake,
+                Compression::disabled(),
+            )
+            .await?
+        }
+        _ => {
+            RawRpcClient::connect(
+                TcpConnector::new(&options.backend_address),
+                Framing::JsonLines,
+                handshake,
+                Compression::disabled(),
+            )
+            .await?
+        }
+    };
+    client.set_timeout(options.timeout);
+
+    let schema = match local_schema {
+        Some(schema) => schema,
+        None => client.reflect(0).await?,
+    };
+    tracing::info!(
+        "Forwarding {} calls of the {} service from {}",
+        schema.calls.len(),
+        schema.service_name,
+        options.listen_address
+    );
+
+    let gateway = Arc::new(Gateway::new(client, schema, options.metadata));
+    let make_service = make_service_fn(move |_| {
+        let gateway = gateway.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let gateway = gateway.clone();
+
+                async move { gateway.handle(request).await }
+            }))
+        }
+    });
+
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_signal()?;
+
+    hyper::Server::try_bind(&options.listen_address)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.triggered())
+        .await?;
+
+    Ok(())
+}